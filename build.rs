@@ -0,0 +1,17 @@
+use std::error::Error;
+
+const PROTO: &str = "examples/shortener/proto/shortener.proto";
+
+// 编译 shortener 示例的 gRPC 接口定义，生成的代码在示例中通过 tonic::include_proto! 引入。
+// 使用 protoc-bin-vendored 自带的 protoc，不需要在系统中另外安装
+fn main() -> Result<(), Box<dyn Error>> {
+    println!("cargo:rerun-if-changed={PROTO}");
+    let mut config = tonic_build::Config::new();
+    config.protoc_executable(protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::configure().compile_protos_with_config(
+        config,
+        &[PROTO],
+        &["examples/shortener/proto"],
+    )?;
+    Ok(())
+}
@@ -0,0 +1,353 @@
+use crate::{
+    clicks::{Bucket, LinkStats},
+    config::Config,
+    domain::DEFAULT_DOMAIN,
+    handlers::{ModerateReq, ShortenReq},
+    state::AppState,
+    store::{LinkStatus, UrlRecord},
+    transfer::ExportedLink,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Parser, Subcommand};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use std::{env, fmt::Write};
+
+// list 每次从存储中读取的数量，和导出一样按主键分页
+const LIST_PAGE_SIZE: i64 = 500;
+
+// `shortener admin` 的子命令，运维人员不用写 SQL 就可以查看和修改短链接。
+// 和服务读取同一份配置、使用同一个 AppState，所以校验规则和存储方式都完全相同
+#[derive(Debug, Parser)]
+#[command(name = "shortener admin", about = "Inspect and fix short links")]
+pub struct AdminCli {
+    #[arg(long, global = true, help = "Print JSON instead of a table")]
+    json: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Branded domain of the link, defaults to the default domain"
+    )]
+    domain: Option<String>,
+    #[command(subcommand)]
+    command: AdminCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    #[command(about = "List short links of all owners")]
+    List {
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+        #[arg(long, value_parser = parse_enum::<LinkStatus>, help = "active, disabled or blocked")]
+        status: Option<LinkStatus>,
+    },
+    #[command(about = "Show everything stored about a short link")]
+    Show { id: String },
+    #[command(about = "Create a short link, the same as POST /")]
+    Create {
+        url: String,
+        #[arg(long)]
+        alias: Option<String>,
+        #[arg(long, help = "RFC 3339 time, e.g. 2030-01-01T00:00:00Z")]
+        expires_at: Option<DateTime<Utc>>,
+        #[arg(long)]
+        max_clicks: Option<i64>,
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[arg(long, help = "Existing management token of the owner")]
+        token: Option<String>,
+    },
+    #[command(about = "Disable a short link, it returns 410 until re-enabled by an admin")]
+    Disable {
+        id: String,
+        #[arg(long)]
+        reason: String,
+    },
+    #[command(about = "Delete a short link regardless of its owner")]
+    Delete { id: String },
+    #[command(about = "Show click statistics of a short link")]
+    Stats {
+        id: String,
+        #[arg(long, default_value = "day", value_parser = parse_enum::<Bucket>, help = "hour or day")]
+        bucket: Bucket,
+    },
+    #[command(about = "Delete expired and used-up links now instead of waiting for the sweeper")]
+    PurgeExpired,
+}
+
+// 解析 admin 之后的参数并执行，参数不合法或者带有 --help 时由 clap 打印提示并退出
+pub async fn run(config: Config, args: &[String]) -> Result<()> {
+    let cli = AdminCli::parse_from(
+        ["shortener admin"]
+            .into_iter()
+            .chain(args.iter().map(String::as_str)),
+    );
+    let state = AppState::try_new(config).await?;
+    print!("{}", execute(&state, cli).await?);
+    Ok(())
+}
+
+// 执行命令，返回需要打印的内容
+pub async fn execute(state: &AppState, cli: AdminCli) -> Result<String> {
+    let domain = match &cli.domain {
+        Some(name) => state
+            .domains
+            .find(name)
+            .ok_or_else(|| anyhow!("domain {name:?} is not configured"))?,
+        None => DEFAULT_DOMAIN,
+    };
+    let json = cli.json;
+    match cli.command {
+        AdminCommand::List { limit, status } => {
+            let records =
+                list(state, cli.domain.is_some().then_some(domain), limit, status).await?;
+            if json {
+                let links: Vec<_> = records.into_iter().map(ExportedLink::from).collect();
+                return to_json(&links);
+            }
+            let rows = records
+                .iter()
+                .map(|record| {
+                    vec![
+                        state.domains.short_url(&record.domain, &record.id),
+                        destination(record),
+                        record.status.to_string(),
+                        record
+                            .expires_at
+                            .map_or_else(|| "-".to_string(), format_time),
+                        record
+                            .created_at
+                            .map_or_else(|| "-".to_string(), format_time),
+                    ]
+                })
+                .collect();
+            Ok(table(
+                &["SHORT URL", "DESTINATION", "STATUS", "EXPIRES", "CREATED"],
+                rows,
+            ))
+        }
+        AdminCommand::Show { id } => {
+            let record = state
+                .store
+                .get(domain, &id)
+                .await?
+                .ok_or_else(|| anyhow!("short link {id:?} does not exist"))?;
+            if json {
+                return to_json(&ExportedLink::from(record));
+            }
+            Ok(details(state, &record))
+        }
+        AdminCommand::Create {
+            url,
+            alias,
+            expires_at,
+            max_clicks,
+            tags,
+            token,
+        } => {
+            let req = ShortenReq {
+                url,
+                alias,
+                expires_at,
+                max_clicks,
+                tags,
+                ..Default::default()
+            };
+            let shortened = state.shorten(&req, domain, token.as_deref()).await?;
+            if json {
+                return to_json(&json!({ "url": shortened.url, "token": shortened.token }));
+            }
+            let mut out = format!("{}\n", shortened.url);
+            if let Some(token) = shortened.token {
+                writeln!(out, "management token: {token}")?;
+            }
+            Ok(out)
+        }
+        AdminCommand::Disable { id, reason } => {
+            let req = ModerateReq {
+                status: LinkStatus::Disabled,
+                reason,
+            };
+            let record = state.moderate(domain, &id, &req, actor()).await?;
+            if json {
+                return to_json(&ExportedLink::from(record));
+            }
+            Ok(format!("{} is now {}\n", record.id, record.status))
+        }
+        AdminCommand::Delete { id } => {
+            let record = state
+                .store
+                .get(domain, &id)
+                .await?
+                .ok_or_else(|| anyhow!("short link {id:?} does not exist"))?;
+            state.remove(&record).await?;
+            if json {
+                return to_json(&json!({ "deleted": id }));
+            }
+            Ok(format!("deleted {id}\n"))
+        }
+        AdminCommand::Stats { id, bucket } => {
+            let stats = state.stats(domain, &id, bucket).await?;
+            if json {
+                return to_json(&stats);
+            }
+            Ok(stats_tables(&stats))
+        }
+        AdminCommand::PurgeExpired => {
+            let purged = state.purge_dead_links().await?;
+            if json {
+                return to_json(&json!({ "purged": purged }));
+            }
+            Ok(format!("purged {purged} expired or used-up links\n"))
+        }
+    }
+}
+
+// 按主键顺序读取所有所有者的短链接，过滤之后最多返回 limit 条
+async fn list(
+    state: &AppState,
+    domain: Option<&str>,
+    limit: usize,
+    status: Option<LinkStatus>,
+) -> Result<Vec<UrlRecord>> {
+    let mut links = Vec::new();
+    let mut after: Option<(String, String)> = None;
+    while links.len() < limit {
+        let cursor = after.as_ref().map(|(d, id)| (d.as_str(), id.as_str()));
+        let page = state.store.export(cursor, LIST_PAGE_SIZE).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some((last.domain.clone(), last.id.clone()));
+        let full = page.len() as i64 == LIST_PAGE_SIZE;
+        links.extend(page.into_iter().filter(|record| {
+            domain.is_none_or(|d| record.domain == d) && status.is_none_or(|s| record.status == s)
+        }));
+        if !full {
+            break;
+        }
+    }
+    links.truncate(limit);
+    Ok(links)
+}
+
+// 审核记录中的操作者，记录是哪个系统用户在命令行中执行的
+fn actor() -> String {
+    let user = env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    format!("cli:{user}")
+}
+
+fn destination(record: &UrlRecord) -> String {
+    if record.sealed_url.is_some() {
+        "(password protected)".to_string()
+    } else {
+        record.url.clone()
+    }
+}
+
+fn details(state: &AppState, record: &UrlRecord) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let rows = vec![
+        vec![
+            "short url".to_string(),
+            state.domains.short_url(&record.domain, &record.id),
+        ],
+        vec!["destination".to_string(), destination(record)],
+        vec!["status".to_string(), record.status.to_string()],
+        vec!["custom".to_string(), record.custom.to_string()],
+        vec!["tags".to_string(), record.tags.join(", ")],
+        vec![
+            "created".to_string(),
+            optional(record.created_at.map(format_time)),
+        ],
+        vec![
+            "not before".to_string(),
+            optional(record.not_before.map(format_time)),
+        ],
+        vec![
+            "expires".to_string(),
+            optional(record.expires_at.map(format_time)),
+        ],
+        vec![
+            "clicks left".to_string(),
+            optional(record.remaining_clicks.map(|n| n.to_string())),
+        ],
+        vec![
+            "redirect type".to_string(),
+            optional(record.redirect_type.map(|t| u16::from(t).to_string())),
+        ],
+        vec!["interstitial".to_string(), record.interstitial.to_string()],
+        vec!["passthrough".to_string(), record.passthrough.to_string()],
+        vec!["has owner".to_string(), record.owner.is_some().to_string()],
+    ];
+    table(&["FIELD", "VALUE"], rows)
+}
+
+fn stats_tables(stats: &LinkStats) -> String {
+    let mut out = format!(
+        "total clicks: {}\nunique visitors: {}\n\n",
+        stats.total, stats.unique_visitors
+    );
+    let buckets = stats
+        .buckets
+        .iter()
+        .map(|b| vec![format_time(b.start), b.clicks.to_string()])
+        .collect();
+    out.push_str(&table(&["START", "CLICKS"], buckets));
+    if !stats.top_referrers.is_empty() {
+        let referrers = stats
+            .top_referrers
+            .iter()
+            .map(|r| vec![r.referrer.clone(), r.clicks.to_string()])
+            .collect();
+        out.push('\n');
+        out.push_str(&table(&["REFERRER", "CLICKS"], referrers));
+    }
+    if !stats.rules.is_empty() {
+        let rules = stats
+            .rules
+            .iter()
+            .map(|r| vec![r.rule.clone(), r.clicks.to_string()])
+            .collect();
+        out.push('\n');
+        out.push_str(&table(&["RULE", "CLICKS"], rules));
+    }
+    out
+}
+
+// 左对齐的纯文本表格，列之间用两个空格分隔
+fn table(header: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<_> = header.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut out = String::new();
+    let header = header.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(header).chain(rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{cell:<width$}"))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string_pretty(value)? + "\n")
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// 命令行参数中的枚举值和 API 中的写法相同，直接复用 serde 的定义
+fn parse_enum<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_string())).map_err(|e| e.to_string())
+}
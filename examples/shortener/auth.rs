@@ -0,0 +1,71 @@
+use crate::{
+    error::ShortenerError,
+    store::{ApiKey, Scope},
+};
+use http::{
+    header::{AUTHORIZATION, COOKIE},
+    HeaderMap,
+};
+use std::net::IpAddr;
+
+// API key 的前缀，方便在日志或者代码仓库中识别出泄露的 key
+pub const API_KEY_PREFIX: &str = "sk_";
+
+// 发起请求的调用方
+#[derive(Debug)]
+pub enum Caller {
+    // 没有提供 API key，按 IP 限流
+    Anonymous(IpAddr),
+    Key(ApiKey),
+    // 使用配置中的管理员 key，不受限流和权限的限制
+    Admin,
+}
+
+impl Caller {
+    pub fn require(&self, scope: Scope) -> Result<(), ShortenerError> {
+        match self {
+            Self::Admin => Ok(()),
+            Self::Key(key) if key.scopes.contains(&scope) || key.scopes.contains(&Scope::Admin) => {
+                Ok(())
+            }
+            Self::Key(key) => Err(ShortenerError::Forbidden(format!(
+                "API key {:?} does not have the {scope} scope",
+                key.id
+            ))),
+            Self::Anonymous(_) => Err(ShortenerError::Unauthorized(format!(
+                "an API key with the {scope} scope is required"
+            ))),
+        }
+    }
+
+    // 写入审核记录的操作者，不包含 key 的明文
+    pub fn actor(&self) -> String {
+        match self {
+            Self::Admin => "admin".to_string(),
+            Self::Key(key) => format!("key:{}", key.id),
+            Self::Anonymous(ip) => format!("anonymous:{ip}"),
+        }
+    }
+}
+
+// 从 Authorization: Bearer <key> 请求头中取出 API key
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+// 从 Cookie 请求头中取出指定名字的 cookie
+pub fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
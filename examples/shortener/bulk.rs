@@ -0,0 +1,255 @@
+use crate::{
+    auth::Caller,
+    error::{Problem, ShortenerError},
+    handlers::ShortenReq,
+    state::AppState,
+    store::{RedirectType, Routing, Utm},
+};
+use axum::body::{self, Body};
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
+use http::{header::CONTENT_TYPE, HeaderMap};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fmt::Display, io};
+use tokio_util::{
+    codec::{FramedRead, LinesCodec},
+    io::StreamReader,
+};
+use tracing::error;
+
+// 每一批短链接在同一个事务中保存
+const CHUNK_SIZE: usize = 500;
+// 一次请求最多创建的短链接数量
+const MAX_ROWS: usize = 10_000;
+// JSON 数组和 CSV 需要完整读取之后才能解析，所以要限制请求体的大小
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+// 请求中的一条记录，解析失败的记录也占一行，在结果中返回对应的错误
+pub type Row = Result<ShortenReq, ShortenerError>;
+
+// CSV 的列和 ShortenReq 的字段一一对应，多个标签用分号分隔，布尔值的列可以留空。
+// 跳转规则是嵌套的结构，只能通过 JSON 和 NDJSON 设置
+#[derive(Debug, Deserialize)]
+struct CsvRow {
+    url: String,
+    #[serde(default)]
+    alias: Option<String>,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    max_clicks: Option<i64>,
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    tags: Option<String>,
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(default)]
+    interstitial: Option<bool>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    stateless: Option<bool>,
+    #[serde(default)]
+    passthrough: Option<bool>,
+    // UTM 参数各占一列，空字符串表示不设置
+    #[serde(default)]
+    utm_source: Option<String>,
+    #[serde(default)]
+    utm_medium: Option<String>,
+    #[serde(default)]
+    utm_campaign: Option<String>,
+    #[serde(default)]
+    utm_term: Option<String>,
+    #[serde(default)]
+    utm_content: Option<String>,
+}
+
+// 响应中的一行，index 是这条记录在请求中的序号（从 0 开始，NDJSON 中的空行不计数）
+#[derive(Debug, Serialize)]
+struct RowResult {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Problem>,
+}
+
+// 根据 Content-Type 解析请求体：JSON 数组和 CSV 完整读取之后再解析，NDJSON 按行流式解析，
+// 客户端可以一边上传一边收到前面几批的结果
+pub async fn read_rows(
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<BoxStream<'static, Row>, ShortenerError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    match mime {
+        "application/json" => {
+            let body = read_body(body).await?;
+            let values: Vec<serde_json::Value> = serde_json::from_slice(&body).map_err(|e| {
+                ShortenerError::Validation(format!("expected a JSON array of links: {e}"))
+            })?;
+            let rows = values
+                .into_iter()
+                .map(|value| serde_json::from_value(value).map_err(invalid_row));
+            Ok(stream::iter(rows).boxed())
+        }
+        "application/x-ndjson" => {
+            let rows = ndjson_lines(body).map(|line| line.and_then(|line| parse_json_row(&line)));
+            Ok(rows.boxed())
+        }
+        "text/csv" => {
+            let body = read_body(body).await?;
+            let rows: Vec<_> = csv::Reader::from_reader(&body[..])
+                .deserialize::<CsvRow>()
+                .map(|row| row.map(ShortenReq::from).map_err(invalid_row))
+                .collect();
+            Ok(stream::iter(rows).boxed())
+        }
+        _ => Err(ShortenerError::Validation(format!(
+            "unsupported content type {content_type:?}, expected application/json, application/x-ndjson or text/csv"
+        ))),
+    }
+}
+
+// 按批创建短链接，每一批的结果拼成若干行 NDJSON。
+// 某一批整体失败时（例如数据库不可用），这一批的每一行都返回同样的错误，后面的批次继续处理。
+// 每一条有效的记录消耗调用方的一个令牌，和逐条调用 POST / 相同，令牌用完之后的记录返回 rate_limited
+pub fn shorten_rows(
+    state: AppState,
+    caller: Caller,
+    domain: String,
+    token: String,
+    rows: BoxStream<'static, Row>,
+) -> impl Stream<Item = String> {
+    let limiter = state.clone();
+    rows.enumerate()
+        .map(|(index, row)| {
+            if index < MAX_ROWS {
+                (index, row)
+            } else {
+                let e = format!("a bulk request can create at most {MAX_ROWS} links");
+                (index, Err(ShortenerError::Validation(e)))
+            }
+        })
+        // 超过上限的第一行返回错误，之后的数据不再读取
+        .take(MAX_ROWS + 1)
+        .map(move |(index, row)| {
+            let row = row.and_then(|req| limiter.check_rate_limit(&caller).map(|()| req));
+            (index, row)
+        })
+        // ready_chunks 不会等待凑满一批，流式上传时已经到达的记录可以先处理
+        .ready_chunks(CHUNK_SIZE)
+        .then(move |chunk| {
+            let state = state.clone();
+            let domain = domain.clone();
+            let token = token.clone();
+            async move {
+                let (indexes, reqs): (Vec<_>, Vec<_>) = chunk.into_iter().unzip();
+                let results: Vec<_> = match state.shorten_many(reqs, &domain, &token).await {
+                    Ok(results) => results
+                        .into_iter()
+                        .map(|ret| ret.map_err(|e| e.problem()))
+                        .collect(),
+                    Err(e) => {
+                        error!("Failed to create a chunk of links: {e:?}");
+                        let problem = e.problem();
+                        indexes.iter().map(|_| Err(problem.clone())).collect()
+                    }
+                };
+                indexes
+                    .into_iter()
+                    .zip(results)
+                    .map(|(index, ret)| {
+                        let (url, error) = match ret {
+                            Ok(url) => (Some(url), None),
+                            Err(problem) => (None, Some(problem)),
+                        };
+                        let row = RowResult { index, url, error };
+                        let mut line = serde_json::to_string(&row)
+                            .expect("bulk results should always be serializable");
+                        line.push('\n');
+                        line
+                    })
+                    .collect()
+            }
+        })
+}
+
+// 按行读取 NDJSON 请求体，跳过空行。每一行读取失败（例如超过长度限制）时返回对应的错误，不影响后面的行
+pub fn ndjson_lines(body: Body) -> impl Stream<Item = Result<String, ShortenerError>> {
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LEN));
+    lines.filter_map(|line| async move {
+        match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => Some(Ok(line)),
+            Err(e) => Some(Err(invalid_row(e))),
+        }
+    })
+}
+
+pub fn parse_json_row<T: DeserializeOwned>(line: &str) -> Result<T, ShortenerError> {
+    serde_json::from_str(line).map_err(invalid_row)
+}
+
+pub async fn read_body(body: Body) -> Result<body::Bytes, ShortenerError> {
+    body::to_bytes(body, MAX_BODY_SIZE).await.map_err(|e| {
+        ShortenerError::Validation(format!(
+            "failed to read the request body (at most {MAX_BODY_SIZE} bytes): {e}"
+        ))
+    })
+}
+
+pub fn invalid_row(e: impl Display) -> ShortenerError {
+    ShortenerError::Validation(format!("invalid row: {e}"))
+}
+
+impl From<CsvRow> for ShortenReq {
+    fn from(row: CsvRow) -> Self {
+        let tags = row
+            .tags
+            .iter()
+            .flat_map(|tags| tags.split(';'))
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect();
+        Self {
+            url: row.url,
+            alias: row.alias,
+            expires_at: row.expires_at,
+            not_before: row.not_before,
+            max_clicks: row.max_clicks,
+            redirect_type: row.redirect_type,
+            tags,
+            domain: row.domain,
+            interstitial: row.interstitial.unwrap_or_default(),
+            password: non_empty(row.password),
+            stateless: row.stateless.unwrap_or_default(),
+            passthrough: row.passthrough.unwrap_or_default(),
+            utm: Utm {
+                source: non_empty(row.utm_source),
+                medium: non_empty(row.utm_medium),
+                campaign: non_empty(row.utm_campaign),
+                term: non_empty(row.utm_term),
+                content: non_empty(row.utm_content),
+            },
+            routing: Routing::default(),
+        }
+    }
+}
+
+// CSV 中留空的列按没有设置处理
+pub fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.is_empty())
+}
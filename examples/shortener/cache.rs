@@ -0,0 +1,170 @@
+use crate::store::{Invalidation, UrlRecord};
+use lru::LruCache;
+use serde::Serialize;
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tracing::info;
+
+// 跳转接口前面的进程内缓存。短链接创建之后几乎不会修改，而跳转占了绝大部分流量，
+// 所以把查询结果缓存起来，大部分跳转都不需要访问数据库。
+// 缓存按 LRU 淘汰，并且每一项都有 TTL；不存在的短链接也会缓存，TTL 更短，
+// 避免有人反复访问不存在的 id 时每次都打到数据库。
+pub struct LinkCache {
+    // 容量为 0 时不启用缓存。短链接由 (域名, id) 确定
+    entries: Option<Mutex<LruCache<(String, String), Entry>>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry {
+    // None 表示短链接不存在
+    record: Option<UrlRecord>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+}
+
+impl LinkCache {
+    pub fn new(capacity: usize, ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+            ttl,
+            negative_ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // 外层的 None 表示没有命中缓存，内层的 None 表示缓存了“短链接不存在”
+    pub fn get(&self, domain: &str, id: &str) -> Option<Option<UrlRecord>> {
+        let entries = self.entries.as_ref()?;
+        let mut entries = entries.lock().unwrap();
+        let key = key(domain, id);
+        let ret = match entries.get(&key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.record.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        };
+        let counter = if ret.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        ret
+    }
+
+    pub fn insert(&self, domain: &str, id: &str, record: Option<UrlRecord>) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let ttl = if record.is_some() {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        let entry = Entry {
+            record,
+            expires_at: Instant::now() + ttl,
+        };
+        entries.lock().unwrap().put(key(domain, id), entry);
+    }
+
+    // 短链接被创建、修改或删除之后调用，下一次访问会重新查询数据库
+    pub fn invalidate(&self, domain: &str, id: &str) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().pop(&key(domain, id));
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().clear();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, capacity) = self.entries.as_ref().map_or((0, 0), |entries| {
+            let entries = entries.lock().unwrap();
+            (entries.len(), entries.cap().get())
+        });
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+            capacity,
+        }
+    }
+}
+
+// 多个实例共享同一个数据库时，其他实例修改了短链接，本实例的缓存也要失效
+pub async fn apply_invalidations(cache: Arc<LinkCache>, mut rx: mpsc::Receiver<Invalidation>) {
+    while let Some(invalidation) = rx.recv().await {
+        match invalidation {
+            Invalidation::Link { domain, id } => cache.invalidate(&domain, &id),
+            // 和数据库的连接断开过，期间的通知可能丢失了，只能清空整个缓存
+            Invalidation::All => {
+                info!("Clearing link cache after missing change notifications");
+                cache.clear();
+            }
+        }
+    }
+}
+
+fn key(domain: &str, id: &str) -> (String, String) {
+    (domain.to_string(), id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str) -> UrlRecord {
+        UrlRecord {
+            id: id.to_string(),
+            url: "https://www.rust-lang.org/".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cache_should_expire_and_evict_entries() {
+        let cache = LinkCache::new(2, Duration::from_secs(60), Duration::ZERO);
+        cache.insert("", "a", Some(record("a")));
+        cache.insert("", "missing", None);
+        assert!(cache.get("", "a").is_some());
+        // 不存在的短链接按 negative_ttl 过期
+        assert!(cache.get("", "missing").is_none());
+
+        cache.insert("", "b", Some(record("b")));
+        cache.insert("", "c", Some(record("c")));
+        // 容量为 2，最久没有访问的 a 被淘汰
+        assert!(cache.get("", "a").is_none());
+        cache.invalidate("", "b");
+        assert!(cache.get("", "b").is_none());
+        assert!(cache.get("", "c").is_some());
+        // 不同域名下的相同 id 是不同的短链接
+        assert!(cache.get("go.example.com", "c").is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 4, 1));
+    }
+}
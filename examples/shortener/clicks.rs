@@ -0,0 +1,223 @@
+use crate::{store::LinkStore, webhooks::Webhooks};
+use chrono::{DateTime, Utc};
+use http::{
+    header::{REFERER, USER_AGENT},
+    HeaderMap,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time,
+};
+use tracing::warn;
+
+// 访问记录缓冲区大小，缓冲区满时丢弃新的访问记录，而不是阻塞跳转
+const CLICK_BUFFER_SIZE: usize = 10_000;
+// 攒够一批或者到达刷新间隔时写入数据库
+const CLICK_BATCH_SIZE: usize = 500;
+const CLICK_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// 从 SHORTENER_IP_HASH_SECRET 派生访问记录中 IP 哈希的密钥时使用的上下文
+const CLICK_IP_HASH_CONTEXT: &str = "shortener 2024-06 click ip hash";
+pub const TOP_REFERRERS: i64 = 10;
+
+// 一次短链接访问记录
+#[derive(Debug, Clone)]
+pub struct Click {
+    pub domain: String,
+    pub link_id: String,
+    pub clicked_at: DateTime<Utc>,
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    // 不保存原始 IP，只保存带密钥的哈希，用来统计独立访客
+    pub ip_hash: String,
+    // 命中的跳转规则，例如 device:ios 或 variant:1
+    pub rule: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    Hour,
+    #[default]
+    Day,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LinkStats {
+    pub total: i64,
+    pub unique_visitors: i64,
+    pub buckets: Vec<ClickBucket>,
+    pub top_referrers: Vec<ReferrerCount>,
+    // 按命中的跳转规则统计的访问次数，没有命中任何规则的访问不计入
+    pub rules: Vec<RuleCount>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct ClickBucket {
+    pub start: DateTime<Utc>,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct ReferrerCount {
+    pub referrer: String,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct RuleCount {
+    pub rule: String,
+    pub clicks: i64,
+}
+
+// 访问记录先写入缓冲区，由后台任务批量写入存储，避免拖慢跳转
+#[derive(Debug, Clone)]
+pub struct ClickRecorder {
+    tx: mpsc::Sender<Click>,
+    ip_hasher: IpHasher,
+}
+
+// IP 地址只有 2^32 个（IPv4），没有密钥的哈希可以用预先计算的表全部还原，
+// 所以用服务端的密钥计算 BLAKE3 keyed hash，不同的用途使用不同的上下文派生不同的密钥
+#[derive(Clone)]
+pub struct IpHasher {
+    key: [u8; 32],
+}
+
+impl ClickRecorder {
+    pub fn new(store: Arc<dyn LinkStore>, webhooks: Arc<Webhooks>, ip_hash_secret: &str) -> Self {
+        let (tx, rx) = mpsc::channel(CLICK_BUFFER_SIZE);
+        tokio::spawn(flush_clicks(store, webhooks, rx));
+        Self {
+            tx,
+            ip_hasher: IpHasher::new(ip_hash_secret, CLICK_IP_HASH_CONTEXT),
+        }
+    }
+
+    pub fn hash_ip(&self, ip: IpAddr) -> String {
+        self.ip_hasher.hash(ip)
+    }
+
+    pub fn record(&self, click: Click) {
+        // try_send 不会等待，缓冲区满时直接丢弃这条记录
+        match self.tx.try_send(click) {
+            Ok(()) => {}
+            Err(TrySendError::Full(click)) => {
+                warn!("Click buffer is full, dropping click for {}", click.link_id)
+            }
+            Err(TrySendError::Closed(_)) => warn!("Click writer has stopped"),
+        }
+    }
+}
+
+// 从缓冲区中读取访问记录，攒够一批或者定时写入存储
+async fn flush_clicks(
+    store: Arc<dyn LinkStore>,
+    webhooks: Arc<Webhooks>,
+    mut rx: mpsc::Receiver<Click>,
+) {
+    let mut buf = Vec::with_capacity(CLICK_BATCH_SIZE);
+    let mut interval = time::interval(CLICK_FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            click = rx.recv() => match click {
+                Some(click) => {
+                    buf.push(click);
+                    if buf.len() < CLICK_BATCH_SIZE {
+                        continue;
+                    }
+                }
+                // 所有发送端都被释放，写入剩余的记录后退出
+                None => {
+                    insert_clicks(&store, &webhooks, &mut buf).await;
+                    return;
+                }
+            },
+            _ = interval.tick() => {}
+        }
+        insert_clicks(&store, &webhooks, &mut buf).await;
+    }
+}
+
+// 写入成功之后再检查访问次数是否达到了 webhook 的阈值
+async fn insert_clicks(store: &Arc<dyn LinkStore>, webhooks: &Webhooks, buf: &mut Vec<Click>) {
+    if buf.is_empty() {
+        return;
+    }
+    let mut counts = HashMap::new();
+    for click in buf.iter() {
+        *counts
+            .entry((click.domain.clone(), click.link_id.clone()))
+            .or_insert(0) += 1;
+    }
+    if let Err(e) = store.insert_clicks(std::mem::take(buf)).await {
+        warn!("Failed to insert clicks: {e}");
+        return;
+    }
+    webhooks.clicks_recorded(counts).await;
+}
+
+impl Click {
+    pub fn new(
+        domain: String,
+        link_id: String,
+        rule: Option<String>,
+        ip_hash: String,
+        headers: &HeaderMap,
+    ) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        Self {
+            domain,
+            link_id,
+            clicked_at: Utc::now(),
+            referrer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            ip_hash,
+            rule,
+        }
+    }
+}
+
+impl IpHasher {
+    pub fn new(secret: &str, context: &str) -> Self {
+        Self {
+            key: blake3::derive_key(context, secret.as_bytes()),
+        }
+    }
+
+    pub fn hash(&self, ip: IpAddr) -> String {
+        blake3::keyed_hash(&self.key, ip.to_string().as_bytes())
+            .to_hex()
+            .to_string()
+    }
+}
+
+// 不要在日志中输出密钥
+impl std::fmt::Debug for IpHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IpHasher").finish_non_exhaustive()
+    }
+}
+
+impl Bucket {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+        }
+    }
+
+    pub fn duration(&self) -> chrono::Duration {
+        match self {
+            Self::Hour => chrono::Duration::hours(1),
+            Self::Day => chrono::Duration::days(1),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::config::Config;
+use url::Url;
+
+// 默认域名（SHORTENER_BASE_URL）在存储中用空字符串表示，
+// 这样配置品牌域名之前创建的短链接不需要迁移，修改 base_url 之后也仍然有效
+pub const DEFAULT_DOMAIN: &str = "";
+
+// 短链接所属的域名。品牌域名按配置中的写法（小写，可能带端口）保存
+#[derive(Debug, Clone)]
+pub struct Domains {
+    base_url: String,
+    // base_url 中的 host 和端口，创建短链接时也可以用它显式指定默认域名
+    default_host: String,
+    scheme: String,
+    branded: Vec<String>,
+}
+
+impl Domains {
+    pub fn new(config: &Config) -> Self {
+        let url = Url::parse(&config.base_url).ok();
+        let default_host = url.as_ref().map(host_with_port).unwrap_or_default();
+        let scheme = url.as_ref().map_or("http", |url| url.scheme()).to_string();
+        Self {
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            default_host,
+            scheme,
+            branded: config.domains.clone(),
+        }
+    }
+
+    // 根据请求的 Host 找到对应的域名。不是品牌域名时（例如直接访问监听地址）使用默认域名
+    pub fn resolve(&self, host: Option<&str>) -> &str {
+        host.and_then(|host| self.find(host))
+            .unwrap_or(DEFAULT_DOMAIN)
+    }
+
+    // 查找配置过的域名，配置中没有写端口时忽略 Host 中的端口
+    pub fn find(&self, host: &str) -> Option<&str> {
+        let host = host.trim().to_ascii_lowercase();
+        if is_same_host(&self.default_host, &host) {
+            return Some(DEFAULT_DOMAIN);
+        }
+        self.branded
+            .iter()
+            .find(|domain| is_same_host(domain, &host))
+            .map(String::as_str)
+    }
+
+    pub fn short_url(&self, domain: &str, id: &str) -> String {
+        if domain == DEFAULT_DOMAIN {
+            format!("{}/{id}", self.base_url)
+        } else {
+            format!("{}://{domain}/{id}", self.scheme)
+        }
+    }
+}
+
+fn host_with_port(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+fn is_same_host(configured: &str, host: &str) -> bool {
+    if configured == host {
+        return true;
+    }
+    // 忽略端口时不能简单地按冒号切分，IPv6 地址中也有冒号
+    !configured.contains(':')
+        && host
+            .rsplit_once(':')
+            .is_some_and(|(name, port)| name == configured && port.parse::<u16>().is_ok())
+}
@@ -0,0 +1,244 @@
+use crate::store::StoreError;
+use axum::{
+    extract::rejection::{FormRejection, JsonRejection, QueryRejection},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    HeaderValue, StatusCode,
+};
+use serde::Serialize;
+use std::time::Duration;
+use thiserror::Error;
+use tonic::{metadata::MetadataValue, Code, Status};
+use tracing::{debug, error};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+// gRPC 响应中保存错误码的 metadata
+pub const ERROR_CODE: &str = "x-error-code";
+
+// 短链接服务对外暴露的错误。每一种错误对应一个固定的 HTTP 状态码和错误码，
+// 客户端应该根据错误码而不是 detail 中的文字判断错误类型。
+#[derive(Debug, Error)]
+pub enum ShortenerError {
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Validation(String),
+    // 没有提供管理令牌
+    #[error("{0}")]
+    Unauthorized(String),
+    // 管理令牌和短链接的所有者不匹配
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    Conflict(String),
+    // 超过了限流配额，客户端需要等待 retry_after 之后再重试
+    #[error("rate limit exceeded, retry after {} seconds", retry_after_secs(.retry_after))]
+    RateLimited { retry_after: Duration },
+    // 短链接已过期或访问次数已用完
+    #[error("{0}")]
+    Expired(String),
+    // 短链接被管理员停用
+    #[error("{0}")]
+    Disabled(String),
+    // 短链接因为法律原因被屏蔽
+    #[error("{0}")]
+    Blocked(String),
+    // 存储后端暂时不可用，例如数据库连接失败，客户端可以稍后重试
+    #[error("storage backend is unavailable")]
+    Unavailable(#[source] anyhow::Error),
+    #[error("internal server error")]
+    Internal(#[source] anyhow::Error),
+}
+
+// RFC 7807 定义的 problem details 格式，code 是扩展字段
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    kind: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+}
+
+impl ShortenerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Expired(_) => StatusCode::GONE,
+            Self::Disabled(_) => StatusCode::GONE,
+            Self::Blocked(_) => StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // 稳定的错误码，不会随着错误信息的措辞变化
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotFound(_) => "not_found",
+            Self::Validation(_) => "validation_failed",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::Conflict(_) => "conflict",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Expired(_) => "expired",
+            Self::Disabled(_) => "disabled",
+            Self::Blocked(_) => "blocked",
+            Self::Unavailable(_) => "backend_unavailable",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let status = self.status();
+        let code = self.code();
+        Problem {
+            kind: format!("urn:shortener:problem:{code}"),
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code,
+        }
+    }
+
+    pub fn not_found(id: &str) -> Self {
+        Self::NotFound(format!("short link {id:?} does not exist"))
+    }
+
+    // 服务端错误记录完整的错误链（{:#} 会把 anyhow 的 cause 用冒号连接起来），
+    // 客户端错误只是正常的业务结果，用 debug 级别记录即可
+    pub fn log(&self) {
+        let code = self.code();
+        match self {
+            Self::Unavailable(e) | Self::Internal(e) => error!(code, "{self}: {e:#}"),
+            _ => debug!(code, "{self}"),
+        }
+    }
+
+    // gRPC 接口使用的状态码。过期、停用和屏蔽的短链接都是暂时无法访问的状态，统一使用 FAILED_PRECONDITION，
+    // 客户端可以通过 metadata 中的错误码区分
+    pub fn grpc_code(&self) -> Code {
+        match self {
+            Self::NotFound(_) => Code::NotFound,
+            Self::Validation(_) => Code::InvalidArgument,
+            Self::Unauthorized(_) => Code::Unauthenticated,
+            Self::Forbidden(_) => Code::PermissionDenied,
+            Self::Conflict(_) => Code::AlreadyExists,
+            Self::RateLimited { .. } => Code::ResourceExhausted,
+            Self::Expired(_) | Self::Disabled(_) | Self::Blocked(_) => Code::FailedPrecondition,
+            Self::Unavailable(_) => Code::Unavailable,
+            Self::Internal(_) => Code::Internal,
+        }
+    }
+}
+
+impl IntoResponse for ShortenerError {
+    fn into_response(self) -> Response {
+        self.log();
+        let status = self.status();
+        let mut res = (status, Json(self.problem())).into_response();
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        if let Self::RateLimited { retry_after } = &self {
+            res.headers_mut()
+                .insert(RETRY_AFTER, retry_after_secs(retry_after).into());
+        }
+        res
+    }
+}
+
+// gRPC 接口的错误和 HTTP 接口一样带上稳定的错误码，限流时同样返回 retry-after
+impl From<ShortenerError> for Status {
+    fn from(e: ShortenerError) -> Self {
+        e.log();
+        let mut status = Status::new(e.grpc_code(), e.to_string());
+        let metadata = status.metadata_mut();
+        metadata.insert(ERROR_CODE, MetadataValue::from_static(e.code()));
+        if let ShortenerError::RateLimited { retry_after } = &e {
+            metadata.insert(RETRY_AFTER.as_str(), retry_after_secs(retry_after).into());
+        }
+        status
+    }
+}
+
+// 存储层返回的是 anyhow::Error，这里根据底层的错误类型转换成对应的 ShortenerError
+impl From<anyhow::Error> for ShortenerError {
+    fn from(e: anyhow::Error) -> Self {
+        if let Some(StoreError::Conflict(_) | StoreError::DuplicateUrl(_)) =
+            e.downcast_ref::<StoreError>()
+        {
+            return Self::Conflict(e.to_string());
+        }
+        if let Some(sqlx_error) = e.downcast_ref::<sqlx::Error>() {
+            if is_unavailable(sqlx_error) {
+                return Self::Unavailable(e);
+            }
+        }
+        Self::Internal(e)
+    }
+}
+
+impl From<JsonRejection> for ShortenerError {
+    fn from(e: JsonRejection) -> Self {
+        Self::Validation(e.body_text())
+    }
+}
+
+impl From<FormRejection> for ShortenerError {
+    fn from(e: FormRejection) -> Self {
+        Self::Validation(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for ShortenerError {
+    fn from(e: QueryRejection) -> Self {
+        Self::Validation(e.body_text())
+    }
+}
+
+// Retry-After 只能是整数秒，向上取整，避免客户端过早重试
+fn retry_after_secs(retry_after: &Duration) -> u64 {
+    retry_after.as_secs_f64().ceil() as u64
+}
+
+// 连接不上数据库或者连接池耗尽，属于暂时性的错误
+fn is_unavailable(e: &sqlx::Error) -> bool {
+    matches!(
+        e,
+        sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn backend_errors_should_be_classified() {
+        let e: anyhow::Result<()> = Err(sqlx::Error::PoolTimedOut).context("get short link");
+        let e = ShortenerError::from(e.unwrap_err());
+        assert_eq!(e.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(e.code(), "backend_unavailable");
+
+        let e = ShortenerError::from(anyhow::Error::from(StoreError::Conflict("abc".into())));
+        assert_eq!(e.status(), StatusCode::CONFLICT);
+
+        let e = ShortenerError::from(anyhow::anyhow!("boom"));
+        assert_eq!(e.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(e.to_string(), "internal server error");
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{
+    auth,
+    clicks::{Bucket, LinkStats},
+    domain::DEFAULT_DOMAIN,
+    error::ShortenerError,
+    handlers::{self, ShortenReq},
+    state::AppState,
+    stateless::StatelessLinks,
+    store::{Device, DeviceRule, RedirectType, Routing, Utm, Variant},
+};
+use axum::{extract::ConnectInfo, Router};
+use chrono::{DateTime, Utc};
+use http::{header::CONTENT_TYPE, HeaderMap};
+use prost_types::Timestamp;
+use std::net::SocketAddr;
+use tonic::{service::Routes, Request, Response, Status};
+
+// tonic-build 在 build.rs 中根据 proto/shortener.proto 生成的代码
+pub mod pb {
+    tonic::include_proto!("shortener.v1");
+}
+
+use pb::shortener_server::{Shortener, ShortenerServer};
+
+// gRPC 接口和 HTTP handler 一样只负责转换请求和响应，
+// 认证、限流和校验都复用同一个 AppState，所以两种接口的行为始终一致
+#[derive(Clone)]
+pub struct ShortenerService {
+    state: AppState,
+}
+
+// tonic 的服务可以直接转换成 axum 的 Router，和 HTTP 路由运行在同一个端口上
+pub fn router(state: AppState) -> Router {
+    Routes::new(ShortenerServer::new(ShortenerService { state })).into_axum_router()
+}
+
+// gRPC 请求的 Content-Type 是 application/grpc，也可能带有 +proto 之类的后缀
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+#[tonic::async_trait]
+impl Shortener for ShortenerService {
+    async fn shorten(
+        &self,
+        request: Request<pb::ShortenRequest>,
+    ) -> Result<Response<pb::ShortenResponse>, Status> {
+        let (headers, addr, req) = into_parts(request)?;
+        let data = ShortenReq::try_from(req)?;
+        let (key, token) = (auth::bearer(&headers), handlers::header_token(&headers));
+        let shortened =
+            handlers::create_link(&self.state, key, token, addr, DEFAULT_DOMAIN, &data).await?;
+        Ok(Response::new(pb::ShortenResponse {
+            url: shortened.url,
+            token: shortened.token,
+        }))
+    }
+
+    // 和在短链接后面加上 + 的预览页面相同：只返回目标地址，不跳转，也不计入访问次数
+    async fn resolve(
+        &self,
+        request: Request<pb::ResolveRequest>,
+    ) -> Result<Response<pb::ResolveResponse>, Status> {
+        let req = request.into_inner();
+        let domain = self.domain(req.domain.as_deref())?;
+        let state = &self.state;
+        let res = if StatelessLinks::is_token(&req.id) {
+            pb::ResolveResponse {
+                short_url: state.domains.short_url(domain, &req.id),
+                url: Some(state.open_stateless(&req.id)?),
+            }
+        } else {
+            let record = state.lookup(domain, &req.id).await?;
+            pb::ResolveResponse {
+                short_url: state.domains.short_url(&record.domain, &record.id),
+                url: record.sealed_url.is_none().then_some(record.url),
+            }
+        };
+        Ok(Response::new(res))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<pb::DeleteRequest>,
+    ) -> Result<Response<pb::DeleteResponse>, Status> {
+        let (headers, addr, req) = into_parts(request)?;
+        let token = handlers::management_token(&self.state, &headers, addr).await?;
+        let domain = self.domain(req.domain.as_deref())?;
+        self.state.delete(domain, &req.id, token).await?;
+        Ok(Response::new(pb::DeleteResponse {}))
+    }
+
+    async fn stats(
+        &self,
+        request: Request<pb::StatsRequest>,
+    ) -> Result<Response<pb::StatsResponse>, Status> {
+        let (headers, addr, req) = into_parts(request)?;
+        let domain = self.domain(req.domain.as_deref())?;
+        let bucket = match req.bucket() {
+            pb::Bucket::Day => Bucket::Day,
+            pb::Bucket::Hour => Bucket::Hour,
+        };
+        let stats =
+            handlers::link_stats(&self.state, &headers, addr, domain, &req.id, bucket).await?;
+        Ok(Response::new(stats_response(req.id, stats)))
+    }
+}
+
+impl ShortenerService {
+    // HTTP 接口按 Host 选择域名，gRPC 请求没有可靠的 Host，需要在请求中指定，不指定时使用默认域名
+    fn domain<'a>(&'a self, domain: Option<&'a str>) -> Result<&'a str, ShortenerError> {
+        match domain {
+            Some(name) => self.state.domains.find(name).ok_or_else(|| {
+                ShortenerError::Validation(format!("domain {name:?} is not served here"))
+            }),
+            None => Ok(DEFAULT_DOMAIN),
+        }
+    }
+}
+
+// 认证信息在 metadata 中，转换回 HTTP 请求头之后就可以复用 HTTP handler 的认证逻辑。
+// 客户端地址来自 axum 的 ConnectInfo，tonic 会把 HTTP 请求的 extensions 原样保留下来
+fn into_parts<T>(request: Request<T>) -> Result<(HeaderMap, SocketAddr, T), ShortenerError> {
+    let (metadata, extensions, message) = request.into_parts();
+    let Some(ConnectInfo(addr)) = extensions.get::<ConnectInfo<SocketAddr>>() else {
+        return Err(ShortenerError::Internal(anyhow::anyhow!(
+            "missing client address for gRPC request"
+        )));
+    };
+    Ok((metadata.into_headers(), *addr, message))
+}
+
+impl TryFrom<pb::ShortenRequest> for ShortenReq {
+    type Error = ShortenerError;
+
+    fn try_from(req: pb::ShortenRequest) -> Result<Self, Self::Error> {
+        let redirect_type = req
+            .redirect_type
+            .map(|status| {
+                u16::try_from(status)
+                    .map_err(|_| format!("unsupported redirect type {status}"))
+                    .and_then(RedirectType::try_from)
+            })
+            .transpose()
+            .map_err(ShortenerError::Validation)?;
+        let utm = req.utm.map(Utm::from).unwrap_or_default();
+        let routing = req.routing.map(Routing::try_from).transpose()?;
+        Ok(Self {
+            url: req.url,
+            alias: req.alias,
+            expires_at: req.expires_at.map(to_datetime).transpose()?,
+            not_before: req.not_before.map(to_datetime).transpose()?,
+            max_clicks: req.max_clicks,
+            redirect_type,
+            tags: req.tags,
+            interstitial: req.interstitial,
+            password: req.password,
+            stateless: req.stateless,
+            passthrough: req.passthrough,
+            utm,
+            routing: routing.unwrap_or_default(),
+            domain: req.domain,
+        })
+    }
+}
+
+impl From<pb::Utm> for Utm {
+    fn from(utm: pb::Utm) -> Self {
+        Self {
+            source: utm.source,
+            medium: utm.medium,
+            campaign: utm.campaign,
+            term: utm.term,
+            content: utm.content,
+        }
+    }
+}
+
+// 规则中的目标地址和 HTTP 接口一样由 AppState 校验，这里只检查设备类型
+impl TryFrom<pb::Routing> for Routing {
+    type Error = ShortenerError;
+
+    fn try_from(routing: pb::Routing) -> Result<Self, Self::Error> {
+        let devices = routing
+            .devices
+            .into_iter()
+            .map(|rule| {
+                let device = match rule.device() {
+                    pb::Device::Ios => Device::Ios,
+                    pb::Device::Android => Device::Android,
+                    pb::Device::Desktop => Device::Desktop,
+                    pb::Device::Unspecified => {
+                        return Err(ShortenerError::Validation(
+                            "device rules must specify a device".to_string(),
+                        ))
+                    }
+                };
+                Ok(DeviceRule {
+                    device,
+                    url: rule.url,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let variants = routing
+            .variants
+            .into_iter()
+            .map(|variant| Variant {
+                url: variant.url,
+                weight: variant.weight.unwrap_or(1),
+            })
+            .collect();
+        Ok(Self { devices, variants })
+    }
+}
+
+fn stats_response(id: String, stats: LinkStats) -> pb::StatsResponse {
+    pb::StatsResponse {
+        id,
+        total: stats.total,
+        unique_visitors: stats.unique_visitors,
+        buckets: stats
+            .buckets
+            .into_iter()
+            .map(|b| pb::ClickBucket {
+                start: Some(to_timestamp(b.start)),
+                clicks: b.clicks,
+            })
+            .collect(),
+        top_referrers: stats
+            .top_referrers
+            .into_iter()
+            .map(|r| pb::ReferrerCount {
+                referrer: r.referrer,
+                clicks: r.clicks,
+            })
+            .collect(),
+        rules: stats
+            .rules
+            .into_iter()
+            .map(|r| pb::RuleCount {
+                rule: r.rule,
+                clicks: r.clicks,
+            })
+            .collect(),
+    }
+}
+
+fn to_datetime(ts: Timestamp) -> Result<DateTime<Utc>, ShortenerError> {
+    u32::try_from(ts.nanos)
+        .ok()
+        .and_then(|nanos| DateTime::from_timestamp(ts.seconds, nanos))
+        .ok_or_else(|| ShortenerError::Validation(format!("invalid timestamp {ts}")))
+}
+
+fn to_timestamp(dt: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: dt.timestamp(),
+        nanos: dt.timestamp_subsec_nanos() as i32,
+    }
+}
@@ -0,0 +1,975 @@
+use crate::{
+    auth::{self, Caller},
+    bulk,
+    clicks::{Bucket, Click, LinkStats},
+    destination,
+    domain::DEFAULT_DOMAIN,
+    error::ShortenerError,
+    pages,
+    qr::{self, QrFormat, QrQuery},
+    routing::{self, Route},
+    state::{self, AppState, Shortened},
+    stateless::StatelessLinks,
+    store::{
+        ApiKey, DeadLetter, LinkFilter, LinkStatus, RedirectType, ReportFilter, ReportReason,
+        Routing, Scope, UrlRecord, Utm, Webhook, WebhookEvent,
+    },
+    transfer::{self, ExportQuery, ImportQuery, TransferFormat},
+};
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{FormRejection, JsonRejection, QueryRejection},
+        ConnectInfo, Host, Path, Query, RawQuery, State,
+    },
+    response::{Html, IntoResponse, Response},
+    Form, Json,
+};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use http::{
+    header::{
+        ACCEPT, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, LOCATION, SET_COOKIE, VARY,
+    },
+    HeaderMap, HeaderValue, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, net::SocketAddr};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ShortenReq {
+    pub url: String,
+    // 可选的自定义短链接（vanity alias），不传时随机生成
+    #[serde(default)]
+    pub alias: Option<String>,
+    // 过期时间，过期后访问返回 410 Gone
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    // 生效时间，在此之前访问返回 404
+    #[serde(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    // 最大访问次数，设置为 1 即为“阅后即焚”链接
+    #[serde(default)]
+    pub max_clicks: Option<i64>,
+    // 跳转时使用的状态码（301、302、307 或 308），不传时使用配置的默认值
+    #[serde(default)]
+    pub redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // 跳转之前先显示提示页面，让访问者看到目标地址
+    #[serde(default)]
+    pub interstitial: bool,
+    // 设置密码之后目标地址只以加密的形式保存，访问时需要输入密码
+    #[serde(default)]
+    pub password: Option<String>,
+    // 创建无状态短链接：目标地址加密之后放在短链接中，不保存到数据库，
+    // 不能设置其他选项，也不能修改、删除或者统计访问。只对有 API key 的调用方开放
+    #[serde(default)]
+    pub stateless: bool,
+    // 允许在短链接后面追加路径，例如 /abc/docs/page?x=1 跳转到目标地址下的 docs/page，查询参数合并到目标地址中
+    #[serde(default)]
+    pub passthrough: bool,
+    // 跳转时默认添加的 UTM 参数，例如 {"source": "newsletter"}，访问短链接时的同名参数优先
+    #[serde(default)]
+    pub utm: Utm,
+    // 按设备或者按权重选择目标地址的规则，
+    // 例如 {"devices": [{"device": "ios", "url": "..."}], "variants": [{"url": "...", "weight": 3}]}
+    #[serde(default)]
+    pub routing: Routing,
+    // 短链接所属的域名，必须是配置过的域名，不传时使用请求的 Host 对应的域名
+    #[serde(default)]
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ShortenRes {
+    url: String,
+    // 新生成的管理令牌，只在这里返回一次
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+// PATCH /:id 的请求体，没有传的字段保持不变
+#[derive(Debug, Deserialize)]
+pub struct LinkPatch {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub interstitial: Option<bool>,
+    #[serde(default)]
+    pub passthrough: Option<bool>,
+    // 传入的 UTM 参数整体替换原来的参数，传 {} 清空
+    #[serde(default)]
+    pub utm: Option<Utm>,
+    // 传入的规则整体替换原来的规则，传 {} 清空
+    #[serde(default)]
+    pub routing: Option<Routing>,
+}
+
+// GET /:id 和 GET /:id/*rest 的路径参数，rest 是短链接后面追加的路径
+#[derive(Debug, Deserialize)]
+pub struct LinkPath {
+    id: String,
+    #[serde(default)]
+    rest: Option<String>,
+}
+
+// 密码表单
+#[derive(Debug, Deserialize)]
+pub struct UnlockForm {
+    password: String,
+}
+
+// 返回给所有者的短链接详情
+#[derive(Debug, Serialize)]
+struct LinkRes {
+    id: String,
+    short_url: String,
+    url: String,
+    redirect_type: RedirectType,
+    tags: Vec<String>,
+    interstitial: bool,
+    // 受密码保护的短链接不返回目标地址，url 为空字符串
+    protected: bool,
+    passthrough: bool,
+    utm: Utm,
+    routing: Routing,
+    status: LinkStatus,
+    created_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    not_before: Option<DateTime<Utc>>,
+    remaining_clicks: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListRes {
+    links: Vec<LinkRes>,
+    // 下一页的 offset，没有更多数据时为 None
+    next_offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueKeyReq {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // 每分钟可以创建的短链接数量和突发数量，不传时使用默认配置
+    #[serde(default)]
+    pub rate_limit: Option<i64>,
+    #[serde(default)]
+    pub burst: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct IssueKeyRes {
+    id: String,
+    name: String,
+    // API key 的明文，只在签发时返回一次
+    key: String,
+    scopes: Vec<Scope>,
+    rate_limit: Option<i64>,
+    burst: Option<i64>,
+    created_at: DateTime<Utc>,
+}
+
+// 管理令牌通过这个请求头传递
+const MANAGEMENT_TOKEN: &str = "x-management-token";
+
+// POST /:id/report 的请求体
+#[derive(Debug, Deserialize)]
+pub struct ReportReq {
+    pub reason: ReportReason,
+    #[serde(default)]
+    pub details: Option<String>,
+}
+
+// 管理员修改短链接审核状态的请求体，reason 会写入审核记录
+#[derive(Debug, Deserialize)]
+pub struct ModerateReq {
+    pub status: LinkStatus,
+    pub reason: String,
+}
+
+// 审核接口用 domain 指定短链接所在的域名（短链接地址中的 host，默认域名也可以传空字符串），
+// 不传时使用请求的 Host 对应的域名
+#[derive(Debug, Deserialize)]
+pub struct ModerationQuery {
+    #[serde(default)]
+    domain: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportRes {
+    id: String,
+    short_url: String,
+    domain: String,
+    link_id: String,
+    reason: ReportReason,
+    details: Option<String>,
+    created_at: DateTime<Utc>,
+    resolved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportListRes {
+    reports: Vec<ReportRes>,
+    next_offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModerationRes {
+    id: String,
+    short_url: String,
+    // 短链接已经被删除时为 None
+    status: Option<LinkStatus>,
+    actions: Vec<ActionRes>,
+}
+
+#[derive(Debug, Serialize)]
+struct ActionRes {
+    status: LinkStatus,
+    reason: String,
+    actor: String,
+    created_at: DateTime<Utc>,
+}
+
+// 订阅短链接事件的请求体
+#[derive(Debug, Deserialize)]
+pub struct WebhookReq {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    // 订阅 link.clicks 时必须设置
+    #[serde(default)]
+    pub click_thresholds: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookRes {
+    id: String,
+    url: String,
+    // 签名用的密钥，只在创建时返回一次
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    events: Vec<WebhookEvent>,
+    click_thresholds: Vec<i64>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeadLetterRes {
+    id: String,
+    event: WebhookEvent,
+    // 原样返回当时发送的请求体
+    payload: serde_json::Value,
+    attempts: i32,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    failed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayRes {
+    replayed: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    #[serde(default)]
+    bucket: Bucket,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsRes {
+    id: String,
+    // flatten 把 LinkStats 的字段展开到 StatsRes 这一层
+    #[serde(flatten)]
+    stats: LinkStats,
+}
+
+// 这两个写法是 Rust 中模式匹配（pattern matching）的一部分，结合了提取器（extractors）和解构（destructuring）的使用方法，
+// 主要用于处理请求参数。在 Axum 框架中，这种写法用于从请求中提取状态和请求体中的 JSON 数据。
+// State(state)：State 是一个提取器，用于从请求的扩展（extensions）中提取共享状态。在这里，state 是提取后的变量名称。
+// State<AppState>：这里指定了提取器的类型，即 State<AppState>。它表示提取器将提取一个 AppState 类型的共享状态
+// Json(data)：Json 是一个提取器，用于从请求体中提取并反序列化 JSON 数据。在这里，data 是提取后的变量名称
+// Json<ShortenReq>：这里指定了提取器的类型，即 Json<ShortenReq>。它表示提取器将请求体中的 JSON 数据反序列化为 ShortenReq 类型的实例
+// 提取器失败时 axum 默认返回纯文本的错误，这里用 Result 接收提取结果，把失败统一转换成 ShortenerError
+pub async fn shorten(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    data: Result<Json<ShortenReq>, JsonRejection>,
+    // Result<impl IntoResponse, ShortenerError> 实际上只有一个返回值，但这个返回值可能是两种不同的情况之一。
+    // Result<T, E> 是 Rust 的一个枚举类型，用于表示可能成功或失败的操作。它有两个变体：
+    // Ok(T)：表示操作成功，包含类型 T 的值，在这里 T 是 impl IntoResponse。
+    // Err(E)：表示操作失败，包含类型 E 的错误，在这里 E 是 ShortenerError。
+    // 所以，这个函数实际上返回的是一个单一的 Result 值，但这个 Result 可能是以下两种情况之一：
+    // 成功情况：Ok(impl IntoResponse)：这里的 impl IntoResponse 表示任何实现了 IntoResponse trait 的类型。在您之前的代码中，这通常是 (StatusCode, Json<ShortenRes>) 的形式。
+    // 错误情况：Err(ShortenerError)：ShortenerError 实现了 IntoResponse，会被转换成 application/problem+json 格式的错误响应。
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Json(data) = data?;
+    let domain = request_domain(&state, host);
+    let (key, token) = (auth::bearer(&headers), header_token(&headers));
+    let shortened = create_link(&state, key, token, addr, domain, &data).await?;
+    let body = Json(ShortenRes {
+        url: shortened.url,
+        token: shortened.token,
+    });
+    // Axum 为元组 (StatusCode::CREATED, body) 实现了 IntoResponse trait。
+    // IntoResponse 提供了一个统一的接口，用于将各种类型转换成 HTTP 响应。
+    Ok((StatusCode::CREATED, body))
+}
+
+// 检查调用方的权限和限流配额之后创建短链接，HTTP 接口、gRPC 接口和网页共用。
+// key 是 API key，token 是调用方已有的管理令牌，两者都没有时生成新的管理令牌
+pub async fn create_link(
+    state: &AppState,
+    key: Option<&str>,
+    token: Option<&str>,
+    addr: SocketAddr,
+    domain: &str,
+    data: &ShortenReq,
+) -> Result<Shortened, ShortenerError> {
+    let caller = state.authenticate(key, addr.ip()).await?;
+    // 无状态短链接无法删除，不对匿名调用方开放
+    if data.stateless || !matches!(caller, Caller::Anonymous(_)) {
+        caller.require(Scope::Create)?;
+    }
+    state.check_rate_limit(&caller)?;
+    // 使用 API key 创建的短链接默认属于这个 key，之后可以直接用 key 管理
+    state.shorten(data, domain, token.or(key)).await
+}
+
+// 批量创建短链接，请求体可以是 JSON 数组、NDJSON 或 CSV，每一条记录的处理方式和 POST / 相同。
+// 响应是 NDJSON，每一行是一条记录的结果（短链接或者错误），按批次流式返回
+pub async fn bulk_shorten(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let key = auth::bearer(&headers);
+    let caller = state.authenticate(key, addr.ip()).await?;
+    // 批量接口不对匿名调用方开放；限流按创建的短链接数量计数，在 shorten_rows 中逐条检查
+    caller.require(Scope::Create)?;
+    let token = header_token(&headers).or(key).unwrap_or_default();
+    let token = state::validate_token(token)?.to_string();
+    let domain = request_domain(&state, host).to_string();
+    let rows = bulk::read_rows(&headers, body).await?;
+    let lines = bulk::shorten_rows(state, caller, domain, token, rows).map(Ok::<_, Infallible>);
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    ))
+}
+
+pub async fn redirect(
+    Path(path): Path<LinkPath>,
+    RawQuery(query): RawQuery,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    req_headers: HeaderMap,
+) -> Result<Response, ShortenerError> {
+    let LinkPath { id, rest } = path;
+    let domain = request_domain(&state, host);
+    // 在 id 后面加上 + 只显示目标地址，不跳转，也不计入访问次数
+    if let Some(id) = id.strip_suffix('+') {
+        if StatelessLinks::is_token(id) {
+            let url = state.open_stateless(id)?;
+            let short_url = state.domains.short_url(domain, id);
+            return Ok(Html(pages::preview(&short_url, Some(&url))).into_response());
+        }
+        let record = state.lookup(domain, id).await?;
+        let short_url = state.domains.short_url(&record.domain, &record.id);
+        let url = record.sealed_url.is_none().then_some(record.url.as_str());
+        return Ok(Html(pages::preview(&short_url, url)).into_response());
+    }
+
+    // 无状态短链接直接解密之后跳转，不访问数据库，所以也不记录访问。它不支持追加路径
+    if StatelessLinks::is_token(&id) {
+        if rest.is_some() {
+            return Err(ShortenerError::not_found(&id));
+        }
+        let url = state.open_stateless(&id)?;
+        return redirect_to(&id, &url, state.config.redirect_type.status());
+    }
+
+    let record = state.lookup(domain, &id).await?;
+    // 受保护的短链接先显示密码表单，提交之后由 unlock 跳转
+    if record.sealed_url.is_some() {
+        if rest.is_some() && !record.passthrough {
+            return Err(ShortenerError::not_found(&id));
+        }
+        return Ok(Html(pages::password_form(false)).into_response());
+    }
+    // 先计算目标地址，不允许追加路径的短链接返回 404，不消耗访问次数
+    let route = routing::route(&record, &req_headers);
+    let target =
+        destination::redirect_target(&record, route.url, rest.as_deref(), query.as_deref())?;
+    state.take_click(&record).await?;
+    let status = record
+        .redirect_type
+        .unwrap_or(state.config.redirect_type)
+        .status();
+    follow(&state, &record, route, &target, status, addr, &req_headers)
+}
+
+// 提交密码表单。密码正确时跳转到解密后的目标地址，
+// 这里必须使用 303，让浏览器用 GET 访问目标地址，而不是把表单再提交一次
+pub async fn unlock(
+    Path(path): Path<LinkPath>,
+    RawQuery(query): RawQuery,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    req_headers: HeaderMap,
+    form: Result<Form<UnlockForm>, FormRejection>,
+) -> Result<Response, ShortenerError> {
+    let Form(form) = form?;
+    state.check_unlock_rate_limit(addr.ip())?;
+    let domain = request_domain(&state, host);
+    match state.unlock(domain, &path.id, &form.password).await? {
+        Some(record) => {
+            let route = routing::route(&record, &req_headers);
+            let (rest, query) = (path.rest.as_deref(), query.as_deref());
+            let target = destination::redirect_target(&record, route.url, rest, query)?;
+            follow(
+                &state,
+                &record,
+                route,
+                &target,
+                StatusCode::SEE_OTHER,
+                addr,
+                &req_headers,
+            )
+        }
+        None => Ok((StatusCode::UNAUTHORIZED, Html(pages::password_form(true))).into_response()),
+    }
+}
+
+// 跳转到目标地址（需要时先显示提示页面），并记录这次访问和命中的规则
+fn follow(
+    state: &AppState,
+    record: &UrlRecord,
+    route: Route,
+    target: &str,
+    status: StatusCode,
+    addr: SocketAddr,
+    req_headers: &HeaderMap,
+) -> Result<Response, ShortenerError> {
+    let mut res = if record.interstitial {
+        Html(pages::interstitial(target)).into_response()
+    } else {
+        redirect_to(&record.id, target, status)?
+    };
+    // 按规则跳转的短链接每次访问的目标地址可能不同，即使使用 301 或 308 也不能被浏览器缓存
+    if !record.routing.is_empty() {
+        let no_store = HeaderValue::from_static("private, no-store");
+        res.headers_mut().insert(CACHE_CONTROL, no_store);
+    }
+    if let Some(cookie) = route.set_cookie {
+        res.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    let click = Click::new(
+        record.domain.clone(),
+        record.id.clone(),
+        route.rule,
+        state.clicks.hash_ip(addr.ip()),
+        req_headers,
+    );
+    state.clicks.record(click);
+    Ok(res)
+}
+
+fn redirect_to(id: &str, url: &str, status: StatusCode) -> Result<Response, ShortenerError> {
+    // 新的短链接在创建时已经规范化过，但是数据库中可能还有旧的不合法的数据，不能直接 unwrap
+    let location = HeaderValue::try_from(url)
+        .map_err(|e| ShortenerError::Internal(anyhow!("invalid destination for {id}: {e}")))?;
+    Ok((status, [(LOCATION, location)]).into_response())
+}
+
+// 二维码中是短链接本身而不是目标地址，这样扫码也会被统计，修改目标地址之后已经印刷的二维码仍然有效
+pub async fn qr_code(
+    Path(id): Path<String>,
+    query: Result<Query<QrQuery>, QueryRejection>,
+    State(state): State<AppState>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(query) = query?;
+    let domain = request_domain(&state, host);
+    let url = if StatelessLinks::is_token(&id) {
+        state.open_stateless(&id)?;
+        state.domains.short_url(domain, &id)
+    } else {
+        let record = state.lookup(domain, &id).await?;
+        state.domains.short_url(&record.domain, &record.id)
+    };
+    let format = query
+        .format
+        .unwrap_or_else(|| QrFormat::negotiate(headers.get(ACCEPT).and_then(|v| v.to_str().ok())));
+    let body = qr::render(&url, format, &query)?;
+    // 同一个地址根据 Accept 返回不同的格式，缓存需要区分
+    Ok((
+        [(CONTENT_TYPE, format.content_type()), (VARY, "accept")],
+        body,
+    ))
+}
+
+pub async fn update_link(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    patch: Result<Json<LinkPatch>, JsonRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Json(patch) = patch?;
+    let token = management_token(&state, &headers, addr).await?;
+    let domain = request_domain(&state, host);
+    let record = state.update(domain, &id, token, &patch).await?;
+    Ok(Json(link_res(&state, record)))
+}
+
+pub async fn delete_link(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let token = management_token(&state, &headers, addr).await?;
+    let domain = request_domain(&state, host);
+    state.delete(domain, &id, token).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_links(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    filter: Result<Query<LinkFilter>, QueryRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(filter) = filter?;
+    let offset = filter.offset.max(0);
+    let token = management_token(&state, &headers, addr).await?;
+    let records = state.list(token, filter).await?;
+    let next_offset = (!records.is_empty()).then(|| offset + records.len() as i64);
+    let links = records
+        .into_iter()
+        .map(|record| link_res(&state, record))
+        .collect();
+    Ok(Json(ListRes { links, next_offset }))
+}
+
+pub async fn stats(
+    Path(id): Path<String>,
+    query: Result<Query<StatsQuery>, QueryRejection>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(query) = query?;
+    let domain = request_domain(&state, host);
+    let stats = link_stats(&state, &headers, addr, domain, &id, query.bucket).await?;
+    Ok(Json(StatsRes { id, stats }))
+}
+
+// 访问统计只对所有者和管理员开放。所有者和 PATCH、DELETE 一样用管理令牌或者具有 manage 权限的 API key 认证，
+// 管理员 key 和具有 admin 权限的 API key 可以查看任何短链接的统计
+pub async fn link_stats(
+    state: &AppState,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    domain: &str,
+    id: &str,
+    bucket: Bucket,
+) -> Result<LinkStats, ShortenerError> {
+    if let (None, Some(key)) = (header_token(headers), auth::bearer(headers)) {
+        let caller = state.authenticate(Some(key), addr.ip()).await?;
+        if caller.require(Scope::Admin).is_ok() {
+            return state.stats(domain, id, bucket).await;
+        }
+    }
+    let token = management_token(state, headers, addr).await?;
+    state.owner_stats(domain, id, token, bucket).await
+}
+
+// 跳转缓存的命中情况
+pub async fn cache_stats(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.cache.stats())
+}
+
+// 导出所有短链接，需要管理员权限。格式由 format 查询参数决定，没有指定时按 Accept 选择，默认是 NDJSON
+pub async fn export_links(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    query: Result<Query<ExportQuery>, QueryRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(query) = query?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let format = query.format.unwrap_or_else(|| {
+        TransferFormat::negotiate(headers.get(ACCEPT).and_then(|v| v.to_str().ok()))
+    });
+    let body = Body::from_stream(transfer::export(state, format));
+    Ok((
+        [
+            (CONTENT_TYPE, format.content_type()),
+            (CONTENT_DISPOSITION, format.content_disposition()),
+        ],
+        body,
+    ))
+}
+
+// 导入导出的短链接，需要管理员权限。请求体可以是 NDJSON 或 CSV，返回导入的统计和冲突
+pub async fn import_links(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    query: Result<Query<ImportQuery>, QueryRejection>,
+    body: Body,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(query) = query?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let links = transfer::read_links(&headers, body).await?;
+    let report = transfer::import(&state, links, query.dry_run).await?;
+    Ok(Json(report))
+}
+
+// 签发 API key 需要管理员权限
+pub async fn issue_api_key(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    req: Result<Json<IssueKeyReq>, JsonRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Json(req) = req?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let (key, secret) = state.issue_api_key(&req).await?;
+    Ok((StatusCode::CREATED, Json(issue_key_res(key, secret))))
+}
+
+pub async fn revoke_api_key(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    state.revoke_api_key(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 举报短链接，不需要登录
+pub async fn report_link(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    req: Result<Json<ReportReq>, JsonRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Json(req) = req?;
+    let domain = request_domain(&state, host);
+    state.report(domain, &id, &req, addr.ip()).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+// 列出所有域名下的举报，需要管理员权限
+pub async fn list_reports(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    filter: Result<Query<ReportFilter>, QueryRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(filter) = filter?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let offset = filter.offset.max(0);
+    let reports = state.list_reports(filter).await?;
+    let next_offset = (!reports.is_empty()).then(|| offset + reports.len() as i64);
+    let reports = reports
+        .into_iter()
+        .map(|report| ReportRes {
+            id: report.id,
+            short_url: state.domains.short_url(&report.domain, &report.link_id),
+            domain: report.domain,
+            link_id: report.link_id,
+            reason: report.reason,
+            details: report.details,
+            created_at: report.created_at,
+            resolved_at: report.resolved_at,
+        })
+        .collect();
+    Ok(Json(ReportListRes {
+        reports,
+        next_offset,
+    }))
+}
+
+// 查看短链接的审核状态和审核记录，需要管理员权限
+pub async fn moderation_log(
+    Path(id): Path<String>,
+    query: Result<Query<ModerationQuery>, QueryRejection>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(query) = query?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let domain = moderation_domain(&state, &query, host)?;
+    Ok(Json(moderation_res(&state, domain, id).await?))
+}
+
+// 停用、屏蔽或者恢复短链接，需要管理员权限
+pub async fn moderate_link(
+    Path(id): Path<String>,
+    query: Result<Query<ModerationQuery>, QueryRejection>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    req: Result<Json<ModerateReq>, JsonRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Query(query) = query?;
+    let Json(req) = req?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let domain = moderation_domain(&state, &query, host)?;
+    state.moderate(domain, &id, &req, caller.actor()).await?;
+    Ok(Json(moderation_res(&state, domain, id).await?))
+}
+
+async fn moderation_res(
+    state: &AppState,
+    domain: &str,
+    id: String,
+) -> Result<ModerationRes, ShortenerError> {
+    let (record, actions) = state.moderation_log(domain, &id).await?;
+    let actions = actions
+        .into_iter()
+        .map(|action| ActionRes {
+            status: action.status,
+            reason: action.reason,
+            actor: action.actor,
+            created_at: action.created_at,
+        })
+        .collect();
+    Ok(ModerationRes {
+        short_url: state.domains.short_url(domain, &id),
+        id,
+        status: record.map(|record| record.status),
+        actions,
+    })
+}
+
+fn moderation_domain<'a>(
+    state: &'a AppState,
+    query: &ModerationQuery,
+    host: Option<Host>,
+) -> Result<&'a str, ShortenerError> {
+    match query.domain.as_deref() {
+        Some("") => Ok(DEFAULT_DOMAIN),
+        Some(name) => state.domains.find(name).ok_or_else(|| {
+            ShortenerError::Validation(format!("domain {name:?} is not served here"))
+        }),
+        None => Ok(request_domain(state, host)),
+    }
+}
+
+// 订阅短链接事件，需要管理员权限
+pub async fn create_webhook(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    req: Result<Json<WebhookReq>, JsonRejection>,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let Json(req) = req?;
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let webhook = state.create_webhook(&req).await?;
+    Ok((StatusCode::CREATED, Json(webhook_res(webhook, true))))
+}
+
+pub async fn list_webhooks(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let webhooks: Vec<_> = state
+        .list_webhooks()
+        .await?
+        .into_iter()
+        .map(|webhook| webhook_res(webhook, false))
+        .collect();
+    Ok(Json(webhooks))
+}
+
+pub async fn delete_webhook(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    state.delete_webhook(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 列出重试次数用完的投递
+pub async fn list_dead_letters(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let letters: Vec<_> = state
+        .dead_letters(&id)
+        .await?
+        .into_iter()
+        .map(dead_letter_res)
+        .collect();
+    Ok(Json(letters))
+}
+
+// 接收方恢复之后，把死信重新放回投递队列
+pub async fn replay_webhook(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ShortenerError> {
+    let caller = state
+        .authenticate(auth::bearer(&headers), addr.ip())
+        .await?;
+    caller.require(Scope::Admin)?;
+    let replayed = state.replay_dead_letters(&id).await?;
+    Ok((StatusCode::ACCEPTED, Json(ReplayRes { replayed })))
+}
+
+// 管理短链接时优先使用管理令牌，其次是具有 manage 权限的 API key
+pub async fn management_token<'a>(
+    state: &AppState,
+    headers: &'a HeaderMap,
+    addr: SocketAddr,
+) -> Result<&'a str, ShortenerError> {
+    if let Some(token) = header_token(headers) {
+        return Ok(token);
+    }
+    let Some(key) = auth::bearer(headers) else {
+        return Err(ShortenerError::Unauthorized(format!(
+            "missing {MANAGEMENT_TOKEN} header or API key"
+        )));
+    };
+    state
+        .authenticate(Some(key), addr.ip())
+        .await?
+        .require(Scope::Manage)?;
+    Ok(key)
+}
+
+pub fn header_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(MANAGEMENT_TOKEN).and_then(|v| v.to_str().ok())
+}
+
+// 短链接属于请求的 Host 对应的域名。Host 提取器会优先使用负载均衡设置的 X-Forwarded-Host
+pub fn request_domain(state: &AppState, host: Option<Host>) -> &str {
+    state
+        .domains
+        .resolve(host.as_ref().map(|Host(host)| host.as_str()))
+}
+
+fn link_res(state: &AppState, record: UrlRecord) -> LinkRes {
+    LinkRes {
+        short_url: state.domains.short_url(&record.domain, &record.id),
+        id: record.id,
+        url: record.url,
+        redirect_type: record.redirect_type.unwrap_or(state.config.redirect_type),
+        tags: record.tags.0,
+        interstitial: record.interstitial,
+        protected: record.sealed_url.is_some(),
+        passthrough: record.passthrough,
+        utm: record.utm.0,
+        routing: record.routing.0,
+        status: record.status,
+        created_at: record.created_at,
+        expires_at: record.expires_at,
+        not_before: record.not_before,
+        remaining_clicks: record.remaining_clicks,
+    }
+}
+
+fn webhook_res(webhook: Webhook, with_secret: bool) -> WebhookRes {
+    WebhookRes {
+        id: webhook.id,
+        url: webhook.url,
+        secret: with_secret.then_some(webhook.secret),
+        events: webhook.events.0,
+        click_thresholds: webhook.click_thresholds.0,
+        created_at: webhook.created_at,
+    }
+}
+
+fn dead_letter_res(letter: DeadLetter) -> DeadLetterRes {
+    DeadLetterRes {
+        id: letter.id,
+        event: letter.event,
+        payload: serde_json::from_str(&letter.payload).unwrap_or(serde_json::Value::Null),
+        attempts: letter.attempts,
+        last_error: letter.last_error,
+        created_at: letter.created_at,
+        failed_at: letter.failed_at,
+    }
+}
+
+fn issue_key_res(key: ApiKey, secret: String) -> IssueKeyRes {
+    IssueKeyRes {
+        id: key.id,
+        name: key.name,
+        key: secret,
+        scopes: key.scopes.0,
+        rate_limit: key.rate_limit,
+        burst: key.burst,
+        created_at: key.created_at,
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    config::{Config, IdStrategy},
+    store::LinkStore,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use nanoid::nanoid;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const DEFAULT_ID_LEN: usize = 6;
+// 和 urls 表中 id 列的长度保持一致
+const MAX_ID_LEN: usize = 32;
+// 每连续冲突这么多次，随机 id 的长度就永久加一
+const COLLISIONS_BEFORE_GROWING: u32 = 2;
+const FEISTEL_ROUNDS: u8 = 4;
+
+// 短链接 id 的生成策略。生成的 id 可能和已有的 id 冲突，调用方需要在冲突时重试，
+// attempt 表示这是第几次尝试（从 0 开始），生成器可以据此调整 id 的长度。
+#[async_trait]
+pub trait IdGenerator: Send + Sync + 'static {
+    async fn generate(&self, attempt: u32) -> Result<String>;
+}
+
+// 随机生成 nanoid，冲突较多时说明当前长度的 id 空间已经比较拥挤，逐步增加长度
+#[derive(Debug)]
+pub struct RandomIdGenerator {
+    len: AtomicUsize,
+}
+
+// 把存储中的自增序列编码成 base62，id 最短，但是可以被枚举
+pub struct SequenceIdGenerator {
+    store: Arc<dyn LinkStore>,
+}
+
+// 先用带密钥的 Feistel 网络把自增序列打乱，再编码成 base62。
+// Feistel 网络是一个置换（双射），不同的序列号一定得到不同的 id，
+// 所以 id 和顺序生成的一样短，但是不知道密钥就无法从一个 id 推算出其他 id。
+pub struct ObfuscatedIdGenerator {
+    store: Arc<dyn LinkStore>,
+    key: [u8; 32],
+}
+
+pub fn new_generator(config: &Config, store: Arc<dyn LinkStore>) -> Arc<dyn IdGenerator> {
+    match config.id_strategy {
+        IdStrategy::Random => Arc::new(RandomIdGenerator::default()),
+        IdStrategy::Sequence => Arc::new(SequenceIdGenerator { store }),
+        IdStrategy::Obfuscated => Arc::new(ObfuscatedIdGenerator::new(store, &config.id_secret)),
+    }
+}
+
+impl Default for RandomIdGenerator {
+    fn default() -> Self {
+        Self {
+            len: AtomicUsize::new(DEFAULT_ID_LEN),
+        }
+    }
+}
+
+#[async_trait]
+impl IdGenerator for RandomIdGenerator {
+    async fn generate(&self, attempt: u32) -> Result<String> {
+        if attempt > 0 && attempt.is_multiple_of(COLLISIONS_BEFORE_GROWING) {
+            let _ = self
+                .len
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                    (len < MAX_ID_LEN).then_some(len + 1)
+                });
+        }
+        let len = self.len.load(Ordering::Relaxed);
+        Ok(nanoid!(len))
+    }
+}
+
+#[async_trait]
+impl IdGenerator for SequenceIdGenerator {
+    // 冲突时取下一个序列号即可，不需要增加长度
+    async fn generate(&self, _attempt: u32) -> Result<String> {
+        Ok(base62(self.store.next_sequence().await?, 0))
+    }
+}
+
+impl ObfuscatedIdGenerator {
+    pub fn new(store: Arc<dyn LinkStore>, secret: &str) -> Self {
+        Self {
+            store,
+            key: blake3::derive_key("shortener obfuscated id v1", secret.as_bytes()),
+        }
+    }
+
+    // 序列号按 id 长度分段：长度为 6 的 id 用完之后才会使用长度为 7 的 id，依此类推。
+    // 每一段内的序列号在固定位数的空间内做置换，再补齐到固定长度，不同段的 id 长度不同，不会互相冲突。
+    fn encode(&self, seq: u64) -> String {
+        let mut offset = seq;
+        let mut len = DEFAULT_ID_LEN;
+        loop {
+            let bits = feistel_bits(len);
+            let size = 1u64 << bits;
+            if offset < size {
+                return base62(self.permute(offset, bits), len);
+            }
+            offset -= size;
+            len += 1;
+        }
+    }
+
+    fn permute(&self, n: u64, bits: u32) -> u64 {
+        let half = bits / 2;
+        let mask = (1u64 << half) - 1;
+        let (mut left, mut right) = (n >> half, n & mask);
+        for round in 0..FEISTEL_ROUNDS {
+            let f = self.round(round, right) & mask;
+            (left, right) = (right, left ^ f);
+        }
+        (left << half) | right
+    }
+
+    fn round(&self, round: u8, value: u64) -> u64 {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(&[round]);
+        hasher.update(&value.to_le_bytes());
+        let hash = hasher.finalize();
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
+}
+
+#[async_trait]
+impl IdGenerator for ObfuscatedIdGenerator {
+    async fn generate(&self, _attempt: u32) -> Result<String> {
+        Ok(self.encode(self.store.next_sequence().await?))
+    }
+}
+
+// 编码成 base62，不足 width 位时在前面补 0
+fn base62(mut n: u64, width: usize) -> String {
+    let mut buf = Vec::new();
+    loop {
+        buf.push(BASE62[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    while buf.len() < width {
+        buf.push(BASE62[0]);
+    }
+    buf.reverse();
+    String::from_utf8(buf).unwrap()
+}
+
+// 长度为 len 的 base62 字符串能容纳的最大偶数位宽，Feistel 网络需要把数字平均分成两半
+fn feistel_bits(len: usize) -> u32 {
+    let bits = (len as f64 * 62f64.log2()).floor() as u32;
+    (bits - bits % 2).min(62)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use std::collections::HashSet;
+
+    #[test]
+    fn base62_should_encode_and_pad() {
+        assert_eq!(base62(0, 0), "0");
+        assert_eq!(base62(61, 0), "z");
+        assert_eq!(base62(62, 0), "10");
+        assert_eq!(base62(62, 6), "000010");
+    }
+
+    #[test]
+    fn obfuscated_ids_should_be_unique_and_fixed_length() {
+        let generator = ObfuscatedIdGenerator::new(Arc::new(MemoryStore::default()), "secret");
+        let ids: HashSet<_> = (0..10_000).map(|seq| generator.encode(seq)).collect();
+        assert_eq!(ids.len(), 10_000);
+        assert!(ids.iter().all(|id| id.len() == DEFAULT_ID_LEN));
+        // 第一段用完之后切换到长度为 7 的 id
+        assert_eq!(generator.encode(1 << feistel_bits(DEFAULT_ID_LEN)).len(), 7);
+    }
+
+    #[tokio::test]
+    async fn random_ids_should_grow_after_repeated_collisions() {
+        let generator = RandomIdGenerator::default();
+        assert_eq!(generator.generate(0).await.unwrap().len(), 6);
+        assert_eq!(generator.generate(1).await.unwrap().len(), 6);
+        assert_eq!(generator.generate(2).await.unwrap().len(), 7);
+        assert_eq!(generator.generate(0).await.unwrap().len(), 7);
+    }
+}
@@ -0,0 +1,140 @@
+mod admin;
+mod auth;
+mod bulk;
+mod cache;
+mod clicks;
+mod config;
+mod destination;
+mod domain;
+mod error;
+mod grpc;
+mod handlers;
+mod id;
+mod pages;
+mod password;
+mod qr;
+mod ratelimit;
+mod routing;
+mod state;
+mod stateless;
+mod store;
+#[cfg(test)]
+mod tests;
+mod transfer;
+mod ui;
+mod webhooks;
+
+use anyhow::{bail, Result};
+use axum::{
+    extract::Request,
+    routing::{delete, get, post},
+    Router,
+};
+use config::Config;
+use state::AppState;
+use std::{env, net::SocketAddr};
+use store::MigrateCommand;
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+use tracing::{info, level_filters::LevelFilter};
+use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    tracing_subscriber::registry().with(layer).init();
+
+    let config = Config::from_env()?;
+    // 不带参数时启动服务，`shortener migrate [up | down [VERSION] | status]` 手动管理迁移，
+    // `shortener admin <COMMAND>` 在命令行中管理短链接
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => serve(config).await,
+        [command, rest @ ..] if command == "migrate" => {
+            let command = MigrateCommand::parse(rest)?;
+            store::migrate(&config.database_url, command).await
+        }
+        [command, rest @ ..] if command == "admin" => admin::run(config, rest).await,
+        [command, ..] => bail!("unknown command {command:?}, expected migrate or admin"),
+    }
+}
+
+async fn serve(config: Config) -> Result<()> {
+    let state = AppState::try_new(config.clone()).await?;
+    info!("Connected to database: {}", config.database_url);
+    tokio::spawn(state::sweep_dead_links(state.clone()));
+    tokio::spawn(ratelimit::sweep_idle_buckets(state.limiter.clone()));
+    tokio::spawn(state.webhooks.clone().run());
+    if let Some(changes) = state.store.subscribe_changes().await? {
+        tokio::spawn(cache::apply_invalidations(state.cache.clone(), changes));
+    }
+    let listener = TcpListener::bind(&config.listen_addr).await?;
+    info!("Listening on: {}", config.listen_addr);
+
+    // 使用 into_make_service_with_connect_info 才能在 handler 中通过 ConnectInfo 拿到客户端地址
+    axum::serve(
+        listener,
+        app(state).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+// HTTP API 和 gRPC 服务共用同一个端口，按 Content-Type 分发请求。
+// axum::serve 同时支持 HTTP/1 和明文的 HTTP/2，所以 gRPC 客户端可以直接连接
+fn app(state: AppState) -> Router {
+    let rest = http_routes(state.clone());
+    let grpc = grpc::router(state);
+    Router::new().fallback(move |req: Request| {
+        let router = if grpc::is_grpc(req.headers()) {
+            grpc.clone()
+        } else {
+            rest.clone()
+        };
+        router.oneshot(req)
+    })
+}
+
+fn http_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(ui::index).post(handlers::shorten))
+        .route("/api/bulk", post(handlers::bulk_shorten))
+        .route("/api/cache", get(handlers::cache_stats))
+        .route("/api/export", get(handlers::export_links))
+        .route("/api/import", post(handlers::import_links))
+        .route("/api/links", get(handlers::list_links))
+        .route("/api/keys", post(handlers::issue_api_key))
+        .route("/api/keys/:id", delete(handlers::revoke_api_key))
+        .route("/api/reports", get(handlers::list_reports))
+        .route(
+            "/api/webhooks",
+            get(handlers::list_webhooks).post(handlers::create_webhook),
+        )
+        .route("/api/webhooks/:id", delete(handlers::delete_webhook))
+        .route(
+            "/api/webhooks/:id/dead-letters",
+            get(handlers::list_dead_letters),
+        )
+        .route("/api/webhooks/:id/replay", post(handlers::replay_webhook))
+        .route(
+            "/api/moderation/:id",
+            get(handlers::moderation_log).post(handlers::moderate_link),
+        )
+        .route("/ui/links", get(ui::links))
+        .route("/ui/shorten", post(ui::shorten))
+        .route("/ui/token", post(ui::set_token))
+        .route(
+            "/:id",
+            get(handlers::redirect)
+                .post(handlers::unlock)
+                .patch(handlers::update_link)
+                .delete(handlers::delete_link),
+        )
+        .route("/:id/qr", get(handlers::qr_code))
+        .route("/:id/stats", get(handlers::stats))
+        .route("/:id/report", post(handlers::report_link))
+        // 允许追加路径的短链接，静态路由 /:id/qr、/:id/stats 和 /:id/report 优先匹配
+        .route("/:id/*rest", get(handlers::redirect).post(handlers::unlock))
+        .with_state(state)
+}
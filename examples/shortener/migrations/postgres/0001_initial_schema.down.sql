@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS api_keys;
+DROP SEQUENCE IF EXISTS urls_id_seq;
+DROP TABLE IF EXISTS clicks;
+DROP TABLE IF EXISTS urls;
@@ -0,0 +1,49 @@
+-- 短链接服务的基础表结构。在引入版本化迁移之前创建的数据库会先由 PgStore 补齐到这个结构，
+-- 所以这里都使用 IF NOT EXISTS
+CREATE TABLE IF NOT EXISTS urls (
+    -- 空字符串表示默认域名
+    domain VARCHAR(255) NOT NULL DEFAULT '',
+    id VARCHAR(32) NOT NULL,
+    url TEXT NOT NULL,
+    custom BOOLEAN NOT NULL DEFAULT FALSE,
+    expires_at TIMESTAMPTZ,
+    not_before TIMESTAMPTZ,
+    remaining_clicks BIGINT,
+    owner TEXT,
+    redirect_type INTEGER,
+    tags JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (domain, id)
+);
+
+-- 只对永久的随机短链接去重：同一个域名下，同一个所有者的同一个长链接最多对应一个永久随机短链接，
+-- 自定义短链接和带有过期策略的短链接不受限制
+CREATE UNIQUE INDEX IF NOT EXISTS urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL;
+
+CREATE INDEX IF NOT EXISTS urls_owner_created_at_idx ON urls (owner, created_at);
+
+CREATE TABLE IF NOT EXISTS clicks (
+    id BIGSERIAL PRIMARY KEY,
+    domain VARCHAR(255) NOT NULL DEFAULT '',
+    link_id VARCHAR(32) NOT NULL,
+    clicked_at TIMESTAMPTZ NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    ip_hash TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS clicks_domain_link_id_clicked_at_idx ON clicks (domain, link_id, clicked_at);
+
+CREATE SEQUENCE IF NOT EXISTS urls_id_seq;
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id VARCHAR(32) PRIMARY KEY,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes JSONB NOT NULL,
+    rate_limit BIGINT,
+    burst BIGINT,
+    created_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
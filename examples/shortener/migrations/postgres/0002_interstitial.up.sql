@@ -0,0 +1,2 @@
+-- 跳转之前先显示一个提示页面，几秒之后再通过 meta refresh 跳转到目标地址
+ALTER TABLE urls ADD COLUMN interstitial BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- 允许在短链接后面追加路径，追加的路径和查询参数会合并到目标地址中
+ALTER TABLE urls ADD COLUMN passthrough BOOLEAN NOT NULL DEFAULT FALSE;
+-- 跳转时默认添加的 UTM 参数，例如 {"source": "newsletter", "medium": "email"}
+ALTER TABLE urls ADD COLUMN utm JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,31 @@
+-- 短链接的审核状态：active 正常跳转，disabled 返回 410，blocked 因为法律原因返回 451
+ALTER TABLE urls ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+
+-- 访问者提交的举报，管理员处理（修改短链接的状态）之后 resolved_at 不为空
+CREATE TABLE abuse_reports (
+    id VARCHAR(32) PRIMARY KEY,
+    domain VARCHAR(255) NOT NULL,
+    link_id VARCHAR(32) NOT NULL,
+    reason TEXT NOT NULL,
+    details TEXT,
+    -- 举报者 IP 加盐后的哈希
+    reporter_hash TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    resolved_at TIMESTAMPTZ
+);
+
+CREATE INDEX abuse_reports_created_at_idx ON abuse_reports (created_at);
+CREATE INDEX abuse_reports_domain_link_id_idx ON abuse_reports (domain, link_id);
+
+-- 审核操作的记录，只增不删，短链接被删除之后也保留
+CREATE TABLE moderation_actions (
+    id VARCHAR(32) PRIMARY KEY,
+    domain VARCHAR(255) NOT NULL,
+    link_id VARCHAR(32) NOT NULL,
+    status TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX moderation_actions_domain_link_id_created_at_idx ON moderation_actions (domain, link_id, created_at);
@@ -0,0 +1,40 @@
+-- 订阅短链接事件的 webhook，secret 用来给每次投递签名，所以需要保存明文
+CREATE TABLE webhooks (
+    id VARCHAR(32) PRIMARY KEY,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    -- 订阅的事件，JSON 数组，例如 ["link.created", "link.clicks"]
+    events JSONB NOT NULL,
+    -- 访问次数达到这些值时发送 link.clicks 事件
+    click_thresholds JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+-- 等待投递的事件，后台任务取出 next_attempt_at 已经到达的记录发送，成功后删除
+CREATE TABLE webhook_deliveries (
+    id VARCHAR(32) PRIMARY KEY,
+    webhook_id VARCHAR(32) NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    -- 原样发送的请求体，签名针对的就是这些字节
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX webhook_deliveries_next_attempt_at_idx ON webhook_deliveries (next_attempt_at);
+
+-- 重试次数用完的投递，可以通过接口重新放回 webhook_deliveries
+CREATE TABLE webhook_dead_letters (
+    id VARCHAR(32) PRIMARY KEY,
+    webhook_id VARCHAR(32) NOT NULL REFERENCES webhooks (id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT,
+    created_at TIMESTAMPTZ NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX webhook_dead_letters_webhook_id_failed_at_idx ON webhook_dead_letters (webhook_id, failed_at);
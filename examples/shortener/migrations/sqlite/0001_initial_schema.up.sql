@@ -0,0 +1,52 @@
+-- 短链接服务的基础表结构。在引入版本化迁移之前创建的数据库会先由 SqliteStore 补齐到这个结构，
+-- 所以这里都使用 IF NOT EXISTS。
+-- SQLite 没有 TIMESTAMPTZ 类型，sqlx 会把 DateTime<Utc> 存成 RFC 3339 格式的字符串
+CREATE TABLE IF NOT EXISTS urls (
+    -- 空字符串表示默认域名
+    domain TEXT NOT NULL DEFAULT '',
+    id TEXT NOT NULL,
+    url TEXT NOT NULL,
+    custom BOOLEAN NOT NULL DEFAULT FALSE,
+    expires_at TEXT,
+    not_before TEXT,
+    remaining_clicks INTEGER,
+    owner TEXT,
+    redirect_type INTEGER,
+    tags TEXT NOT NULL DEFAULT '[]',
+    created_at TEXT,
+    PRIMARY KEY (domain, id)
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL;
+
+CREATE INDEX IF NOT EXISTS urls_owner_created_at_idx ON urls (owner, created_at);
+
+CREATE TABLE IF NOT EXISTS clicks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    domain TEXT NOT NULL DEFAULT '',
+    link_id TEXT NOT NULL,
+    clicked_at TEXT NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    ip_hash TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS clicks_domain_link_id_clicked_at_idx ON clicks (domain, link_id, clicked_at);
+
+-- SQLite 没有 SEQUENCE，用一张表模拟
+CREATE TABLE IF NOT EXISTS sequences (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    scopes TEXT NOT NULL,
+    rate_limit INTEGER,
+    burst INTEGER,
+    created_at TEXT NOT NULL,
+    revoked_at TEXT
+);
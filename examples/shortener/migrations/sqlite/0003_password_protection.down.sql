@@ -0,0 +1,9 @@
+-- 没有 sealed_url 之后受密码保护的短链接无法再使用，回退时一并删除
+DELETE FROM clicks WHERE (domain, link_id) IN (SELECT domain, id FROM urls WHERE sealed_url IS NOT NULL);
+DELETE FROM urls WHERE sealed_url IS NOT NULL;
+
+DROP INDEX urls_domain_owner_permanent_url_key;
+CREATE UNIQUE INDEX urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL;
+
+ALTER TABLE urls DROP COLUMN sealed_url;
@@ -0,0 +1,7 @@
+-- 受密码保护的短链接只在 sealed_url 中保存加密后的目标地址，url 为空字符串。
+-- 它们不参与按 url 去重，部分唯一索引需要排除它们
+ALTER TABLE urls ADD COLUMN sealed_url TEXT;
+
+DROP INDEX urls_domain_owner_permanent_url_key;
+CREATE UNIQUE INDEX urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL AND sealed_url IS NULL;
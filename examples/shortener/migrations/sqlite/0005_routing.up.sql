@@ -0,0 +1,4 @@
+-- 按设备或者按权重选择目标地址的规则，例如 {"devices": [{"device": "ios", "url": "..."}], "variants": [{"url": "...", "weight": 1}]}
+ALTER TABLE urls ADD COLUMN routing TEXT NOT NULL DEFAULT '{}';
+-- 这次访问命中的规则，例如 device:ios 或 variant:1，没有命中任何规则时为 NULL
+ALTER TABLE clicks ADD COLUMN rule TEXT;
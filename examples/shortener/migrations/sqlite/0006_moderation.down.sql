@@ -0,0 +1,3 @@
+DROP TABLE moderation_actions;
+DROP TABLE abuse_reports;
+ALTER TABLE urls DROP COLUMN status;
@@ -0,0 +1,5 @@
+-- 恢复原来的索引。同一个所有者的同一个 url 同时有普通的短链接和带有 passthrough 或 UTM 参数的短链接时，
+-- 创建唯一索引会失败，整个回退不会生效，不会删除任何短链接；需要先手动处理这些重复的短链接再回退
+DROP INDEX urls_domain_owner_permanent_url_key;
+CREATE UNIQUE INDEX urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL AND sealed_url IS NULL;
@@ -0,0 +1,6 @@
+-- 去重时返回的是已有的短链接，带有 passthrough 或 UTM 参数的短链接参与去重会丢掉这些选项，
+-- 部分唯一索引需要排除它们
+DROP INDEX urls_domain_owner_permanent_url_key;
+CREATE UNIQUE INDEX urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL AND sealed_url IS NULL
+    AND NOT passthrough AND utm = '{}';
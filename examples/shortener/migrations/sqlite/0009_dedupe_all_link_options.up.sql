@@ -0,0 +1,6 @@
+-- 设置了跳转类型、标签、中间页或跳转规则的短链接和 0008 中的 passthrough、UTM 参数一样不参与去重，
+-- 部分唯一索引只包含没有任何选项的永久随机短链接
+DROP INDEX urls_domain_owner_permanent_url_key;
+CREATE UNIQUE INDEX urls_domain_owner_permanent_url_key ON urls (domain, owner, url)
+WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL AND sealed_url IS NULL
+    AND redirect_type IS NULL AND tags = '[]' AND NOT interstitial AND NOT passthrough AND utm = '{}' AND routing = '{}';
@@ -0,0 +1,107 @@
+// 预览页、密码表单和跳转提示页都很简单，直接拼接 HTML，插入的内容都需要转义。
+// 目标地址在创建时已经限制为 http 或 https，不会出现 javascript: 之类的地址
+
+// 跳转提示页停留的秒数
+const INTERSTITIAL_DELAY_SECS: u32 = 5;
+
+// GET /:id+ 显示短链接的目标地址，不跳转。受密码保护的短链接没有明文地址，url 为 None
+pub fn preview(short_url: &str, url: Option<&str>) -> String {
+    let short_url = escape(short_url);
+    let destination = match url {
+        Some(url) => {
+            let url = escape(url);
+            format!(r#"<p><a href="{url}" rel="noopener noreferrer">{url}</a></p>"#)
+        }
+        None => "<p>The destination is protected by a password.</p>".to_string(),
+    };
+    layout(
+        "Link preview",
+        "",
+        &format!(
+            r#"<h1>Link preview</h1>
+<p><code>{short_url}</code> points to:</p>
+{destination}"#
+        ),
+    )
+}
+
+// 受密码保护的短链接的密码表单，提交到当前地址。wrong_password 为 true 时提示密码错误
+pub fn password_form(wrong_password: bool) -> String {
+    let error = if wrong_password {
+        "<p><strong>Wrong password, please try again.</strong></p>\n"
+    } else {
+        ""
+    };
+    layout(
+        "Password required",
+        "",
+        &format!(
+            r#"<h1>This link is password protected</h1>
+{error}<form method="post">
+<label>Password <input type="password" name="password" autocomplete="current-password" required autofocus></label>
+<button type="submit">Continue</button>
+</form>"#
+        ),
+    )
+}
+
+// 跳转之前的提示页，通过 meta refresh 在几秒之后跳转，访问者也可以直接点击链接
+pub fn interstitial(url: &str) -> String {
+    let url = escape(url);
+    layout(
+        "You are leaving this site",
+        &format!(r#"<meta http-equiv="refresh" content="{INTERSTITIAL_DELAY_SECS};url={url}">"#),
+        &format!(
+            r#"<h1>You are being redirected</h1>
+<p>This link will take you to:</p>
+<p><a href="{url}" rel="noopener noreferrer">{url}</a></p>
+<p>You will be redirected in {INTERSTITIAL_DELAY_SECS} seconds. Only continue if you trust this site.</p>"#
+        ),
+    )
+}
+
+fn layout(title: &str, head: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta name="robots" content="noindex">
+{head}
+<title>{title}</title>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#
+    )
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_is_escaped() {
+        let page = interstitial(r#"https://example.com/?a=1&b="><script>"#);
+        assert!(page.contains(r#"url=https://example.com/?a=1&amp;b=&quot;&gt;&lt;script&gt;""#));
+        assert!(!page.contains("<script>"));
+    }
+}
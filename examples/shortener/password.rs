@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+// 受密码保护的短链接只保存加密后的目标地址，数据库被导出也无法得知目标地址。
+// 密钥由密码和随机 salt 经过 Argon2id 派生，每一次加密都使用新的 salt 和 nonce，
+// 结果是 base64 编码的 salt || nonce || 密文（包含 Poly1305 认证标签）
+pub fn seal(url: &str, password: &str) -> Result<String> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = ChaCha20Poly1305::new(&derive_key(password, &salt)?);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, url.as_bytes())
+        .map_err(|e| anyhow!("failed to encrypt destination: {e}"))?;
+    let mut sealed = Vec::with_capacity(SALT_LEN + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(STANDARD.encode(sealed))
+}
+
+// 解密目标地址。密码错误时认证标签校验失败，返回 None
+pub fn open(sealed: &str, password: &str) -> Result<Option<String>> {
+    let sealed = STANDARD.decode(sealed)?;
+    if sealed.len() < SALT_LEN + NONCE_LEN {
+        return Err(anyhow!("sealed destination is too short"));
+    }
+    let (salt, rest) = sealed.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(&derive_key(password, salt)?);
+    match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(url) => Ok(Some(String::from_utf8(url)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+// Argon2 故意设计得很慢，可以有效地拖慢暴力破解，调用方应该放到 spawn_blocking 中执行
+fn derive_key(password: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("failed to derive key from password: {e}"))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_right_password_opens_the_destination() {
+        let url = "https://www.rust-lang.org/";
+        let sealed = seal(url, "correct horse").unwrap();
+        assert!(!sealed.contains("rust-lang"));
+        assert_eq!(
+            open(&sealed, "correct horse").unwrap().as_deref(),
+            Some(url)
+        );
+        assert_eq!(open(&sealed, "battery staple").unwrap(), None);
+        // 同样的地址和密码每次加密的结果都不同
+        assert_ne!(seal(url, "correct horse").unwrap(), sealed);
+    }
+}
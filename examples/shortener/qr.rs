@@ -0,0 +1,253 @@
+use crate::error::ShortenerError;
+use anyhow::anyhow;
+use qrcode::{Color, EcLevel, QrCode};
+use serde::Deserialize;
+use std::fmt::Write;
+
+const DEFAULT_SIZE: u32 = 256;
+const MIN_SIZE: u32 = 32;
+const MAX_SIZE: u32 = 4096;
+// 规范要求四周至少留出 4 个模块宽的空白，印刷在深色背景上时尤其重要
+const DEFAULT_MARGIN: u32 = 4;
+const MAX_MARGIN: u32 = 64;
+
+// GET /:id/qr 的查询参数
+#[derive(Debug, Deserialize)]
+pub struct QrQuery {
+    // 不传时根据 Accept 请求头选择
+    #[serde(default)]
+    pub format: Option<QrFormat>,
+    // 图片的宽和高（像素）。PNG 的模块只能是整数个像素，多出来的部分会补在四周的空白中
+    #[serde(default = "default_size")]
+    pub size: u32,
+    // 四周空白的宽度，以模块为单位
+    #[serde(default = "default_margin")]
+    pub margin: u32,
+    // 纠错等级 L、M、Q、H，等级越高越能容忍污损，但二维码也越密
+    #[serde(default)]
+    pub ecc: Ecc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QrFormat {
+    Svg,
+    Png,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Ecc {
+    #[serde(alias = "l")]
+    L,
+    #[default]
+    #[serde(alias = "m")]
+    M,
+    #[serde(alias = "q")]
+    Q,
+    #[serde(alias = "h")]
+    H,
+}
+
+impl QrFormat {
+    // 按 Accept 中的权重选择 SVG 或 PNG，两者都不接受（例如 */*）时使用 SVG
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut best = (Self::Svg, 0.0);
+        for item in accept.unwrap_or_default().split(',') {
+            let mut parts = item.split(';').map(str::trim);
+            let format = match parts.next().unwrap_or_default() {
+                "image/svg+xml" => Self::Svg,
+                "image/png" => Self::Png,
+                _ => continue,
+            };
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > best.1 {
+                best = (format, q);
+            }
+        }
+        best.0
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Svg => "image/svg+xml",
+            Self::Png => "image/png",
+        }
+    }
+}
+
+impl From<Ecc> for EcLevel {
+    fn from(ecc: Ecc) -> Self {
+        match ecc {
+            Ecc::L => EcLevel::L,
+            Ecc::M => EcLevel::M,
+            Ecc::Q => EcLevel::Q,
+            Ecc::H => EcLevel::H,
+        }
+    }
+}
+
+// 在进程内生成二维码，不依赖外部服务
+pub fn render(data: &str, format: QrFormat, query: &QrQuery) -> Result<Vec<u8>, ShortenerError> {
+    if !(MIN_SIZE..=MAX_SIZE).contains(&query.size) {
+        return Err(ShortenerError::Validation(format!(
+            "size must be between {MIN_SIZE} and {MAX_SIZE} pixels"
+        )));
+    }
+    if query.margin > MAX_MARGIN {
+        return Err(ShortenerError::Validation(format!(
+            "margin must be at most {MAX_MARGIN} modules"
+        )));
+    }
+    let code = QrCode::with_error_correction_level(data, query.ecc.into()).map_err(|e| {
+        ShortenerError::Validation(format!("failed to encode {data} as a QR code: {e}"))
+    })?;
+    let modules = Modules::new(&code, query.margin);
+    match format {
+        QrFormat::Svg => Ok(modules.to_svg(query.size).into_bytes()),
+        QrFormat::Png => modules.to_png(query.size),
+    }
+}
+
+// 加上空白之后的模块矩阵，dark[y * width + x] 表示这个模块是否是深色
+struct Modules {
+    width: u32,
+    dark: Vec<bool>,
+}
+
+impl Modules {
+    fn new(code: &QrCode, margin: u32) -> Self {
+        let code_width = code.width() as u32;
+        let width = code_width + margin * 2;
+        let mut dark = vec![false; (width * width) as usize];
+        for (i, color) in code.to_colors().into_iter().enumerate() {
+            let (x, y) = (i as u32 % code_width, i as u32 / code_width);
+            dark[((y + margin) * width + x + margin) as usize] = color == Color::Dark;
+        }
+        Self { width, dark }
+    }
+
+    fn is_dark(&self, x: u32, y: u32) -> bool {
+        self.dark[(y * self.width + x) as usize]
+    }
+
+    // viewBox 以模块为单位，由浏览器或打印机缩放到 size，所以 SVG 的尺寸总是精确的。
+    // 同一行中相邻的深色模块合并成一个矩形，减小文件体积
+    fn to_svg(&self, size: u32) -> String {
+        let width = self.width;
+        let mut path = String::new();
+        for y in 0..width {
+            let mut x = 0;
+            while x < width {
+                if !self.is_dark(x, y) {
+                    x += 1;
+                    continue;
+                }
+                let start = x;
+                while x < width && self.is_dark(x, y) {
+                    x += 1;
+                }
+                let _ = write!(path, "M{start} {y}h{}v1H{start}z", x - start);
+            }
+        }
+        format!(
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" width="{size}" height="{size}" viewBox="0 0 {width} {width}" shape-rendering="crispEdges"><rect width="{width}" height="{width}" fill="#fff"/><path d="{path}" fill="#000"/></svg>
+"##
+        )
+    }
+
+    // 每个模块占整数个像素，避免缩放造成模块大小不一而难以识别。
+    // size 比模块数还小时每个模块占一个像素，图片会比 size 大
+    fn to_png(&self, size: u32) -> Result<Vec<u8>, ShortenerError> {
+        let dimension = size.max(self.width);
+        let scale = dimension / self.width;
+        let offset = (dimension - scale * self.width) / 2;
+        let mut pixels = vec![u8::MAX; (dimension * dimension) as usize];
+        for y in 0..self.width * scale {
+            for x in 0..self.width * scale {
+                if self.is_dark(x / scale, y / scale) {
+                    pixels[((y + offset) * dimension + x + offset) as usize] = 0;
+                }
+            }
+        }
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, dimension, dimension);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(|e| ShortenerError::Internal(anyhow!("failed to encode PNG: {e}")))?;
+        Ok(png)
+    }
+}
+
+fn default_size() -> u32 {
+    DEFAULT_SIZE
+}
+
+fn default_margin() -> u32 {
+    DEFAULT_MARGIN
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(size: u32, margin: u32) -> QrQuery {
+        QrQuery {
+            format: None,
+            size,
+            margin,
+            ecc: Ecc::default(),
+        }
+    }
+
+    #[test]
+    fn accept_header_selects_format() {
+        assert_eq!(QrFormat::negotiate(None), QrFormat::Svg);
+        assert_eq!(QrFormat::negotiate(Some("*/*")), QrFormat::Svg);
+        assert_eq!(QrFormat::negotiate(Some("image/png")), QrFormat::Png);
+        assert_eq!(
+            QrFormat::negotiate(Some("image/svg+xml;q=0.5, image/png")),
+            QrFormat::Png
+        );
+        assert_eq!(
+            QrFormat::negotiate(Some("image/png;q=0.8, image/svg+xml;q=0.9")),
+            QrFormat::Svg
+        );
+    }
+
+    #[test]
+    fn png_has_requested_size() {
+        let png = render("http://sho.rt/abc", QrFormat::Png, &query(300, 4)).unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(png));
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!((info.width, info.height), (300, 300));
+    }
+
+    #[test]
+    fn margin_surrounds_the_code() {
+        let code = QrCode::new("http://sho.rt/abc").unwrap();
+        let modules = Modules::new(&code, 2);
+        assert_eq!(modules.width, code.width() as u32 + 4);
+        assert!((0..modules.width).all(|x| !modules.is_dark(x, 0) && !modules.is_dark(x, 1)));
+        // 左上角定位图案的第一个模块
+        assert!(modules.is_dark(2, 2));
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        for query in [
+            query(MIN_SIZE - 1, 4),
+            query(MAX_SIZE + 1, 4),
+            query(256, MAX_MARGIN + 1),
+        ] {
+            let ret = render("http://sho.rt/abc", QrFormat::Svg, &query);
+            assert!(matches!(ret, Err(ShortenerError::Validation(_))));
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::config::Quota;
+use dashmap::DashMap;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// 令牌桶限流：每个调用方一个桶，桶的容量是 burst，令牌按照每分钟 per_minute 个的速度补充，
+// 每个请求消耗一个令牌。短时间内可以突发 burst 个请求，长期来看不会超过 per_minute。
+// 限流状态只保存在进程内，多个实例各自限流。
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: DashMap<String, TokenBucket>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+    quota: Quota,
+}
+
+impl RateLimiter {
+    // 消耗一个令牌，没有令牌时返回还需要等待多久
+    pub fn check(&self, key: &str, quota: Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket {
+                tokens: quota.burst as f64,
+                updated_at: now,
+                quota,
+            });
+        bucket.quota = quota;
+        bucket.refill(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - bucket.tokens;
+        Err(Duration::from_secs_f64(missing / quota.per_second()))
+    }
+
+    // 删除已经装满的桶，它们和新建的桶没有区别，避免不再访问的调用方一直占用内存
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.quota.burst as f64
+        });
+    }
+}
+
+pub async fn sweep_idle_buckets(limiter: Arc<RateLimiter>) {
+    let mut interval = time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        limiter.sweep();
+    }
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.quota.per_second()).min(self.quota.burst as f64);
+        self.updated_at = now;
+    }
+}
+
+impl Quota {
+    fn per_second(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_should_allow_bursts_and_then_throttle() {
+        let limiter = RateLimiter::default();
+        let quota = Quota {
+            per_minute: 60,
+            burst: 3,
+        };
+        for _ in 0..3 {
+            assert!(limiter.check("ip:127.0.0.1", quota).is_ok());
+        }
+        let retry_after = limiter.check("ip:127.0.0.1", quota).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        // 每个调用方的桶是独立的
+        assert!(limiter.check("ip:127.0.0.2", quota).is_ok());
+
+        limiter.sweep();
+        assert_eq!(limiter.buckets.len(), 2);
+    }
+}
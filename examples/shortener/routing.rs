@@ -0,0 +1,190 @@
+use crate::{
+    auth,
+    store::{Device, Routing, UrlRecord},
+};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use http::{header::USER_AGENT, HeaderMap, HeaderValue};
+
+// 记录 A/B 测试分组的 cookie，Path 限定为短链接自己的路径，所以不同的短链接互不影响
+const VARIANT_COOKIE: &str = "shortener_variant";
+const VARIANT_COOKIE_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+// 这次访问选中的目标地址
+#[derive(Debug)]
+pub struct Route<'a> {
+    pub url: &'a str,
+    // 命中的规则，记录在访问记录中，没有命中任何规则时为 None
+    pub rule: Option<String>,
+    // 新分配的分组需要写入 cookie，下次访问时看到同一个分组
+    pub set_cookie: Option<HeaderValue>,
+}
+
+// 按短链接的规则选择目标地址：先按 User-Agent 识别的设备匹配设备规则，
+// 没有命中时按权重选择一个分组，cookie 中已经有分组并且这个分组仍然存在时沿用它
+pub fn route<'a>(record: &'a UrlRecord, headers: &HeaderMap) -> Route<'a> {
+    let Routing { devices, variants } = &record.routing.0;
+    let default = Route {
+        url: &record.url,
+        rule: None,
+        set_cookie: None,
+    };
+    if !devices.is_empty() {
+        let user_agent = headers.get(USER_AGENT).and_then(|v| v.to_str().ok());
+        let device = detect_device(user_agent.unwrap_or_default());
+        if let Some(rule) = devices.iter().find(|rule| rule.device == device) {
+            return Route {
+                url: &rule.url,
+                rule: Some(format!("device:{device}")),
+                set_cookie: None,
+            };
+        }
+    }
+    if variants.is_empty() {
+        return default;
+    }
+
+    let sticky = variant_cookie(headers).filter(|&i| variants.get(i).is_some_and(|v| v.weight > 0));
+    let (index, set_cookie) = match sticky {
+        Some(index) => (index, None),
+        None => {
+            let weights: Vec<_> = variants.iter().map(|v| v.weight).collect();
+            let Some(index) = pick_weighted(&weights, OsRng.next_u32()) else {
+                return default;
+            };
+            let cookie = format!(
+                "{VARIANT_COOKIE}={index}; Path=/{}; Max-Age={VARIANT_COOKIE_MAX_AGE}; HttpOnly; SameSite=Lax",
+                record.id
+            );
+            (index, HeaderValue::from_str(&cookie).ok())
+        }
+    };
+    Route {
+        url: &variants[index].url,
+        rule: Some(format!("variant:{index}")),
+        set_cookie,
+    }
+}
+
+// 只需要区分三类设备，不必完整解析 User-Agent。
+// iPadOS 13 之后 Safari 默认使用和 macOS 相同的 User-Agent，这时会被识别为桌面设备
+pub fn detect_device(user_agent: &str) -> Device {
+    let ua = user_agent.to_ascii_lowercase();
+    if ["iphone", "ipad", "ipod"].iter().any(|s| ua.contains(s)) {
+        Device::Ios
+    } else if ua.contains("android") {
+        Device::Android
+    } else {
+        Device::Desktop
+    }
+}
+
+// 用随机数 n 按权重选择一个下标，所有权重都是 0 时返回 None
+fn pick_weighted(weights: &[u32], n: u32) -> Option<usize> {
+    let total: u64 = weights.iter().map(|&w| w as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut n = n as u64 % total;
+    weights.iter().position(|&w| {
+        if n < w as u64 {
+            return true;
+        }
+        n -= w as u64;
+        false
+    })
+}
+
+// 从 Cookie 请求头中读取之前分配的分组
+fn variant_cookie(headers: &HeaderMap) -> Option<usize> {
+    auth::cookie(headers, VARIANT_COOKIE)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{DeviceRule, Variant};
+    use http::header::COOKIE;
+    use sqlx::types::Json;
+
+    #[test]
+    fn devices_should_be_detected_from_user_agents() {
+        let cases = [
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X)",
+                Device::Ios,
+            ),
+            ("Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X)", Device::Ios),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) Mobile",
+                Device::Android,
+            ),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64)", Device::Desktop),
+            ("curl/8.4.0", Device::Desktop),
+            ("", Device::Desktop),
+        ];
+        for (ua, device) in cases {
+            assert_eq!(detect_device(ua), device, "{ua}");
+        }
+    }
+
+    #[test]
+    fn variants_should_be_picked_by_weight() {
+        assert_eq!(pick_weighted(&[1, 3], 0), Some(0));
+        assert_eq!(pick_weighted(&[1, 3], 1), Some(1));
+        assert_eq!(pick_weighted(&[1, 3], 3), Some(1));
+        assert_eq!(pick_weighted(&[1, 3], 4), Some(0));
+        assert_eq!(pick_weighted(&[0, 2], 0), Some(1));
+        assert_eq!(pick_weighted(&[0, 0], 7), None);
+        assert_eq!(pick_weighted(&[], 7), None);
+    }
+
+    #[test]
+    fn sticky_cookie_should_keep_the_variant() {
+        let record = UrlRecord {
+            id: "abc".to_string(),
+            url: "https://example.com/".to_string(),
+            routing: Json(Routing {
+                devices: vec![DeviceRule {
+                    device: Device::Ios,
+                    url: "https://apps.apple.com/".to_string(),
+                }],
+                variants: vec![
+                    Variant {
+                        url: "https://example.com/a".to_string(),
+                        weight: 1,
+                    },
+                    Variant {
+                        url: "https://example.com/b".to_string(),
+                        weight: 1,
+                    },
+                ],
+            }),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static("Mozilla/5.0 (iPhone)"));
+        let ios = route(&record, &headers);
+        assert_eq!(ios.url, "https://apps.apple.com/");
+        assert_eq!(ios.rule.as_deref(), Some("device:ios"));
+
+        let first = route(&record, &HeaderMap::new());
+        let cookie = first.set_cookie.unwrap();
+        let cookie = cookie.to_str().unwrap();
+        assert!(cookie.contains("; Path=/abc;"));
+        let mut headers = HeaderMap::new();
+        let value = cookie.split(';').next().unwrap();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("a=1; {value}")).unwrap(),
+        );
+        for _ in 0..10 {
+            let again = route(&record, &headers);
+            assert_eq!(again.url, first.url);
+            assert_eq!(again.rule, first.rule);
+            assert!(again.set_cookie.is_none());
+        }
+        // 分组已经不存在时重新分配
+        headers.insert(COOKIE, HeaderValue::from_static("shortener_variant=5"));
+        assert!(route(&record, &headers).set_cookie.is_some());
+    }
+}
@@ -0,0 +1,1107 @@
+use crate::{
+    auth::{Caller, API_KEY_PREFIX},
+    cache::LinkCache,
+    clicks::{Bucket, ClickRecorder, IpHasher, LinkStats},
+    config::{Config, Quota},
+    destination::DestinationPolicy,
+    domain::Domains,
+    error::ShortenerError,
+    handlers::{IssueKeyReq, LinkPatch, ModerateReq, ReportReq, ShortenReq, WebhookReq},
+    id::{self, IdGenerator},
+    password,
+    ratelimit::RateLimiter,
+    stateless::StatelessLinks,
+    store::{
+        self, AbuseReport, ApiKey, DeadLetter, LinkFilter, LinkStatus, LinkStore, LinkUpdate,
+        ModerationAction, NewLink, ReportFilter, Routing, StoreError, UrlRecord, Utm, Webhook,
+        WebhookEvent,
+    },
+    webhooks::Webhooks,
+};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use nanoid::nanoid;
+use sqlx::types::Json;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::time;
+use tracing::{info, warn};
+use url::Url;
+
+const ALIAS_MIN_LEN: usize = 3;
+const ALIAS_MAX_LEN: usize = 32;
+// 这些路径被服务本身占用（或预留给以后的接口），不能作为自定义短链接
+const RESERVED_ALIASES: &[&str] = &["api", "health", "admin", "static", "ui"];
+// 后台清理过期短链接的时间间隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// 生成的 id 冲突时最多重试的次数
+const MAX_ID_ATTEMPTS: u32 = 8;
+// 管理令牌的长度，nanoid 的每个字符包含 6 位随机数
+const TOKEN_LEN: usize = 32;
+const MAX_TAGS: usize = 10;
+const TAG_MAX_LEN: usize = 32;
+const MAX_LIST_LIMIT: i64 = 500;
+const API_KEY_LEN: usize = 40;
+const PASSWORD_MIN_LEN: usize = 8;
+const PASSWORD_MAX_LEN: usize = 128;
+const UTM_MAX_LEN: usize = 256;
+const MAX_VARIANTS: usize = 10;
+const MAX_VARIANT_WEIGHT: u32 = 10_000;
+const REPORT_DETAILS_MAX_LEN: usize = 1000;
+const MODERATION_REASON_MAX_LEN: usize = 500;
+// 举报者 IP 的哈希使用和访问记录不同的密钥，两者无法互相对应
+const REPORTER_HASH_CONTEXT: &str = "shortener 2024-06 reporter ip hash";
+const WEBHOOK_SECRET_PREFIX: &str = "whsec_";
+const WEBHOOK_SECRET_LEN: usize = 32;
+const MAX_CLICK_THRESHOLDS: usize = 10;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub store: Arc<dyn LinkStore>,
+    pub ids: Arc<dyn IdGenerator>,
+    pub destinations: Arc<DestinationPolicy>,
+    pub domains: Arc<Domains>,
+    pub clicks: ClickRecorder,
+    pub cache: Arc<LinkCache>,
+    pub limiter: Arc<RateLimiter>,
+    pub stateless: Arc<StatelessLinks>,
+    pub webhooks: Arc<Webhooks>,
+    reporter_hasher: IpHasher,
+}
+
+impl AppState {
+    pub async fn try_new(config: Config) -> Result<Self> {
+        let store = store::connect(&config.database_url, config.auto_migrate).await?;
+        Ok(Self::new(config, store))
+    }
+
+    pub fn new(config: Config, store: Arc<dyn LinkStore>) -> Self {
+        let domains = Arc::new(Domains::new(&config));
+        let webhooks = Arc::new(Webhooks::new(&config, store.clone(), domains.clone()));
+        Self {
+            ids: id::new_generator(&config, store.clone()),
+            destinations: Arc::new(DestinationPolicy::new(&config)),
+            domains,
+            stateless: Arc::new(StatelessLinks::new(&config.stateless_keys)),
+            cache: Arc::new(LinkCache::new(
+                config.cache_capacity,
+                config.cache_ttl,
+                config.cache_negative_ttl,
+            )),
+            clicks: ClickRecorder::new(store.clone(), webhooks.clone(), &config.ip_hash_secret),
+            reporter_hasher: IpHasher::new(&config.ip_hash_secret, REPORTER_HASH_CONTEXT),
+            config: Arc::new(config),
+            webhooks,
+            limiter: Arc::new(RateLimiter::default()),
+            store,
+        }
+    }
+
+    // 创建短链接。调用方没有提供管理令牌时生成一个新的令牌，和短链接一起返回；
+    // 令牌只返回这一次，数据库中只保存它的哈希。
+    // 用同一个令牌创建的短链接属于同一个所有者，可以用这个令牌一起管理。
+    // 请求中没有指定域名时，短链接属于 domain（通常由请求的 Host 决定）
+    pub async fn shorten(
+        &self,
+        req: &ShortenReq,
+        domain: &str,
+        token: Option<&str>,
+    ) -> Result<Shortened, ShortenerError> {
+        // 和保存的地址一样规范化，受密码保护的短链接只保存密文，所以在这里单独计算
+        let destination = self
+            .destinations
+            .normalize(&req.url)
+            .map_err(ShortenerError::Validation)?;
+        // 无状态短链接不保存到数据库，没有所有者，也就不需要管理令牌
+        if req.stateless {
+            let url = self.shorten_stateless(req, domain)?;
+            return Ok(Shortened {
+                url,
+                destination,
+                token: None,
+            });
+        }
+        let (owner, new_token) = match token {
+            Some(token) => (hash_token(validate_token(token)?), None),
+            None => {
+                let token = nanoid!(TOKEN_LEN);
+                (hash_token(&token), Some(token))
+            }
+        };
+        let link = self.prepare_link(req, domain, owner).await?;
+        let url = self.create_links(vec![link]).await?.remove(0)?;
+        Ok(Shortened {
+            url,
+            destination,
+            token: new_token,
+        })
+    }
+
+    // 批量创建属于同一个所有者的短链接，每一行的校验规则和去重方式都和 shorten 相同。
+    // 返回的短链接地址和 reqs 一一对应，某一行失败不影响其他行；整批失败时（例如数据库不可用）返回 Err
+    pub async fn shorten_many(
+        &self,
+        reqs: Vec<Result<ShortenReq, ShortenerError>>,
+        domain: &str,
+        token: &str,
+    ) -> Result<Vec<Result<String, ShortenerError>>, ShortenerError> {
+        let owner = hash_token(validate_token(token)?);
+        // 无状态短链接和校验失败的行直接得到结果，其余的行为 None，等保存之后按顺序填入
+        let mut results = Vec::with_capacity(reqs.len());
+        let mut links = Vec::new();
+        for req in reqs {
+            results.push(match req {
+                Ok(req) if req.stateless => Some(self.shorten_stateless(&req, domain)),
+                Ok(req) => match self.prepare_link(&req, domain, owner.clone()).await {
+                    Ok(link) => {
+                        links.push(link);
+                        None
+                    }
+                    Err(e) => Some(Err(e)),
+                },
+                Err(e) => Some(Err(e)),
+            });
+        }
+        let mut created = self.create_links(links).await?.into_iter();
+        Ok(results
+            .into_iter()
+            .map(|ret| ret.unwrap_or_else(|| created.next().unwrap()))
+            .collect())
+    }
+
+    // 无状态短链接把加密后的目标地址放在路径中，只能设置目标地址和域名，
+    // 其他选项都需要保存在数据库中，不能和它一起使用
+    fn shorten_stateless(&self, req: &ShortenReq, domain: &str) -> Result<String, ShortenerError> {
+        let domain = self.request_domain(req, domain)?;
+        let url = self
+            .destinations
+            .normalize(&req.url)
+            .map_err(ShortenerError::Validation)?;
+        let unsupported = [
+            ("alias", req.alias.is_some()),
+            ("expires_at", req.expires_at.is_some()),
+            ("not_before", req.not_before.is_some()),
+            ("max_clicks", req.max_clicks.is_some()),
+            ("redirect_type", req.redirect_type.is_some()),
+            ("tags", !req.tags.is_empty()),
+            ("interstitial", req.interstitial),
+            ("password", req.password.is_some()),
+            ("passthrough", req.passthrough),
+            ("utm", !req.utm.is_empty()),
+            ("routing", !req.routing.is_empty()),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(ShortenerError::Validation(format!(
+                "{name} cannot be used with stateless links"
+            )));
+        }
+        let token = self.stateless.seal(&url).ok_or_else(|| {
+            ShortenerError::Validation("stateless links are not enabled on this server".to_string())
+        })?;
+        Ok(self.domains.short_url(domain, &token))
+    }
+
+    // 解密无状态短链接，token 无效或者密钥已经被删除时和不存在的短链接一样返回 404
+    pub fn open_stateless(&self, token: &str) -> Result<String, ShortenerError> {
+        self.stateless
+            .open(token)
+            .ok_or_else(|| ShortenerError::not_found(token))
+    }
+
+    // 规则中的目标地址和短链接的目标地址一样需要校验和规范化，每种设备最多一条规则
+    fn normalize_routing(&self, routing: &Routing) -> Result<Routing, ShortenerError> {
+        let mut normalized = routing.clone();
+        for (i, rule) in routing.devices.iter().enumerate() {
+            if routing.devices[..i].iter().any(|r| r.device == rule.device) {
+                return Err(ShortenerError::Validation(format!(
+                    "duplicate routing rule for device {}",
+                    rule.device
+                )));
+            }
+        }
+        if routing.variants.len() > MAX_VARIANTS {
+            return Err(ShortenerError::Validation(format!(
+                "a link can have at most {MAX_VARIANTS} variants"
+            )));
+        }
+        if routing
+            .variants
+            .iter()
+            .any(|v| !(1..=MAX_VARIANT_WEIGHT).contains(&v.weight))
+        {
+            return Err(ShortenerError::Validation(format!(
+                "variant weight must be between 1 and {MAX_VARIANT_WEIGHT}"
+            )));
+        }
+        let urls = normalized
+            .devices
+            .iter_mut()
+            .map(|rule| &mut rule.url)
+            .chain(normalized.variants.iter_mut().map(|v| &mut v.url));
+        for url in urls {
+            *url = self
+                .destinations
+                .normalize(url)
+                .map_err(ShortenerError::Validation)?;
+        }
+        Ok(normalized)
+    }
+
+    // 请求中指定的域名必须是配置过的域名，没有指定时使用 domain
+    fn request_domain<'a>(
+        &'a self,
+        req: &ShortenReq,
+        domain: &'a str,
+    ) -> Result<&'a str, ShortenerError> {
+        match &req.domain {
+            Some(name) => self.domains.find(name).ok_or_else(|| {
+                ShortenerError::Validation(format!("domain {name:?} is not served here"))
+            }),
+            None => Ok(domain),
+        }
+    }
+
+    // 校验请求并转换成需要保存的数据，随机短链接的 id 在保存时才生成
+    async fn prepare_link(
+        &self,
+        req: &ShortenReq,
+        domain: &str,
+        owner: String,
+    ) -> Result<NewLink, ShortenerError> {
+        let domain = self.request_domain(req, domain)?;
+        let url = self
+            .destinations
+            .normalize(&req.url)
+            .map_err(ShortenerError::Validation)?;
+        if let Some(alias) = &req.alias {
+            validate_alias(alias)?;
+        }
+        validate_policy(req)?;
+        validate_tags(&req.tags)?;
+        validate_utm(&req.utm)?;
+        let routing = self.normalize_routing(&req.routing)?;
+        // 受保护的短链接只保存加密后的目标地址
+        let (url, sealed_url) = match &req.password {
+            // 规则中的目标地址是明文，不能和密码一起使用
+            Some(_) if !routing.is_empty() => {
+                return Err(ShortenerError::Validation(
+                    "routing cannot be used with password protected links".to_string(),
+                ));
+            }
+            Some(password) => {
+                validate_password(password)?;
+                let password = password.clone();
+                let sealed = tokio::task::spawn_blocking(move || password::seal(&url, &password))
+                    .await
+                    .map_err(anyhow::Error::from)??;
+                (String::new(), Some(sealed))
+            }
+            None => (url, None),
+        };
+        Ok(NewLink {
+            domain: domain.to_string(),
+            id: req.alias.clone().unwrap_or_default(),
+            url,
+            custom: req.alias.is_some(),
+            expires_at: req.expires_at,
+            not_before: req.not_before,
+            max_clicks: req.max_clicks,
+            owner: Some(owner),
+            redirect_type: req.redirect_type,
+            tags: req.tags.clone(),
+            interstitial: req.interstitial,
+            sealed_url,
+            passthrough: req.passthrough,
+            utm: req.utm.clone(),
+            routing,
+        })
+    }
+
+    // 保存一批短链接，返回的结果（短链接的完整地址）和 links 一一对应。
+    // 自定义短链接冲突时直接返回错误，交给调用方处理；
+    // 生成的 id 可能和已有的 id 冲突（包括自定义短链接），冲突的行重新生成 id 之后再保存一次
+    async fn create_links(
+        &self,
+        links: Vec<NewLink>,
+    ) -> Result<Vec<Result<String, ShortenerError>>, ShortenerError> {
+        let mut results: Vec<_> = links.iter().map(|_| None).collect();
+        let mut pending: Vec<_> = links.into_iter().enumerate().collect();
+        let mut created_links = Vec::new();
+        for attempt in 0..MAX_ID_ATTEMPTS {
+            if pending.is_empty() {
+                break;
+            }
+            for (_, link) in pending.iter_mut().filter(|(_, link)| !link.custom) {
+                link.id = self.generate_id(attempt).await?;
+            }
+            let batch: Vec<_> = pending.iter().map(|(_, link)| link.clone()).collect();
+            let created = self.store.create_many(&batch).await?;
+
+            let mut retry = Vec::new();
+            for ((i, link), ret) in pending.into_iter().zip(created) {
+                match ret {
+                    Err(e)
+                        if !link.custom
+                            && matches!(e.downcast_ref(), Some(StoreError::Conflict(_))) =>
+                    {
+                        warn!("Generated id {} already exists, retrying", link.id);
+                        retry.push((i, link));
+                    }
+                    Ok(id) => {
+                        // 这个 id 之前可能被当作“不存在”缓存了
+                        self.cache.invalidate(&link.domain, &id);
+                        results[i] = Some(Ok(self.domains.short_url(&link.domain, &id)));
+                        // 返回的 id 和 link.id 不同时，是去重之后返回的已有短链接
+                        if id == link.id {
+                            created_links.push(self.webhooks.link_data(&UrlRecord::from(&link)));
+                        }
+                    }
+                    Err(e) => results[i] = Some(Err(e.into())),
+                }
+            }
+            pending = retry;
+        }
+        self.webhooks
+            .emit(WebhookEvent::Created, created_links)
+            .await;
+        Ok(results
+            .into_iter()
+            .map(|ret| {
+                ret.unwrap_or_else(|| {
+                    Err(ShortenerError::Internal(anyhow!(
+                        "failed to generate a unique id after {MAX_ID_ATTEMPTS} attempts"
+                    )))
+                })
+            })
+            .collect())
+    }
+
+    // 跳过被服务本身占用的 id
+    async fn generate_id(&self, attempt: u32) -> Result<String> {
+        loop {
+            let id = self.ids.generate(attempt).await?;
+            if !is_reserved(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    // 查找当前有效的短链接，但不扣减访问次数，例如生成二维码时
+    pub async fn lookup(&self, domain: &str, id: &str) -> Result<UrlRecord, ShortenerError> {
+        let Some(record) = self.get_link(domain, id).await? else {
+            return Err(ShortenerError::not_found(id));
+        };
+
+        // 缓存中的过期时间和生效时间不会过时，剩余访问次数可能已经过时，
+        // 但是这里只用它判断是否有次数限制，真正的扣减由存储原子地完成
+        let now = Utc::now();
+        // 还没到生效时间的短链接对访问者来说相当于不存在
+        if record.not_before.is_some_and(|t| t > now) {
+            return Err(ShortenerError::not_found(id));
+        }
+        if record.expires_at.is_some_and(|t| t <= now) {
+            return Err(ShortenerError::Expired(format!(
+                "short link {id:?} has expired"
+            )));
+        }
+        match record.status {
+            LinkStatus::Active => Ok(record),
+            LinkStatus::Disabled => Err(ShortenerError::Disabled(format!(
+                "short link {id:?} has been disabled"
+            ))),
+            LinkStatus::Blocked => Err(ShortenerError::Blocked(format!(
+                "short link {id:?} is unavailable for legal reasons"
+            ))),
+        }
+    }
+
+    // 访问短链接时调用。有访问次数限制的短链接需要原子地扣减次数，次数已用完时返回错误
+    pub async fn take_click(&self, record: &UrlRecord) -> Result<(), ShortenerError> {
+        let (domain, id) = (&record.domain, &record.id);
+        if record.remaining_clicks.is_some() && !self.store.take_click(domain, id).await? {
+            return Err(ShortenerError::Expired(format!(
+                "short link {id:?} has reached its click limit"
+            )));
+        }
+        Ok(())
+    }
+
+    // 用密码解锁受保护的短链接，成功时扣减访问次数并返回带有明文目标地址的记录，密码错误时返回 None
+    pub async fn unlock(
+        &self,
+        domain: &str,
+        id: &str,
+        password: &str,
+    ) -> Result<Option<UrlRecord>, ShortenerError> {
+        let mut record = self.lookup(domain, id).await?;
+        let Some(sealed) = record.sealed_url.clone() else {
+            return Err(ShortenerError::Validation(format!(
+                "short link {id:?} is not password protected"
+            )));
+        };
+        let password = password.to_string();
+        // Argon2 很慢，不能阻塞异步运行时的线程
+        let url = tokio::task::spawn_blocking(move || password::open(&sealed, &password))
+            .await
+            .map_err(anyhow::Error::from)??;
+        let Some(url) = url else {
+            return Ok(None);
+        };
+        self.take_click(&record).await?;
+        record.url = url;
+        Ok(Some(record))
+    }
+
+    // 导入一个短链接，保留它的 id。已经存在同一个短链接（目标地址和所有者都相同）时什么都不做，
+    // 所以同一份导出文件可以重复导入；id 被其他短链接占用时返回冲突。
+    // dry_run 为 true 时只检查不保存，这时和同一个所有者的永久短链接重复的情况要到真正保存时才能发现
+    pub async fn import_link(
+        &self,
+        mut record: UrlRecord,
+        dry_run: bool,
+    ) -> Result<ImportOutcome, ShortenerError> {
+        validate_imported_id(&record.id)?;
+        if !record.domain.is_empty() && self.domains.find(&record.domain) != Some(&record.domain) {
+            return Err(ShortenerError::Validation(format!(
+                "domain {:?} is not served here",
+                record.domain
+            )));
+        }
+        // 受保护的短链接没有明文地址
+        if record.sealed_url.is_none() {
+            record.url = self
+                .destinations
+                .normalize(&record.url)
+                .map_err(ShortenerError::Validation)?;
+        } else if !record.routing.is_empty() {
+            return Err(ShortenerError::Validation(
+                "routing cannot be used with password protected links".to_string(),
+            ));
+        }
+        record.routing.0 = self.normalize_routing(&record.routing)?;
+        validate_tags(&record.tags)?;
+        validate_utm(&record.utm)?;
+
+        let (domain, id) = (&record.domain, &record.id);
+        if let Some(existing) = self.store.get(domain, id).await? {
+            let same = existing.url == record.url
+                && existing.sealed_url == record.sealed_url
+                && existing.owner == record.owner;
+            return Ok(if same {
+                ImportOutcome::Unchanged
+            } else {
+                ImportOutcome::Conflict(format!("id {id:?} is already taken by another link"))
+            });
+        }
+        if dry_run {
+            return Ok(ImportOutcome::Created);
+        }
+        match self.store.import(&record).await {
+            Ok(()) => {
+                self.cache.invalidate(domain, id);
+                Ok(ImportOutcome::Created)
+            }
+            Err(e) => match e.downcast_ref::<StoreError>() {
+                Some(e) => Ok(ImportOutcome::Conflict(e.to_string())),
+                None => Err(e.into()),
+            },
+        }
+    }
+
+    // 只有短链接的所有者可以修改它
+    pub async fn update(
+        &self,
+        domain: &str,
+        id: &str,
+        token: &str,
+        patch: &LinkPatch,
+    ) -> Result<UrlRecord, ShortenerError> {
+        let record = self.authorize(domain, id, token).await?;
+        // 受保护的短链接只保存了密文，修改目标地址需要重新创建
+        if record.sealed_url.is_some() && patch.url.is_some() {
+            return Err(ShortenerError::Validation(
+                "the destination of a password protected link cannot be changed".to_string(),
+            ));
+        }
+        if record.sealed_url.is_some() && patch.routing.as_ref().is_some_and(|r| !r.is_empty()) {
+            return Err(ShortenerError::Validation(
+                "routing cannot be used with password protected links".to_string(),
+            ));
+        }
+        let url = patch
+            .url
+            .as_deref()
+            .map(|url| self.destinations.normalize(url))
+            .transpose()
+            .map_err(ShortenerError::Validation)?;
+        if let Some(tags) = &patch.tags {
+            validate_tags(tags)?;
+        }
+        if let Some(utm) = &patch.utm {
+            validate_utm(utm)?;
+        }
+        let routing = patch
+            .routing
+            .as_ref()
+            .map(|routing| self.normalize_routing(routing))
+            .transpose()?;
+        let update = LinkUpdate {
+            url,
+            redirect_type: patch.redirect_type,
+            tags: patch.tags.clone(),
+            interstitial: patch.interstitial,
+            passthrough: patch.passthrough,
+            utm: patch.utm.clone(),
+            routing,
+        };
+        // 检查所有者之后短链接可能已经被删除了
+        if !self.store.update(domain, id, &update).await? {
+            return Err(ShortenerError::not_found(id));
+        }
+        self.cache.invalidate(domain, id);
+        let record = self
+            .store
+            .get(domain, id)
+            .await?
+            .ok_or_else(|| ShortenerError::not_found(id))?;
+        self.emit_link(WebhookEvent::Updated, &record).await;
+        Ok(record)
+    }
+
+    pub async fn delete(&self, domain: &str, id: &str, token: &str) -> Result<(), ShortenerError> {
+        let record = self.authorize(domain, id, token).await?;
+        self.remove(&record).await
+    }
+
+    // 删除短链接，不检查所有者，命令行工具直接调用
+    pub async fn remove(&self, record: &UrlRecord) -> Result<(), ShortenerError> {
+        let (domain, id) = (&record.domain, &record.id);
+        if !self.store.delete(domain, id).await? {
+            return Err(ShortenerError::not_found(id));
+        }
+        self.cache.invalidate(domain, id);
+        self.emit_link(WebhookEvent::Deleted, record).await;
+        Ok(())
+    }
+
+    // 删除已过期或访问次数已用完的短链接，返回删除的数量
+    pub async fn purge_dead_links(&self) -> Result<usize> {
+        let purged = self.store.purge_dead_links().await?;
+        for record in &purged {
+            self.cache.invalidate(&record.domain, &record.id);
+        }
+        let data = purged
+            .iter()
+            .map(|record| self.webhooks.link_data(record))
+            .collect();
+        self.webhooks.emit(WebhookEvent::Expired, data).await;
+        Ok(purged.len())
+    }
+
+    async fn emit_link(&self, event: WebhookEvent, record: &UrlRecord) {
+        let data = self.webhooks.link_data(record);
+        self.webhooks.emit(event, vec![data]).await;
+    }
+
+    // 列出令牌所有者的短链接
+    pub async fn list(
+        &self,
+        token: &str,
+        mut filter: LinkFilter,
+    ) -> Result<Vec<UrlRecord>, ShortenerError> {
+        let owner = hash_token(validate_token(token)?);
+        filter.limit = filter.limit.clamp(1, MAX_LIST_LIMIT);
+        filter.offset = filter.offset.max(0);
+        Ok(self.store.list(&owner, &filter).await?)
+    }
+
+    // 没有 key 时是匿名调用方，key 不存在或者已经被吊销时返回 401
+    pub async fn authenticate(
+        &self,
+        key: Option<&str>,
+        ip: IpAddr,
+    ) -> Result<Caller, ShortenerError> {
+        let Some(key) = key else {
+            return Ok(Caller::Anonymous(ip));
+        };
+        // 比较哈希而不是直接比较字符串，比较所用的时间和 key 的内容无关
+        let key_hash = hash_token(key);
+        if let Some(admin_key) = &self.config.admin_key {
+            if hash_token(admin_key) == key_hash {
+                return Ok(Caller::Admin);
+            }
+        }
+        match self.store.find_api_key(&key_hash).await? {
+            Some(key) => Ok(Caller::Key(key)),
+            None => Err(ShortenerError::Unauthorized(
+                "invalid or revoked API key".to_string(),
+            )),
+        }
+    }
+
+    // 创建短链接前检查调用方的配额
+    pub fn check_rate_limit(&self, caller: &Caller) -> Result<(), ShortenerError> {
+        let (bucket, quota) = match caller {
+            Caller::Admin => return Ok(()),
+            Caller::Key(key) => {
+                let default = self.config.key_quota;
+                let quota = Quota {
+                    per_minute: key.rate_limit.map_or(default.per_minute, |n| n as u32),
+                    burst: key.burst.map_or(default.burst, |n| n as u32),
+                };
+                (format!("key:{}", key.id), quota)
+            }
+            Caller::Anonymous(ip) => (format!("ip:{ip}"), self.config.anonymous_quota),
+        };
+        self.limiter
+            .check(&bucket, quota)
+            .map_err(|retry_after| ShortenerError::RateLimited { retry_after })
+    }
+
+    // 密码尝试按 IP 限流，配额和匿名创建短链接相同，用来拖慢暴力破解
+    pub fn check_unlock_rate_limit(&self, ip: IpAddr) -> Result<(), ShortenerError> {
+        self.limiter
+            .check(&format!("unlock:{ip}"), self.config.anonymous_quota)
+            .map_err(|retry_after| ShortenerError::RateLimited { retry_after })
+    }
+
+    // 签发新的 API key，返回 key 的记录和明文
+    pub async fn issue_api_key(
+        &self,
+        req: &IssueKeyReq,
+    ) -> Result<(ApiKey, String), ShortenerError> {
+        if req.name.trim().is_empty() {
+            return Err(ShortenerError::Validation(
+                "name must not be empty".to_string(),
+            ));
+        }
+        if req.scopes.is_empty() {
+            return Err(ShortenerError::Validation(
+                "at least one scope is required".to_string(),
+            ));
+        }
+        if req
+            .rate_limit
+            .is_some_and(|n| !(1..=u32::MAX as i64).contains(&n))
+            || req
+                .burst
+                .is_some_and(|n| !(1..=u32::MAX as i64).contains(&n))
+        {
+            return Err(ShortenerError::Validation(
+                "rate_limit and burst must be positive".to_string(),
+            ));
+        }
+        let secret = format!("{API_KEY_PREFIX}{}", nanoid!(API_KEY_LEN));
+        let key = ApiKey {
+            id: nanoid!(12),
+            name: req.name.trim().to_string(),
+            key_hash: hash_token(&secret),
+            scopes: Json(req.scopes.clone()),
+            rate_limit: req.rate_limit,
+            burst: req.burst,
+            created_at: Utc::now(),
+            revoked_at: None,
+        };
+        self.store.create_api_key(&key).await?;
+        Ok((key, secret))
+    }
+
+    pub async fn revoke_api_key(&self, id: &str) -> Result<(), ShortenerError> {
+        if !self.store.revoke_api_key(id).await? {
+            return Err(ShortenerError::NotFound(format!(
+                "API key {id:?} does not exist or has been revoked"
+            )));
+        }
+        Ok(())
+    }
+
+    // 任何人都可以举报短链接，按 IP 限流。已经被停用的短链接也可以举报
+    pub async fn report(
+        &self,
+        domain: &str,
+        id: &str,
+        req: &ReportReq,
+        ip: IpAddr,
+    ) -> Result<(), ShortenerError> {
+        self.limiter
+            .check(&format!("report:{ip}"), self.config.anonymous_quota)
+            .map_err(|retry_after| ShortenerError::RateLimited { retry_after })?;
+        let details = req
+            .details
+            .as_deref()
+            .map(str::trim)
+            .filter(|details| !details.is_empty());
+        if details.is_some_and(|details| details.chars().count() > REPORT_DETAILS_MAX_LEN) {
+            return Err(ShortenerError::Validation(format!(
+                "details must be at most {REPORT_DETAILS_MAX_LEN} characters"
+            )));
+        }
+        if self.store.get(domain, id).await?.is_none() {
+            return Err(ShortenerError::not_found(id));
+        }
+        let report = AbuseReport {
+            id: nanoid!(12),
+            domain: domain.to_string(),
+            link_id: id.to_string(),
+            reason: req.reason,
+            details: details.map(String::from),
+            reporter_hash: self.reporter_hasher.hash(ip),
+            created_at: Utc::now(),
+            resolved_at: None,
+        };
+        Ok(self.store.insert_report(&report).await?)
+    }
+
+    pub async fn list_reports(
+        &self,
+        mut filter: ReportFilter,
+    ) -> Result<Vec<AbuseReport>, ShortenerError> {
+        filter.limit = filter.limit.clamp(1, MAX_LIST_LIMIT);
+        filter.offset = filter.offset.max(0);
+        Ok(self.store.list_reports(&filter).await?)
+    }
+
+    // 修改短链接的审核状态，并在审核记录中留下操作者和原因
+    pub async fn moderate(
+        &self,
+        domain: &str,
+        id: &str,
+        req: &ModerateReq,
+        actor: String,
+    ) -> Result<UrlRecord, ShortenerError> {
+        let reason = req.reason.trim();
+        if reason.is_empty() || reason.chars().count() > MODERATION_REASON_MAX_LEN {
+            return Err(ShortenerError::Validation(format!(
+                "reason must be between 1 and {MODERATION_REASON_MAX_LEN} characters"
+            )));
+        }
+        let action = ModerationAction {
+            id: nanoid!(12),
+            domain: domain.to_string(),
+            link_id: id.to_string(),
+            status: req.status,
+            reason: reason.to_string(),
+            actor,
+            created_at: Utc::now(),
+        };
+        if !self.store.moderate(&action).await? {
+            return Err(ShortenerError::not_found(id));
+        }
+        info!("Short link {domain}/{id} is now {} ({reason})", req.status);
+        self.cache.invalidate(domain, id);
+        let record = self
+            .store
+            .get(domain, id)
+            .await?
+            .ok_or_else(|| ShortenerError::not_found(id))?;
+        self.emit_link(WebhookEvent::Updated, &record).await;
+        Ok(record)
+    }
+
+    // 短链接当前的记录和它的审核记录，短链接被删除之后仍然可以查看审核记录
+    pub async fn moderation_log(
+        &self,
+        domain: &str,
+        id: &str,
+    ) -> Result<(Option<UrlRecord>, Vec<ModerationAction>), ShortenerError> {
+        let record = self.store.get(domain, id).await?;
+        let actions = self.store.moderation_log(domain, id).await?;
+        if record.is_none() && actions.is_empty() {
+            return Err(ShortenerError::not_found(id));
+        }
+        Ok((record, actions))
+    }
+
+    // 订阅短链接事件，返回的记录中带有签名用的密钥
+    pub async fn create_webhook(&self, req: &WebhookReq) -> Result<Webhook, ShortenerError> {
+        let invalid = |msg: String| Err(ShortenerError::Validation(msg));
+        let url = match Url::parse(req.url.trim()) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => url,
+            _ => return invalid(format!("{:?} is not an http or https url", req.url)),
+        };
+        let mut events = Vec::new();
+        for event in &req.events {
+            if !events.contains(event) {
+                events.push(*event);
+            }
+        }
+        if events.is_empty() {
+            return invalid("at least one event is required".to_string());
+        }
+        let mut thresholds = req.click_thresholds.clone();
+        thresholds.sort_unstable();
+        thresholds.dedup();
+        if thresholds.len() > MAX_CLICK_THRESHOLDS || thresholds.iter().any(|&n| n < 1) {
+            return invalid(format!(
+                "click_thresholds must be at most {MAX_CLICK_THRESHOLDS} positive numbers"
+            ));
+        }
+        let clicks = events.contains(&WebhookEvent::Clicks);
+        if clicks == thresholds.is_empty() {
+            return invalid(format!(
+                "click_thresholds must be set exactly when subscribing to {}",
+                WebhookEvent::Clicks
+            ));
+        }
+        let webhook = Webhook {
+            id: nanoid!(12),
+            url: url.to_string(),
+            secret: format!("{WEBHOOK_SECRET_PREFIX}{}", nanoid!(WEBHOOK_SECRET_LEN)),
+            events: Json(events),
+            click_thresholds: Json(thresholds),
+            created_at: Utc::now(),
+        };
+        self.store.create_webhook(&webhook).await?;
+        Ok(webhook)
+    }
+
+    pub async fn list_webhooks(&self) -> Result<Vec<Webhook>, ShortenerError> {
+        Ok(self.store.list_webhooks().await?)
+    }
+
+    pub async fn delete_webhook(&self, id: &str) -> Result<(), ShortenerError> {
+        if !self.store.delete_webhook(id).await? {
+            return Err(webhook_not_found(id));
+        }
+        Ok(())
+    }
+
+    pub async fn dead_letters(&self, id: &str) -> Result<Vec<DeadLetter>, ShortenerError> {
+        self.find_webhook(id).await?;
+        Ok(self.store.list_dead_letters(id).await?)
+    }
+
+    // 把重试次数用完的投递重新放回队列，返回放回的数量
+    pub async fn replay_dead_letters(&self, id: &str) -> Result<u64, ShortenerError> {
+        self.find_webhook(id).await?;
+        let replayed = self.store.replay_dead_letters(id, Utc::now()).await?;
+        if replayed > 0 {
+            info!("Replaying {replayed} dead letters of webhook {id}");
+            self.webhooks.wake();
+        }
+        Ok(replayed)
+    }
+
+    async fn find_webhook(&self, id: &str) -> Result<Webhook, ShortenerError> {
+        self.store
+            .list_webhooks()
+            .await?
+            .into_iter()
+            .find(|webhook| webhook.id == id)
+            .ok_or_else(|| webhook_not_found(id))
+    }
+
+    // 不经过缓存，直接读取存储中的所有者
+    async fn authorize(
+        &self,
+        domain: &str,
+        id: &str,
+        token: &str,
+    ) -> Result<UrlRecord, ShortenerError> {
+        let owner = hash_token(validate_token(token)?);
+        let Some(record) = self.store.get(domain, id).await? else {
+            return Err(ShortenerError::not_found(id));
+        };
+        if record.owner.as_deref() != Some(owner.as_str()) {
+            return Err(ShortenerError::Forbidden(format!(
+                "the management token does not own short link {id:?}"
+            )));
+        }
+        Ok(record)
+    }
+
+    // 先查缓存，没有命中时再查询存储并写入缓存
+    async fn get_link(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>> {
+        if let Some(record) = self.cache.get(domain, id) {
+            return Ok(record);
+        }
+        let record = self.store.get(domain, id).await?;
+        self.cache.insert(domain, id, record.clone());
+        Ok(record)
+    }
+
+    // 只有所有者可以查看访问统计
+    pub async fn owner_stats(
+        &self,
+        domain: &str,
+        id: &str,
+        token: &str,
+        bucket: Bucket,
+    ) -> Result<LinkStats, ShortenerError> {
+        self.authorize(domain, id, token).await?;
+        Ok(self.store.link_stats(domain, id, bucket).await?)
+    }
+
+    // 不检查所有者，管理员和命令行工具调用
+    pub async fn stats(
+        &self,
+        domain: &str,
+        id: &str,
+        bucket: Bucket,
+    ) -> Result<LinkStats, ShortenerError> {
+        if self.store.get(domain, id).await?.is_none() {
+            return Err(ShortenerError::not_found(id));
+        }
+        Ok(self.store.link_stats(domain, id, bucket).await?)
+    }
+}
+
+// 新建的短链接
+#[derive(Debug)]
+pub struct Shortened {
+    // 短链接的完整地址
+    pub url: String,
+    // 规范化之后的目标地址，去重时和已有短链接的 url 相同
+    pub destination: String,
+    // 新生成的管理令牌，调用方提供了令牌时为 None
+    pub token: Option<String>,
+}
+
+// 导入一个短链接的结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportOutcome {
+    Created,
+    // 同一个短链接已经存在
+    Unchanged,
+    // id 被其他短链接占用，或者和同一个所有者的永久短链接重复
+    Conflict(String),
+}
+
+pub async fn sweep_dead_links(state: AppState) {
+    let mut interval = time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.purge_dead_links().await {
+            Ok(0) => {}
+            Ok(n) => info!("Purged {n} dead links"),
+            Err(e) => warn!("Failed to purge dead links: {e}"),
+        }
+    }
+}
+
+fn validate_policy(req: &ShortenReq) -> Result<(), ShortenerError> {
+    let invalid = |msg: &str| Err(ShortenerError::Validation(msg.to_string()));
+    if req.max_clicks.is_some_and(|n| n < 1) {
+        return invalid("max_clicks must be at least 1");
+    }
+    if req.expires_at.is_some_and(|t| t <= Utc::now()) {
+        return invalid("expires_at must be in the future");
+    }
+    if let (Some(not_before), Some(expires_at)) = (req.not_before, req.expires_at) {
+        if not_before >= expires_at {
+            return invalid("not_before must be earlier than expires_at");
+        }
+    }
+    Ok(())
+}
+
+fn validate_password(password: &str) -> Result<(), ShortenerError> {
+    let len = password.chars().count();
+    if !(PASSWORD_MIN_LEN..=PASSWORD_MAX_LEN).contains(&len) {
+        return Err(ShortenerError::Validation(format!(
+            "password must be between {PASSWORD_MIN_LEN} and {PASSWORD_MAX_LEN} characters"
+        )));
+    }
+    Ok(())
+}
+
+fn validate_alias(alias: &str) -> Result<(), ShortenerError> {
+    if !(ALIAS_MIN_LEN..=ALIAS_MAX_LEN).contains(&alias.len()) {
+        return Err(ShortenerError::Validation(format!(
+            "alias must be between {ALIAS_MIN_LEN} and {ALIAS_MAX_LEN} characters"
+        )));
+    }
+    // 只允许 URL 安全的字符，和 nanoid 生成的字符集保持一致
+    if !alias
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(ShortenerError::Validation(
+            "alias may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+    if is_reserved(alias) {
+        return Err(ShortenerError::Validation(format!(
+            "alias {alias:?} is reserved"
+        )));
+    }
+    Ok(())
+}
+
+fn validate_utm(utm: &Utm) -> Result<(), ShortenerError> {
+    for (name, value) in utm.params() {
+        if value.is_empty() || value.chars().count() > UTM_MAX_LEN {
+            return Err(ShortenerError::Validation(format!(
+                "{name} must be between 1 and {UTM_MAX_LEN} characters"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// 导入的 id 可能是生成的，比自定义短链接的最小长度更短，其他规则相同
+fn validate_imported_id(id: &str) -> Result<(), ShortenerError> {
+    if id.is_empty() || id.len() > ALIAS_MAX_LEN {
+        return Err(ShortenerError::Validation(format!(
+            "id must be between 1 and {ALIAS_MAX_LEN} characters"
+        )));
+    }
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(ShortenerError::Validation(
+            "id may only contain letters, digits, '-' and '_'".to_string(),
+        ));
+    }
+    if is_reserved(id) {
+        return Err(ShortenerError::Validation(format!("id {id:?} is reserved")));
+    }
+    Ok(())
+}
+
+fn validate_tags(tags: &[String]) -> Result<(), ShortenerError> {
+    if tags.len() > MAX_TAGS {
+        return Err(ShortenerError::Validation(format!(
+            "a link may have at most {MAX_TAGS} tags"
+        )));
+    }
+    for tag in tags {
+        if tag.is_empty()
+            || tag.len() > TAG_MAX_LEN
+            || !tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ShortenerError::Validation(format!(
+                "invalid tag {tag:?}: tags must be 1 to {TAG_MAX_LEN} letters, digits, '-' or '_'"
+            )));
+        }
+    }
+    Ok(())
+}
+
+// 调用方也可以用自己的令牌创建短链接，太短的令牌容易被猜到
+pub fn validate_token(token: &str) -> Result<&str, ShortenerError> {
+    if token.len() < TOKEN_LEN {
+        return Err(ShortenerError::Unauthorized(
+            "invalid management token".to_string(),
+        ));
+    }
+    Ok(token)
+}
+
+// 令牌本身是高熵的随机字符串，不需要加盐或者使用慢哈希
+fn hash_token(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex().to_string()
+}
+
+fn webhook_not_found(id: &str) -> ShortenerError {
+    ShortenerError::NotFound(format!("webhook {id:?} does not exist"))
+}
+
+fn is_reserved(id: &str) -> bool {
+    RESERVED_ALIASES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(id))
+}
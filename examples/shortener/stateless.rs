@@ -0,0 +1,118 @@
+use crate::config::StatelessKey;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+const NONCE_LEN: usize = 12;
+// key id 和密文之间的分隔符。数据库中的 id 只包含字母、数字、'-' 和 '_'，
+// 所以带有分隔符的 id 一定是无状态短链接
+const SEPARATOR: char = '.';
+
+// 无状态短链接：目标地址用服务端的密钥加密之后直接放在路径中，形如 /{key id}.{密文}，
+// 跳转时解密即可，不需要查询数据库。密文使用 URL 安全的 base64 编码，内容是 nonce || 密文（包含认证标签），
+// key id 作为附加数据参与认证，不能被替换成其他 key 的 id。
+// 轮换密钥时把新的 key 放在配置的最前面，新的短链接使用它加密，旧的 key 继续用于解密已经发出去的短链接；
+// 从配置中删除一个 key 会让用它加密的短链接全部失效，这也是撤销无状态短链接的唯一方式
+pub struct StatelessLinks {
+    keys: Vec<(String, ChaCha20Poly1305)>,
+}
+
+impl StatelessLinks {
+    pub fn new(keys: &[StatelessKey]) -> Self {
+        let keys = keys
+            .iter()
+            .map(|k| {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&k.key));
+                (k.id.clone(), cipher)
+            })
+            .collect();
+        Self { keys }
+    }
+
+    pub fn is_token(id: &str) -> bool {
+        id.contains(SEPARATOR)
+    }
+
+    // 用第一个 key 加密目标地址，没有配置任何 key 时返回 None
+    pub fn seal(&self, url: &str) -> Option<String> {
+        let (id, cipher) = self.keys.first()?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: url.as_bytes(),
+            aad: id.as_bytes(),
+        };
+        // 只有明文过长（超过 256 GiB）时才会加密失败
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .expect("encrypting a url should never fail");
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        Some(format!("{id}{SEPARATOR}{}", URL_SAFE_NO_PAD.encode(data)))
+    }
+
+    // 解密 token，key id 不存在、格式错误或者认证失败时返回 None
+    pub fn open(&self, token: &str) -> Option<String> {
+        let (id, data) = token.split_once(SEPARATOR)?;
+        let (_, cipher) = self.keys.iter().find(|(key_id, _)| key_id == id)?;
+        let data = URL_SAFE_NO_PAD.decode(data).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: id.as_bytes(),
+        };
+        let url = cipher.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(url).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str, byte: u8) -> StatelessKey {
+        StatelessKey {
+            id: id.to_string(),
+            key: [byte; 32],
+        }
+    }
+
+    #[test]
+    fn tokens_survive_key_rotation() {
+        let old = StatelessLinks::new(&[key("k1", 1)]);
+        let token = old.seal("https://www.rust-lang.org/").unwrap();
+        assert!(token.starts_with("k1."));
+        assert!(StatelessLinks::is_token(&token));
+
+        let rotated = StatelessLinks::new(&[key("k2", 2), key("k1", 1)]);
+        assert!(rotated
+            .seal("https://tokio.rs/")
+            .unwrap()
+            .starts_with("k2."));
+        assert_eq!(
+            rotated.open(&token).as_deref(),
+            Some("https://www.rust-lang.org/")
+        );
+        // 删除旧的 key 之后，用它加密的短链接失效
+        assert_eq!(StatelessLinks::new(&[key("k2", 2)]).open(&token), None);
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let links = StatelessLinks::new(&[key("k1", 1), key("k2", 2)]);
+        let token = links.seal("https://www.rust-lang.org/").unwrap();
+        let (_, data) = token.split_once('.').unwrap();
+        // 换成另一个 key 的 id
+        assert_eq!(links.open(&format!("k2.{data}")), None);
+        let mut tampered = token.clone().into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+        assert_eq!(links.open(&String::from_utf8(tampered).unwrap()), None);
+        assert_eq!(links.open("k1.short"), None);
+        assert_eq!(StatelessLinks::new(&[]).seal("https://tokio.rs/"), None);
+    }
+}
@@ -0,0 +1,506 @@
+use super::{
+    AbuseReport, ApiKey, DeadLetter, LinkFilter, LinkStore, LinkUpdate, ModerationAction, NewLink,
+    ReportFilter, StoreError, UrlRecord, Webhook, WebhookDelivery,
+};
+use crate::clicks::{
+    Bucket, Click, ClickBucket, LinkStats, ReferrerCount, RuleCount, TOP_REFERRERS,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, DurationRound, Utc};
+use dashmap::{mapref::entry::Entry, DashMap};
+use sqlx::types::Json;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+// 基于 DashMap 的内存存储，进程退出后数据丢失，主要用于测试和本地开发
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    // 短链接由 (域名, id) 确定
+    links: DashMap<(String, String), UrlRecord>,
+    // 永久随机短链接的 (域名, 所有者, url) -> id 索引，用来去重
+    permanent: DashMap<(String, Option<String>, String), String>,
+    clicks: Mutex<Vec<Click>>,
+    sequence: AtomicU64,
+    api_keys: DashMap<String, ApiKey>,
+    reports: Mutex<Vec<AbuseReport>>,
+    moderation: Mutex<Vec<ModerationAction>>,
+    webhooks: Mutex<Vec<Webhook>>,
+    deliveries: Mutex<Vec<WebhookDelivery>>,
+    dead_letters: Mutex<Vec<DeadLetter>>,
+}
+
+#[async_trait]
+impl LinkStore for MemoryStore {
+    async fn create(&self, link: &NewLink) -> Result<String> {
+        if !link.is_permanent() {
+            return self.insert(link);
+        }
+        // entry 会锁住 url 所在的分片，保证并发创建相同 url 时只会插入一次
+        let key = (link.domain.clone(), link.owner.clone(), link.url.clone());
+        match self.permanent.entry(key) {
+            Entry::Occupied(e) => Ok(e.get().clone()),
+            Entry::Vacant(e) => {
+                let id = self.insert(link)?;
+                e.insert(id.clone());
+                Ok(id)
+            }
+        }
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>> {
+        Ok(self
+            .links
+            .get(&key(domain, id))
+            .map(|record| record.clone()))
+    }
+
+    async fn update(&self, domain: &str, id: &str, update: &LinkUpdate) -> Result<bool> {
+        let Some(mut record) = self.links.get_mut(&key(domain, id)) else {
+            return Ok(false);
+        };
+        // 修改 url 或者选项都可能让短链接进入或离开去重索引，先在副本上修改，
+        // 和数据库的部分唯一索引一样，和其他永久短链接重复时整个修改都不生效
+        let mut updated = record.clone();
+        if let Some(url) = &update.url {
+            updated.url = url.clone();
+        }
+        if let Some(redirect_type) = update.redirect_type {
+            updated.redirect_type = Some(redirect_type);
+        }
+        if let Some(tags) = &update.tags {
+            updated.tags = Json(tags.clone());
+        }
+        if let Some(interstitial) = update.interstitial {
+            updated.interstitial = interstitial;
+        }
+        if let Some(passthrough) = update.passthrough {
+            updated.passthrough = passthrough;
+        }
+        if let Some(utm) = &update.utm {
+            updated.utm = Json(utm.clone());
+        }
+        if let Some(routing) = &update.routing {
+            updated.routing = Json(routing.clone());
+        }
+        let old_key = (domain.to_string(), record.owner.clone(), record.url.clone());
+        let new_key = (
+            domain.to_string(),
+            updated.owner.clone(),
+            updated.url.clone(),
+        );
+        let was_permanent = self.permanent.get(&old_key).is_some_and(|e| *e == id);
+        let unchanged = was_permanent && updated.is_permanent() && old_key == new_key;
+        if updated.is_permanent() && !unchanged {
+            match self.permanent.entry(new_key) {
+                Entry::Occupied(_) => {
+                    return Err(StoreError::DuplicateUrl(updated.url.clone()).into());
+                }
+                Entry::Vacant(e) => {
+                    e.insert(id.to_string());
+                }
+            }
+        }
+        if was_permanent && !unchanged {
+            self.permanent.remove(&old_key);
+        }
+        *record = updated;
+        Ok(true)
+    }
+
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool> {
+        let Some((_, record)) = self.links.remove(&key(domain, id)) else {
+            return Ok(false);
+        };
+        self.permanent.remove_if(
+            &(record.domain, record.owner, record.url),
+            |_, permanent_id| permanent_id == id,
+        );
+        self.clicks
+            .lock()
+            .unwrap()
+            .retain(|click| click.domain != domain || click.link_id != id);
+        Ok(true)
+    }
+
+    async fn list(&self, owner: &str, filter: &LinkFilter) -> Result<Vec<UrlRecord>> {
+        let mut links: Vec<_> = self
+            .links
+            .iter()
+            .filter(|record| record.owner.as_deref() == Some(owner) && record.matches(filter))
+            .map(|record| record.clone())
+            .collect();
+        links.sort_by(|a, b| {
+            b.created_at
+                .cmp(&a.created_at)
+                .then(a.domain.cmp(&b.domain))
+                .then(a.id.cmp(&b.id))
+        });
+        Ok(links
+            .into_iter()
+            .skip(filter.offset as usize)
+            .take(filter.limit as usize)
+            .collect())
+    }
+
+    async fn export(&self, after: Option<(&str, &str)>, limit: i64) -> Result<Vec<UrlRecord>> {
+        let mut links: Vec<_> = self
+            .links
+            .iter()
+            .filter(|record| {
+                after.is_none_or(|after| (record.domain.as_str(), record.id.as_str()) > after)
+            })
+            .map(|record| record.clone())
+            .collect();
+        links.sort_by(|a, b| a.domain.cmp(&b.domain).then(a.id.cmp(&b.id)));
+        links.truncate(limit as usize);
+        Ok(links)
+    }
+
+    async fn import(&self, record: &UrlRecord) -> Result<()> {
+        if !record.is_permanent() {
+            return self.insert_record(record);
+        }
+        // 和 create 一样先锁住去重索引，再锁住短链接，避免两者以相反的顺序加锁
+        let key = (
+            record.domain.clone(),
+            record.owner.clone(),
+            record.url.clone(),
+        );
+        match self.permanent.entry(key) {
+            Entry::Occupied(_) => Err(StoreError::DuplicateUrl(record.url.clone()).into()),
+            Entry::Vacant(e) => {
+                self.insert_record(record)?;
+                e.insert(record.id.clone());
+                Ok(())
+            }
+        }
+    }
+
+    async fn take_click(&self, domain: &str, id: &str) -> Result<bool> {
+        // get_mut 持有分片的写锁，判断和扣减之间不会有其他请求修改这一条记录
+        let Some(mut record) = self.links.get_mut(&key(domain, id)) else {
+            return Ok(false);
+        };
+        match record.remaining_clicks {
+            Some(n) if n > 0 => {
+                record.remaining_clicks = Some(n - 1);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn purge_dead_links(&self) -> Result<Vec<UrlRecord>> {
+        let now = Utc::now();
+        let mut dead = HashSet::new();
+        let mut purged = Vec::new();
+        self.links.retain(|key, record| {
+            if record.is_dead(now) {
+                dead.insert(key.clone());
+                purged.push(record.clone());
+                return false;
+            }
+            true
+        });
+        if !dead.is_empty() {
+            let mut clicks = self.clicks.lock().unwrap();
+            clicks.retain(|click| !dead.contains(&(click.domain.clone(), click.link_id.clone())));
+        }
+        Ok(purged)
+    }
+
+    async fn insert_clicks(&self, clicks: Vec<Click>) -> Result<()> {
+        self.clicks.lock().unwrap().extend(clicks);
+        Ok(())
+    }
+
+    async fn link_stats(&self, domain: &str, id: &str, bucket: Bucket) -> Result<LinkStats> {
+        let clicks = self.clicks.lock().unwrap();
+        let mut stats = LinkStats::default();
+        let mut visitors = HashSet::new();
+        let mut buckets = BTreeMap::new();
+        let mut referrers = HashMap::new();
+        let mut rules = HashMap::new();
+        for click in clicks
+            .iter()
+            .filter(|click| click.domain == domain && click.link_id == id)
+        {
+            stats.total += 1;
+            visitors.insert(&click.ip_hash);
+            let start = click.clicked_at.duration_trunc(bucket.duration())?;
+            *buckets.entry(start).or_insert(0) += 1;
+            if let Some(referrer) = &click.referrer {
+                *referrers.entry(referrer.clone()).or_insert(0) += 1;
+            }
+            if let Some(rule) = &click.rule {
+                *rules.entry(rule.clone()).or_insert(0) += 1;
+            }
+        }
+        stats.unique_visitors = visitors.len() as i64;
+        stats.buckets = buckets
+            .into_iter()
+            .map(|(start, clicks)| ClickBucket { start, clicks })
+            .collect();
+        let mut referrers: Vec<_> = referrers
+            .into_iter()
+            .map(|(referrer, clicks)| ReferrerCount { referrer, clicks })
+            .collect();
+        referrers.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.referrer.cmp(&b.referrer)));
+        referrers.truncate(TOP_REFERRERS as usize);
+        stats.top_referrers = referrers;
+        let mut rules: Vec<_> = rules
+            .into_iter()
+            .map(|(rule, clicks)| RuleCount { rule, clicks })
+            .collect();
+        rules.sort_by(|a, b| b.clicks.cmp(&a.clicks).then(a.rule.cmp(&b.rule)));
+        stats.rules = rules;
+        Ok(stats)
+    }
+
+    async fn count_clicks(&self, domain: &str, id: &str) -> Result<i64> {
+        let clicks = self.clicks.lock().unwrap();
+        Ok(clicks
+            .iter()
+            .filter(|click| click.domain == domain && click.link_id == id)
+            .count() as i64)
+    }
+
+    async fn next_sequence(&self) -> Result<u64> {
+        Ok(self.sequence.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        self.api_keys.insert(key.id.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        Ok(self
+            .api_keys
+            .iter()
+            .find(|key| key.key_hash == key_hash && key.revoked_at.is_none())
+            .map(|key| key.clone()))
+    }
+
+    async fn revoke_api_key(&self, id: &str) -> Result<bool> {
+        let Some(mut key) = self.api_keys.get_mut(id) else {
+            return Ok(false);
+        };
+        if key.revoked_at.is_some() {
+            return Ok(false);
+        }
+        key.revoked_at = Some(Utc::now());
+        Ok(true)
+    }
+
+    async fn insert_report(&self, report: &AbuseReport) -> Result<()> {
+        self.reports.lock().unwrap().push(report.clone());
+        Ok(())
+    }
+
+    async fn list_reports(&self, filter: &ReportFilter) -> Result<Vec<AbuseReport>> {
+        let reports = self.reports.lock().unwrap();
+        Ok(reports
+            .iter()
+            .rev()
+            .filter(|report| filter.include_resolved || report.resolved_at.is_none())
+            .skip(filter.offset as usize)
+            .take(filter.limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn moderate(&self, action: &ModerationAction) -> Result<bool> {
+        let key = key(&action.domain, &action.link_id);
+        // 持有短链接的写锁直到记录完成，和数据库中的事务一样
+        let Some(mut record) = self.links.get_mut(&key) else {
+            return Ok(false);
+        };
+        record.status = action.status;
+        self.moderation.lock().unwrap().push(action.clone());
+        for report in self.reports.lock().unwrap().iter_mut() {
+            if report.domain == action.domain
+                && report.link_id == action.link_id
+                && report.resolved_at.is_none()
+            {
+                report.resolved_at = Some(action.created_at);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn moderation_log(&self, domain: &str, id: &str) -> Result<Vec<ModerationAction>> {
+        let actions = self.moderation.lock().unwrap();
+        Ok(actions
+            .iter()
+            .filter(|action| action.domain == domain && action.link_id == id)
+            .cloned()
+            .collect())
+    }
+
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()> {
+        self.webhooks.lock().unwrap().push(webhook.clone());
+        Ok(())
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>> {
+        Ok(self.webhooks.lock().unwrap().clone())
+    }
+
+    async fn delete_webhook(&self, id: &str) -> Result<bool> {
+        let mut webhooks = self.webhooks.lock().unwrap();
+        let len = webhooks.len();
+        webhooks.retain(|webhook| webhook.id != id);
+        if webhooks.len() == len {
+            return Ok(false);
+        }
+        // 和数据库中的 ON DELETE CASCADE 一样
+        self.deliveries
+            .lock()
+            .unwrap()
+            .retain(|delivery| delivery.webhook_id != id);
+        self.dead_letters
+            .lock()
+            .unwrap()
+            .retain(|letter| letter.webhook_id != id);
+        Ok(true)
+    }
+
+    async fn enqueue_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .extend_from_slice(deliveries);
+        Ok(())
+    }
+
+    async fn claim_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let mut due: Vec<_> = deliveries
+            .iter_mut()
+            .filter(|delivery| delivery.next_attempt_at <= now)
+            .collect();
+        due.sort_by_key(|delivery| delivery.next_attempt_at);
+        Ok(due
+            .into_iter()
+            .take(limit as usize)
+            .map(|delivery| {
+                let claimed = delivery.clone();
+                delivery.next_attempt_at = lease_until;
+                claimed
+            })
+            .collect())
+    }
+
+    async fn complete_delivery(&self, id: &str) -> Result<()> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .retain(|delivery| delivery.id != id);
+        Ok(())
+    }
+
+    async fn retry_delivery(
+        &self,
+        id: &str,
+        attempts: i32,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        if let Some(delivery) = deliveries.iter_mut().find(|delivery| delivery.id == id) {
+            delivery.attempts = attempts;
+            delivery.next_attempt_at = next_attempt_at;
+            delivery.last_error = Some(error.to_string());
+        }
+        Ok(())
+    }
+
+    async fn dead_letter(
+        &self,
+        id: &str,
+        attempts: i32,
+        error: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let Some(i) = deliveries.iter().position(|delivery| delivery.id == id) else {
+            return Ok(());
+        };
+        let delivery = deliveries.remove(i);
+        self.dead_letters.lock().unwrap().push(DeadLetter {
+            id: delivery.id,
+            webhook_id: delivery.webhook_id,
+            event: delivery.event,
+            payload: delivery.payload,
+            attempts,
+            last_error: Some(error.to_string()),
+            created_at: delivery.created_at,
+            failed_at,
+        });
+        Ok(())
+    }
+
+    async fn list_dead_letters(&self, webhook_id: &str) -> Result<Vec<DeadLetter>> {
+        let letters = self.dead_letters.lock().unwrap();
+        Ok(letters
+            .iter()
+            .filter(|letter| letter.webhook_id == webhook_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn replay_dead_letters(&self, webhook_id: &str, now: DateTime<Utc>) -> Result<u64> {
+        // 和 dead_letter 以相同的顺序加锁
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let mut letters = self.dead_letters.lock().unwrap();
+        let (replayed, kept): (Vec<_>, Vec<_>) = letters
+            .drain(..)
+            .partition(|letter| letter.webhook_id == webhook_id);
+        *letters = kept;
+        let count = replayed.len() as u64;
+        deliveries.extend(replayed.into_iter().map(|letter| WebhookDelivery {
+            id: letter.id,
+            webhook_id: letter.webhook_id,
+            event: letter.event,
+            payload: letter.payload,
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: letter.last_error,
+            created_at: letter.created_at,
+        }));
+        Ok(count)
+    }
+}
+
+impl MemoryStore {
+    fn insert(&self, link: &NewLink) -> Result<String> {
+        self.insert_record(&link.into())?;
+        Ok(link.id.clone())
+    }
+
+    fn insert_record(&self, record: &UrlRecord) -> Result<()> {
+        match self.links.entry(key(&record.domain, &record.id)) {
+            Entry::Occupied(_) => Err(StoreError::Conflict(record.id.clone()).into()),
+            Entry::Vacant(e) => {
+                e.insert(record.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+fn key(domain: &str, id: &str) -> (String, String) {
+    (domain.to_string(), id.to_string())
+}
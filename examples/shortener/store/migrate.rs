@@ -0,0 +1,267 @@
+use anyhow::{bail, Context, Result};
+use sqlx::{
+    migrate::{Migrate, Migration, Migrator},
+    Database, Pool,
+};
+use std::collections::HashMap;
+
+// `shortener migrate` 子命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateCommand {
+    // 执行所有还没有执行的迁移
+    Up,
+    // 回退到 target 版本（不包含 target 本身），没有指定时只回退最新的一个迁移
+    Down { target: Option<i64> },
+    // 列出每个迁移的状态
+    Status,
+}
+
+// 数据库中的迁移记录和当前程序内嵌的迁移的对比结果
+struct MigrationState<'a> {
+    applied: Vec<i64>,
+    pending: Vec<&'a Migration>,
+    // 数据库中有、但当前程序不认识的迁移，说明数据库已经被更新的版本迁移过了
+    unknown: Vec<i64>,
+}
+
+impl MigrateCommand {
+    // 解析 migrate 之后的参数，不带参数时等同于 up
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let args: Vec<_> = args.iter().map(String::as_str).collect();
+        let command = match args[..] {
+            [] | ["up"] => Self::Up,
+            ["down"] => Self::Down { target: None },
+            ["down", target] => Self::Down {
+                target: Some(
+                    target
+                        .parse()
+                        .with_context(|| format!("invalid migration version: {target}"))?,
+                ),
+            },
+            ["status"] => Self::Status,
+            _ => bail!("usage: shortener migrate [up | down [VERSION] | status]"),
+        };
+        Ok(command)
+    }
+}
+
+// 启动时检查迁移状态：数据库的版本比程序新时拒绝启动，避免旧版本的程序写坏新的表结构；
+// 还有没执行的迁移时，auto_migrate 为 true 就直接执行，否则要求先手动执行 migrate
+pub async fn prepare<DB>(pool: &Pool<DB>, migrator: &Migrator, auto_migrate: bool) -> Result<()>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let state = load_state(pool, migrator).await?;
+    if let Some(version) = state.unknown.last() {
+        bail!(
+            "database schema version {version} is newer than this build supports ({}), please upgrade the shortener",
+            latest_version(migrator)
+        );
+    }
+    if state.pending.is_empty() {
+        return Ok(());
+    }
+    if !auto_migrate {
+        let versions: Vec<_> = state.pending.iter().map(|m| m.version).collect();
+        bail!("database has pending migrations {versions:?}, run `shortener migrate` first or set SHORTENER_AUTO_MIGRATE=true");
+    }
+    migrator.run(pool).await?;
+    Ok(())
+}
+
+pub async fn execute<DB>(
+    pool: &Pool<DB>,
+    migrator: &Migrator,
+    command: MigrateCommand,
+) -> Result<()>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let state = load_state(pool, migrator).await?;
+    match command {
+        MigrateCommand::Up => {
+            // 数据库比程序新时 sqlx 会报 VersionMissing，这里给出更明确的提示
+            if let Some(version) = state.unknown.last() {
+                bail!("database schema version {version} is newer than this build supports");
+            }
+            for migration in &state.pending {
+                println!("applying {} {}", migration.version, migration.description);
+            }
+            migrator.run(pool).await?;
+            println!("database is at version {}", latest_version(migrator));
+        }
+        MigrateCommand::Down { target } => {
+            let Some(&latest) = state.applied.last() else {
+                println!("no migrations to revert");
+                return Ok(());
+            };
+            // 默认回退到倒数第二个已执行的迁移，只有一个时回退到空数据库
+            let target =
+                target.unwrap_or_else(|| state.applied.iter().rev().nth(1).copied().unwrap_or(0));
+            if target >= latest {
+                bail!("database is at version {latest}, cannot revert to {target}");
+            }
+            for version in state.applied.iter().rev().filter(|&&v| v > target) {
+                println!("reverting {version}");
+            }
+            migrator.undo(pool, target).await?;
+            println!("database is at version {target}");
+        }
+        MigrateCommand::Status => {
+            for version in &state.applied {
+                let description = up_migrations(migrator)
+                    .find(|m| m.version == *version)
+                    .map_or("(unknown to this build)", |m| &m.description);
+                println!("{version}\t{description}\tapplied");
+            }
+            for migration in &state.pending {
+                println!("{}\t{}\tpending", migration.version, migration.description);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn load_state<'a, DB>(pool: &Pool<DB>, migrator: &'a Migrator) -> Result<MigrationState<'a>>
+where
+    DB: Database,
+    DB::Connection: Migrate,
+{
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    // 上一次迁移执行到一半失败了，需要人工处理
+    if let Some(version) = conn.dirty_version().await? {
+        bail!("migration {version} was partially applied, fix the database manually before continuing");
+    }
+    let applied: HashMap<_, _> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum))
+        .collect();
+    let mut state = MigrationState {
+        applied: applied.keys().copied().collect(),
+        pending: Vec::new(),
+        unknown: Vec::new(),
+    };
+    state.applied.sort_unstable();
+    for migration in up_migrations(migrator) {
+        match applied.get(&migration.version) {
+            Some(checksum) if *checksum != migration.checksum => {
+                bail!(
+                    "migration {} has been modified after it was applied",
+                    migration.version
+                );
+            }
+            Some(_) => {}
+            None => state.pending.push(migration),
+        }
+    }
+    state.unknown = state
+        .applied
+        .iter()
+        .copied()
+        .filter(|version| !migrator.version_exists(*version))
+        .collect();
+    Ok(state)
+}
+
+fn up_migrations(migrator: &Migrator) -> impl Iterator<Item = &Migration> {
+    migrator
+        .iter()
+        .filter(|m| m.migration_type.is_up_migration())
+}
+
+fn latest_version(migrator: &Migrator) -> i64 {
+    up_migrations(migrator)
+        .map(|m| m.version)
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::sqlite::MIGRATOR;
+    use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_migrate_command() {
+        let parse = |args: &[&str]| {
+            let args: Vec<_> = args.iter().map(|s| s.to_string()).collect();
+            MigrateCommand::parse(&args)
+        };
+        assert_eq!(parse(&[]).unwrap(), MigrateCommand::Up);
+        assert_eq!(parse(&["up"]).unwrap(), MigrateCommand::Up);
+        assert_eq!(
+            parse(&["down"]).unwrap(),
+            MigrateCommand::Down { target: None }
+        );
+        assert_eq!(
+            parse(&["down", "0"]).unwrap(),
+            MigrateCommand::Down { target: Some(0) }
+        );
+        assert_eq!(parse(&["status"]).unwrap(), MigrateCommand::Status);
+        assert!(parse(&["down", "latest"]).is_err());
+        assert!(parse(&["sideways"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn pending_migrations_run_only_when_allowed() {
+        let pool = memory_pool().await;
+        let e = prepare(&pool, &MIGRATOR, false).await.unwrap_err();
+        assert!(e.to_string().contains("pending migrations"));
+
+        prepare(&pool, &MIGRATOR, true).await.unwrap();
+        prepare(&pool, &MIGRATOR, false).await.unwrap();
+        sqlx::query("SELECT count(*) FROM urls")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // 不指定版本时只回退最新的一个迁移
+        execute(&pool, &MIGRATOR, MigrateCommand::Down { target: None })
+            .await
+            .unwrap();
+        let state = load_state(&pool, &MIGRATOR).await.unwrap();
+        let pending: Vec<_> = state.pending.iter().map(|m| m.version).collect();
+        assert_eq!(pending, [latest_version(&MIGRATOR)]);
+
+        execute(&pool, &MIGRATOR, MigrateCommand::Down { target: Some(0) })
+            .await
+            .unwrap();
+        assert!(sqlx::query("SELECT count(*) FROM urls")
+            .execute(&pool)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn newer_schema_is_refused() {
+        let pool = memory_pool().await;
+        prepare(&pool, &MIGRATOR, true).await.unwrap();
+        let newer = latest_version(&MIGRATOR) + 1;
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES ($1, 'from the future', TRUE, x'00', 0)",
+        )
+        .bind(newer)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        for auto_migrate in [true, false] {
+            let e = prepare(&pool, &MIGRATOR, auto_migrate).await.unwrap_err();
+            assert!(e.to_string().contains("is newer than this build"));
+        }
+    }
+}
@@ -0,0 +1,677 @@
+mod memory;
+mod migrate;
+mod postgres;
+mod sqlite;
+
+use crate::clicks::{Bucket, Click, LinkStats};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json, FromRow};
+use std::sync::Arc;
+use strum::Display;
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+pub use memory::MemoryStore;
+pub use migrate::MigrateCommand;
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+// 短链接的存储后端。AppState 只依赖这个 trait，具体使用哪个实现由配置决定，
+// 这样测试时可以使用内存存储，而不需要启动 PostgreSQL。
+// trait 中的 async fn 目前还不能用于 dyn Trait，所以借助 async_trait 宏把它们转换成返回 Box<dyn Future> 的方法。
+#[async_trait]
+pub trait LinkStore: Send + Sync + 'static {
+    // 保存短链接，返回短链接的 id。短链接由域名和 id 一起确定，不同域名下可以有相同的 id。
+    // 永久的随机短链接会按 url 去重，如果同一个域名下已经存在指向相同 url 的短链接，则返回已有的 id。
+    // id 已被占用时返回 StoreError::Conflict。
+    async fn create(&self, link: &NewLink) -> Result<String>;
+
+    // 批量保存短链接，返回的结果和 links 一一对应，某一行 id 冲突不影响其他行。
+    // 默认逐行调用 create，支持事务的存储应该把一批短链接放在同一个事务中保存
+    async fn create_many(&self, links: &[NewLink]) -> Result<Vec<Result<String>>> {
+        let mut results = Vec::with_capacity(links.len());
+        for link in links {
+            match self.create(link).await {
+                Err(e) if e.downcast_ref::<StoreError>().is_none() => return Err(e),
+                ret => results.push(ret),
+            }
+        }
+        Ok(results)
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>>;
+
+    // 修改短链接，短链接不存在时返回 false。
+    // 修改之后的 url 和同一个所有者的其他永久短链接重复时返回 StoreError::DuplicateUrl
+    async fn update(&self, domain: &str, id: &str, update: &LinkUpdate) -> Result<bool>;
+
+    // 删除短链接以及它的访问记录，短链接不存在时返回 false
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool>;
+
+    // 按创建时间倒序列出某个所有者在所有域名下的短链接
+    async fn list(&self, owner: &str, filter: &LinkFilter) -> Result<Vec<UrlRecord>>;
+
+    // 按 (域名, id) 的顺序分页列出所有短链接，after 是上一页的最后一个短链接，用于导出
+    async fn export(&self, after: Option<(&str, &str)>, limit: i64) -> Result<Vec<UrlRecord>>;
+
+    // 原样保存导入的短链接，保留 id、创建时间和剩余访问次数。
+    // id 已被占用时返回 StoreError::Conflict，和同一个所有者的永久短链接重复时返回 StoreError::DuplicateUrl
+    async fn import(&self, record: &UrlRecord) -> Result<()>;
+
+    // 原子地把剩余访问次数减一，次数已用完时返回 false
+    async fn take_click(&self, domain: &str, id: &str) -> Result<bool>;
+
+    // 删除已过期或访问次数已用完的短链接以及它们的访问记录，返回被删除的短链接
+    async fn purge_dead_links(&self) -> Result<Vec<UrlRecord>>;
+
+    async fn insert_clicks(&self, clicks: Vec<Click>) -> Result<()>;
+
+    async fn link_stats(&self, domain: &str, id: &str, bucket: Bucket) -> Result<LinkStats>;
+
+    // 短链接的总访问次数
+    async fn count_clicks(&self, domain: &str, id: &str) -> Result<i64>;
+
+    // 返回自增序列的下一个值，用于生成顺序的短链接 id
+    async fn next_sequence(&self) -> Result<u64>;
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()>;
+
+    // 按哈希查找没有被吊销的 API key
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>>;
+
+    // 吊销 API key，key 不存在或者已经被吊销时返回 false
+    async fn revoke_api_key(&self, id: &str) -> Result<bool>;
+
+    async fn insert_report(&self, report: &AbuseReport) -> Result<()>;
+
+    // 按提交时间倒序列出所有域名下的举报
+    async fn list_reports(&self, filter: &ReportFilter) -> Result<Vec<AbuseReport>>;
+
+    // 在同一个事务中修改短链接的审核状态、记录这次操作，并把这个短链接未处理的举报标记为已处理。
+    // 短链接不存在时返回 false
+    async fn moderate(&self, action: &ModerationAction) -> Result<bool>;
+
+    // 按时间顺序列出一个短链接的审核记录
+    async fn moderation_log(&self, domain: &str, id: &str) -> Result<Vec<ModerationAction>>;
+
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()>;
+
+    // 按创建时间列出所有 webhook
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>>;
+
+    // 删除 webhook 以及它还没有完成的投递和死信，webhook 不存在时返回 false
+    async fn delete_webhook(&self, id: &str) -> Result<bool>;
+
+    async fn enqueue_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()>;
+
+    // 取出最多 limit 条到期（next_attempt_at 不晚于 now）的投递，同时把它们的 next_attempt_at 推迟到 lease_until，
+    // 这样其他实例不会同时发送同一条投递；发送过程中进程退出时，到了 lease_until 会被重新取出
+    async fn claim_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>>;
+
+    // 投递成功，删除这条投递
+    async fn complete_delivery(&self, id: &str) -> Result<()>;
+
+    // 投递失败，记录已经尝试的次数和错误，到 next_attempt_at 时重试
+    async fn retry_delivery(
+        &self,
+        id: &str,
+        attempts: i32,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()>;
+
+    // 重试次数用完，在同一个事务中把投递移到死信表
+    async fn dead_letter(
+        &self,
+        id: &str,
+        attempts: i32,
+        error: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<()>;
+
+    // 按失败时间列出一个 webhook 的死信
+    async fn list_dead_letters(&self, webhook_id: &str) -> Result<Vec<DeadLetter>>;
+
+    // 在同一个事务中把一个 webhook 的死信放回投递队列，尝试次数清零并立即发送，返回放回的数量
+    async fn replay_dead_letters(&self, webhook_id: &str, now: DateTime<Utc>) -> Result<u64>;
+
+    // 订阅其他实例对短链接的修改，用于让多个实例的缓存保持一致。
+    // 只有能被多个实例共享的存储才需要实现，默认返回 None
+    async fn subscribe_changes(&self) -> Result<Option<mpsc::Receiver<Invalidation>>> {
+        Ok(None)
+    }
+}
+
+// 需要失效的缓存
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    Link { domain: String, id: String },
+    All,
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("id {0:?} is already taken")]
+    Conflict(String),
+    #[error("another link of the same owner already points to {0}")]
+    DuplicateUrl(String),
+}
+
+// FromRow 是 sqlx 库中的一个宏，允许你将数据库中的一行数据直接映射到一个结构体实例
+#[derive(Debug, Clone, Default, FromRow)]
+pub struct UrlRecord {
+    // 当结构包含查询中不存在的字段时，如果字段类型具有 Default 的实现，则可以使用 default 属性为所述字段分配默认值。
+    // 空字符串表示默认域名
+    #[sqlx(default)]
+    pub domain: String,
+    #[sqlx(default)]
+    pub id: String,
+    #[sqlx(default)]
+    pub url: String,
+    // 是否是用户指定的自定义短链接，导出时需要保留，决定导入之后是否参与去重
+    #[sqlx(default)]
+    pub custom: bool,
+    #[sqlx(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    pub not_before: Option<DateTime<Utc>>,
+    // 剩余访问次数，NULL 表示不限制
+    #[sqlx(default)]
+    pub remaining_clicks: Option<i64>,
+    // 管理令牌的哈希，没有所有者的旧短链接无法被修改或删除
+    #[sqlx(default)]
+    pub owner: Option<String>,
+    // NULL 表示使用默认的跳转方式
+    #[sqlx(default)]
+    pub redirect_type: Option<RedirectType>,
+    // 数据库中以 JSON 数组的形式保存
+    #[sqlx(default)]
+    pub tags: Json<Vec<String>>,
+    #[sqlx(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    // 跳转之前是否先显示提示页面
+    #[sqlx(default)]
+    pub interstitial: bool,
+    // 受密码保护的短链接加密后的目标地址，这时 url 为空字符串
+    #[sqlx(default)]
+    pub sealed_url: Option<String>,
+    // 是否把短链接后面追加的路径和查询参数合并到目标地址中
+    #[sqlx(default)]
+    pub passthrough: bool,
+    // 跳转时默认添加的 UTM 参数
+    #[sqlx(default)]
+    pub utm: Json<Utm>,
+    // 按设备或者按权重选择目标地址的规则
+    #[sqlx(default)]
+    pub routing: Json<Routing>,
+    // 审核状态，只有管理员可以修改
+    #[sqlx(default)]
+    pub status: LinkStatus,
+}
+
+// API key 只保存哈希，明文只在签发时返回一次
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Json<Vec<Scope>>,
+    // 每分钟可以创建的短链接数量和突发数量，NULL 表示使用默认配置
+    pub rate_limit: Option<i64>,
+    pub burst: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+// API key 的权限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Scope {
+    // 创建短链接
+    Create,
+    // 修改、删除和列出用这个 key 创建的短链接
+    Manage,
+    // 签发和吊销 API key，同时拥有其他所有权限
+    Admin,
+}
+
+// 跳转时使用的状态码。301 和 308 会被浏览器永久缓存，之后修改目标地址对已经访问过的用户不生效；
+// 302 和 307 每次都会重新请求短链接服务。
+// repr(i32) 让 sqlx 把它当作整数保存，serde 则把它序列化为状态码数字
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(try_from = "u16", into = "u16")]
+#[repr(i32)]
+pub enum RedirectType {
+    MovedPermanently = 301,
+    Found = 302,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+}
+
+// 跳转时添加到目标地址中的 UTM 参数，字段名去掉了 utm_ 前缀。
+// 目标地址中已经有的参数保持不变，访问短链接时的查询参数可以覆盖它们
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Utm {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+// 短链接的审核状态，数据库中以小写的字符串保存
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum LinkStatus {
+    #[default]
+    Active,
+    // 被管理员停用，访问时返回 410
+    Disabled,
+    // 因为法律原因被屏蔽，访问时返回 451
+    Blocked,
+}
+
+// 举报的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, Display)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ReportReason {
+    Phishing,
+    Malware,
+    Spam,
+    Other,
+}
+
+// 访问者提交的举报
+#[derive(Debug, Clone, FromRow)]
+pub struct AbuseReport {
+    pub id: String,
+    pub domain: String,
+    pub link_id: String,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    // 不保存原始 IP，只保存加盐后的哈希
+    pub reporter_hash: String,
+    pub created_at: DateTime<Utc>,
+    // 管理员修改了短链接的状态之后，这个短链接之前的举报都算作已处理
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+// 一次审核操作：把短链接改为 status，reason 是管理员填写的原因，actor 是操作者
+#[derive(Debug, Clone, FromRow)]
+pub struct ModerationAction {
+    pub id: String,
+    pub domain: String,
+    pub link_id: String,
+    pub status: LinkStatus,
+    pub reason: String,
+    pub actor: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// webhook 可以订阅的事件，序列化为 link.created 这样的字符串
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, Display)]
+#[sqlx(type_name = "text")]
+pub enum WebhookEvent {
+    #[serde(rename = "link.created")]
+    #[sqlx(rename = "link.created")]
+    #[strum(serialize = "link.created")]
+    Created,
+    #[serde(rename = "link.updated")]
+    #[sqlx(rename = "link.updated")]
+    #[strum(serialize = "link.updated")]
+    Updated,
+    #[serde(rename = "link.deleted")]
+    #[sqlx(rename = "link.deleted")]
+    #[strum(serialize = "link.deleted")]
+    Deleted,
+    // 过期或访问次数用完之后被后台任务清理
+    #[serde(rename = "link.expired")]
+    #[sqlx(rename = "link.expired")]
+    #[strum(serialize = "link.expired")]
+    Expired,
+    // 访问次数达到了 webhook 设置的某个阈值
+    #[serde(rename = "link.clicks")]
+    #[sqlx(rename = "link.clicks")]
+    #[strum(serialize = "link.clicks")]
+    Clicks,
+}
+
+// 订阅短链接事件的外部地址
+#[derive(Debug, Clone, FromRow)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    // 给投递签名的密钥，只在创建时返回一次
+    pub secret: String,
+    pub events: Json<Vec<WebhookEvent>>,
+    // 订阅了 link.clicks 时，访问次数达到这些值各通知一次
+    pub click_thresholds: Json<Vec<i64>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// 等待发送给某个 webhook 的一个事件
+#[derive(Debug, Clone, FromRow)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    // 原样发送的请求体，重试时不会重新生成，签名也针对这些字节
+    pub payload: String,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// 重试次数用完的投递
+#[derive(Debug, Clone, FromRow)]
+pub struct DeadLetter {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    pub payload: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub failed_at: DateTime<Utc>,
+}
+
+// 列出举报时的过滤和分页条件
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportFilter {
+    // 默认只返回还没有处理的举报
+    #[serde(default)]
+    pub include_resolved: bool,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+// 按访问者的设备或者按权重选择目标地址的规则。
+// 设备规则优先，没有命中时按权重在 variants 中选择，两者都没有时使用短链接的 url
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Routing {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceRule {
+    pub device: Device,
+    pub url: String,
+}
+
+// 根据 User-Agent 识别的设备类型，无法识别的都算作桌面设备
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Device {
+    Ios,
+    Android,
+    Desktop,
+}
+
+// A/B 测试的一个分组，被选中的概率是 weight 占所有分组 weight 之和的比例
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Variant {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+// 修改短链接时需要更新的字段，None 表示不修改
+#[derive(Debug, Clone, Default)]
+pub struct LinkUpdate {
+    pub url: Option<String>,
+    pub redirect_type: Option<RedirectType>,
+    pub tags: Option<Vec<String>>,
+    pub interstitial: Option<bool>,
+    pub passthrough: Option<bool>,
+    pub utm: Option<Utm>,
+    pub routing: Option<Routing>,
+}
+
+// 列出短链接时的过滤和分页条件
+#[derive(Debug, Clone, Deserialize)]
+pub struct LinkFilter {
+    // 只返回带有这个标签的短链接
+    #[serde(default)]
+    pub tag: Option<String>,
+    // 只返回 id 或者 url 中包含这个字符串的短链接，不区分大小写
+    #[serde(default)]
+    pub q: Option<String>,
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+// 新建短链接时需要保存的数据
+#[derive(Debug, Clone)]
+pub struct NewLink {
+    pub domain: String,
+    pub id: String,
+    pub url: String,
+    // 是否是用户指定的自定义短链接
+    pub custom: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub not_before: Option<DateTime<Utc>>,
+    pub max_clicks: Option<i64>,
+    pub owner: Option<String>,
+    pub redirect_type: Option<RedirectType>,
+    pub tags: Vec<String>,
+    pub interstitial: bool,
+    pub sealed_url: Option<String>,
+    pub passthrough: bool,
+    pub utm: Utm,
+    pub routing: Routing,
+}
+
+// 查询短链接时需要的列，各个存储后端共用
+const COLUMNS: &str = "domain, id, url, custom, expires_at, not_before, remaining_clicks, owner, redirect_type, tags, created_at, interstitial, sealed_url, passthrough, utm, routing, status";
+
+// 根据 URL 的 scheme 选择存储后端。auto_migrate 为 false 时，还有没执行的迁移就返回错误
+pub async fn connect(url: &str, auto_migrate: bool) -> Result<Arc<dyn LinkStore>> {
+    let store: Arc<dyn LinkStore> = match url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("postgres" | "postgresql") => Arc::new(PgStore::try_new(url, auto_migrate).await?),
+        Some("sqlite") => Arc::new(SqliteStore::try_new(url, auto_migrate).await?),
+        Some("memory") => Arc::new(MemoryStore::default()),
+        _ => bail!("unsupported database url: {url}"),
+    };
+    Ok(store)
+}
+
+// 执行 migrate 子命令
+pub async fn migrate(url: &str, command: MigrateCommand) -> Result<()> {
+    match url.split_once(':').map(|(scheme, _)| scheme) {
+        Some("postgres" | "postgresql") => PgStore::migrate(url, command).await,
+        Some("sqlite") => SqliteStore::migrate(url, command).await,
+        Some("memory") => bail!("the memory store has no schema to migrate"),
+        _ => bail!("unsupported database url: {url}"),
+    }
+}
+
+impl RedirectType {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::MovedPermanently => StatusCode::MOVED_PERMANENTLY,
+            Self::Found => StatusCode::FOUND,
+            Self::TemporaryRedirect => StatusCode::TEMPORARY_REDIRECT,
+            Self::PermanentRedirect => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+}
+
+impl TryFrom<u16> for RedirectType {
+    type Error = String;
+
+    fn try_from(status: u16) -> Result<Self, Self::Error> {
+        match status {
+            301 => Ok(Self::MovedPermanently),
+            302 => Ok(Self::Found),
+            307 => Ok(Self::TemporaryRedirect),
+            308 => Ok(Self::PermanentRedirect),
+            _ => Err(format!(
+                "unsupported redirect type {status}, expected 301, 302, 307 or 308"
+            )),
+        }
+    }
+}
+
+impl From<RedirectType> for u16 {
+    fn from(redirect_type: RedirectType) -> Self {
+        redirect_type.status().as_u16()
+    }
+}
+
+impl Default for LinkFilter {
+    fn default() -> Self {
+        Self {
+            tag: None,
+            q: None,
+            limit: default_limit(),
+            offset: 0,
+        }
+    }
+}
+
+fn default_limit() -> i64 {
+    50
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Utm {
+    // (参数名, 值) 列表，没有设置的参数不包含在内
+    pub fn params(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("utm_source", &self.source),
+            ("utm_medium", &self.medium),
+            ("utm_campaign", &self.campaign),
+            ("utm_term", &self.term),
+            ("utm_content", &self.content),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.params().next().is_none()
+    }
+}
+
+impl Routing {
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty() && self.variants.is_empty()
+    }
+}
+
+impl NewLink {
+    // 永久的随机短链接才参与去重（只在同一个所有者的短链接之间去重），
+    // 自定义短链接、带有过期策略的短链接和受密码保护的短链接总是新建。
+    // 去重时返回的是已有的短链接，所以设置了任何选项（跳转类型、标签、中间页、passthrough、UTM 参数、跳转规则）
+    // 的短链接也总是新建，否则这些选项会被丢掉。新增选项时需要同时修改这里和部分唯一索引的条件
+    pub fn is_permanent(&self) -> bool {
+        !self.custom
+            && self.expires_at.is_none()
+            && self.not_before.is_none()
+            && self.max_clicks.is_none()
+            && self.sealed_url.is_none()
+            && self.redirect_type.is_none()
+            && self.tags.is_empty()
+            && !self.interstitial
+            && !self.passthrough
+            && self.utm.is_empty()
+            && self.routing.is_empty()
+    }
+}
+
+impl UrlRecord {
+    // 和 NewLink::is_permanent 相同，剩余访问次数为 NULL 说明没有次数限制
+    pub fn is_permanent(&self) -> bool {
+        !self.custom
+            && self.expires_at.is_none()
+            && self.not_before.is_none()
+            && self.remaining_clicks.is_none()
+            && self.sealed_url.is_none()
+            && self.redirect_type.is_none()
+            && self.tags.is_empty()
+            && !self.interstitial
+            && !self.passthrough
+            && self.utm.is_empty()
+            && self.routing.is_empty()
+    }
+
+    // 已过期或访问次数已用完
+    pub fn is_dead(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|t| t <= now) || self.remaining_clicks == Some(0)
+    }
+
+    // 内存存储用它实现 list 的过滤条件
+    pub fn matches(&self, filter: &LinkFilter) -> bool {
+        let tag_matches = filter
+            .tag
+            .as_ref()
+            .is_none_or(|tag| self.tags.contains(tag));
+        let q_matches = filter.q.as_ref().is_none_or(|q| {
+            let q = q.to_lowercase();
+            self.id.to_lowercase().contains(&q) || self.url.to_lowercase().contains(&q)
+        });
+        tag_matches && q_matches
+    }
+}
+
+impl From<&NewLink> for UrlRecord {
+    fn from(link: &NewLink) -> Self {
+        Self {
+            domain: link.domain.clone(),
+            id: link.id.clone(),
+            url: link.url.clone(),
+            custom: link.custom,
+            expires_at: link.expires_at,
+            not_before: link.not_before,
+            remaining_clicks: link.max_clicks,
+            owner: link.owner.clone(),
+            redirect_type: link.redirect_type,
+            tags: Json(link.tags.clone()),
+            created_at: Some(Utc::now()),
+            interstitial: link.interstitial,
+            sealed_url: link.sealed_url.clone(),
+            passthrough: link.passthrough,
+            utm: Json(link.utm.clone()),
+            routing: Json(link.routing.clone()),
+            status: LinkStatus::Active,
+        }
+    }
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_unique_violation())
+}
@@ -0,0 +1,848 @@
+use super::{
+    is_unique_violation,
+    migrate::{self, MigrateCommand},
+    AbuseReport, ApiKey, DeadLetter, Invalidation, LinkFilter, LinkStore, LinkUpdate,
+    ModerationAction, NewLink, ReportFilter, StoreError, UrlRecord, Webhook, WebhookDelivery,
+    COLUMNS,
+};
+use crate::clicks::{
+    Bucket, Click, ClickBucket, LinkStats, ReferrerCount, RuleCount, TOP_REFERRERS,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::Migrator, postgres::PgListener, types::Json, Connection, PgConnection, PgPool,
+    Postgres, QueryBuilder,
+};
+use std::time::Duration;
+use tokio::{sync::mpsc, time};
+use tracing::{info, warn};
+
+// 短链接被修改时通过 NOTIFY 发送到这个频道，payload 是“域名/id”
+const CHANGES_CHANNEL: &str = "shortener_link_changes";
+
+// 内嵌在程序中的版本化迁移，路径相对于 crate 根目录
+pub(super) static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/postgres");
+
+// 引入版本化迁移之前，表结构是在启动时用一组可以重复执行的语句维护的。
+// 这样的旧数据库第一次迁移之前，先用下面的语句把它补齐到 0001 迁移的结构，之后再交给 sqlx 管理：
+// id 原来是 CHAR(6)，并且 url 上有 UNIQUE 约束，这样同一个长链接无法拥有多个自定义短链接；
+// 主键原来只有 id，支持多个域名之后改为 (domain, id)
+const LEGACY_UPGRADE: &[&str] = &[
+    "ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(32)",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS custom BOOLEAN NOT NULL DEFAULT FALSE",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS not_before TIMESTAMPTZ",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS remaining_clicks BIGINT",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS owner TEXT",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS redirect_type INTEGER",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS tags JSONB NOT NULL DEFAULT '[]'",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now()",
+    "ALTER TABLE urls ADD COLUMN IF NOT EXISTS domain VARCHAR(255) NOT NULL DEFAULT ''",
+    // 主键只有一列时说明是旧表，改为 (domain, id)
+    r#"
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1 FROM pg_constraint
+            WHERE conrelid = 'urls'::regclass AND contype = 'p' AND array_length(conkey, 1) = 1
+        ) THEN
+            ALTER TABLE urls DROP CONSTRAINT urls_pkey, ADD PRIMARY KEY (domain, id);
+        END IF;
+    END
+    $$
+    "#,
+    "ALTER TABLE urls DROP CONSTRAINT IF EXISTS urls_url_key",
+    "DROP INDEX IF EXISTS urls_generated_url_key",
+    // 去重原来是全局的，短链接可以被修改之后改为只在同一个所有者的短链接之间去重
+    "DROP INDEX IF EXISTS urls_permanent_url_key",
+    "DROP INDEX IF EXISTS urls_owner_permanent_url_key",
+    "ALTER TABLE IF EXISTS clicks ADD COLUMN IF NOT EXISTS domain VARCHAR(255) NOT NULL DEFAULT ''",
+    "DROP INDEX IF EXISTS clicks_link_id_clicked_at_idx",
+];
+
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    db: PgPool,
+}
+
+impl PgStore {
+    pub async fn try_new(url: &str, auto_migrate: bool) -> Result<Self> {
+        let pool = PgPool::connect(url).await?;
+        if auto_migrate {
+            upgrade_legacy(&pool).await?;
+        }
+        migrate::prepare(&pool, &MIGRATOR, auto_migrate).await?;
+        Ok(Self { db: pool })
+    }
+
+    pub async fn migrate(url: &str, command: MigrateCommand) -> Result<()> {
+        let pool = PgPool::connect(url).await?;
+        if command == MigrateCommand::Up {
+            upgrade_legacy(&pool).await?;
+        }
+        migrate::execute(&pool, &MIGRATOR, command).await
+    }
+
+    // 通知所有实例（包括自己）这个短链接已经改变。
+    // 通知失败不影响修改本身，其他实例的缓存最多在 TTL 之后失效
+    async fn notify_changed(&self, domain: &str, id: &str) {
+        let ret = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANGES_CHANNEL)
+            .bind(format!("{domain}/{id}"))
+            .execute(&self.db)
+            .await;
+        if let Err(e) = ret {
+            warn!("Failed to notify change of {domain}/{id}: {e}");
+        }
+    }
+}
+
+// 只处理有 urls 表、但还没有执行过迁移的旧数据库，其他情况什么都不做。
+// 迁移记录表可能已经被 `migrate status` 创建出来了，所以要看其中有没有记录
+async fn upgrade_legacy(pool: &PgPool) -> Result<()> {
+    let (has_urls, has_migrations): (bool, bool) = sqlx::query_as(
+        "SELECT to_regclass('urls') IS NOT NULL, to_regclass('_sqlx_migrations') IS NOT NULL",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !has_urls {
+        return Ok(());
+    }
+    if has_migrations {
+        let (migrated,): (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM _sqlx_migrations)")
+            .fetch_one(pool)
+            .await?;
+        if migrated {
+            return Ok(());
+        }
+    }
+    info!("Upgrading a database created before versioned migrations");
+    let mut tx = pool.begin().await?;
+    for statement in LEGACY_UPGRADE {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// 把 LISTEN 收到的通知转发到 channel，直到接收方被关闭
+async fn forward_changes(mut listener: PgListener, tx: mpsc::Sender<Invalidation>) {
+    loop {
+        // try_recv 在连接断开时返回 None，下一次调用时会自动重连
+        let invalidation = match listener.try_recv().await {
+            // 域名和 id 中都不会出现斜杠
+            Ok(Some(notification)) => match notification.payload().split_once('/') {
+                Some((domain, id)) => Invalidation::Link {
+                    domain: domain.to_string(),
+                    id: id.to_string(),
+                },
+                None => {
+                    warn!("Invalid notification on {CHANGES_CHANNEL}: {notification:?}");
+                    continue;
+                }
+            },
+            Ok(None) => {
+                warn!("Lost connection to {CHANGES_CHANNEL}, reconnecting");
+                Invalidation::All
+            }
+            Err(e) => {
+                warn!("Failed to receive from {CHANGES_CHANNEL}: {e}");
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        if tx.send(invalidation).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[async_trait]
+impl LinkStore for PgStore {
+    async fn create(&self, link: &NewLink) -> Result<String> {
+        let mut conn = self.db.acquire().await?;
+        match insert_link(&mut conn, link).await {
+            Ok(id) => {
+                // 新建的短链接可能被其他实例当作“不存在”缓存了
+                self.notify_changed(&link.domain, &id).await;
+                Ok(id)
+            }
+            Err(e) if is_unique_violation(&e) => Err(StoreError::Conflict(link.id.clone()).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn create_many(&self, links: &[NewLink]) -> Result<Vec<Result<String>>> {
+        let mut tx = self.db.begin().await?;
+        let mut results = Vec::with_capacity(links.len());
+        for link in links {
+            // 在事务中再开启事务会创建一个 SAVEPOINT，某一行冲突时只回滚这一行，不影响同一批的其他行
+            let mut savepoint = tx.begin().await?;
+            match insert_link(&mut savepoint, link).await {
+                Ok(id) => {
+                    savepoint.commit().await?;
+                    results.push(Ok(id));
+                }
+                Err(e) if is_unique_violation(&e) => {
+                    savepoint.rollback().await?;
+                    results.push(Err(StoreError::Conflict(link.id.clone()).into()));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        tx.commit().await?;
+        for (link, ret) in links.iter().zip(&results) {
+            if let Ok(id) = ret {
+                self.notify_changed(&link.domain, id).await;
+            }
+        }
+        Ok(results)
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>> {
+        let ret = sqlx::query_as(&format!(
+            "SELECT {COLUMNS} FROM urls WHERE domain = $1 AND id = $2"
+        ))
+        .bind(domain)
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn update(&self, domain: &str, id: &str, update: &LinkUpdate) -> Result<bool> {
+        // COALESCE 返回第一个不为 NULL 的参数，没有传入的字段保持原来的值
+        let ret = sqlx::query(
+            r#"
+            UPDATE urls SET
+                url = COALESCE($3, url),
+                redirect_type = COALESCE($4, redirect_type),
+                tags = COALESCE($5, tags),
+                interstitial = COALESCE($6, interstitial),
+                passthrough = COALESCE($7, passthrough),
+                utm = COALESCE($8, utm),
+                routing = COALESCE($9, routing)
+            WHERE domain = $1 AND id = $2
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(&update.url)
+        .bind(update.redirect_type)
+        .bind(update.tags.as_ref().map(Json))
+        .bind(update.interstitial)
+        .bind(update.passthrough)
+        .bind(update.utm.as_ref().map(Json))
+        .bind(update.routing.as_ref().map(Json))
+        .execute(&self.db)
+        .await;
+        match ret {
+            Ok(ret) if ret.rows_affected() > 0 => {
+                self.notify_changed(domain, id).await;
+                Ok(true)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if is_unique_violation(&e) => {
+                Err(StoreError::DuplicateUrl(update.url.clone().unwrap_or_default()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+            WITH deleted AS (
+                DELETE FROM urls WHERE domain = $1 AND id = $2 RETURNING domain, id
+            ), deleted_clicks AS (
+                DELETE FROM clicks WHERE (domain, link_id) IN (SELECT domain, id FROM deleted)
+            )
+            SELECT count(*) FROM deleted
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_one(&self.db)
+        .await?;
+        if count > 0 {
+            self.notify_changed(domain, id).await;
+        }
+        Ok(count > 0)
+    }
+
+    async fn list(&self, owner: &str, filter: &LinkFilter) -> Result<Vec<UrlRecord>> {
+        // jsonb 的 ? 运算符判断数组中是否包含某个字符串；
+        // strpos 做子串匹配，不需要像 LIKE 那样转义 % 和 _
+        let ret = sqlx::query_as(&format!(
+            r#"
+            SELECT {COLUMNS} FROM urls
+            WHERE owner = $1
+                AND ($2::TEXT IS NULL OR tags ? $2)
+                AND ($3::TEXT IS NULL OR strpos(lower(id || ' ' || url), lower($3)) > 0)
+            ORDER BY created_at DESC, domain, id
+            LIMIT $4 OFFSET $5
+            "#
+        ))
+        .bind(owner)
+        .bind(&filter.tag)
+        .bind(&filter.q)
+        .bind(filter.limit)
+        .bind(filter.offset)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn export(&self, after: Option<(&str, &str)>, limit: i64) -> Result<Vec<UrlRecord>> {
+        // 按主键做 keyset 分页，导出大量数据时不会像 OFFSET 那样越来越慢
+        let (domain, id) = after.unzip();
+        let ret = sqlx::query_as(&format!(
+            r#"
+            SELECT {COLUMNS} FROM urls
+            WHERE $1::TEXT IS NULL OR (domain, id) > ($1, $2)
+            ORDER BY domain, id
+            LIMIT $3
+            "#
+        ))
+        .bind(domain)
+        .bind(id)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn import(&self, record: &UrlRecord) -> Result<()> {
+        // 主键冲突时什么都不做，这样才能和永久短链接的去重索引冲突区分开
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO urls (domain, id, url, custom, expires_at, not_before, remaining_clicks, owner, redirect_type, tags, created_at, interstitial, sealed_url, passthrough, utm, routing, status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+            ON CONFLICT (domain, id) DO NOTHING
+            "#,
+        )
+        .bind(&record.domain)
+        .bind(&record.id)
+        .bind(&record.url)
+        .bind(record.custom)
+        .bind(record.expires_at)
+        .bind(record.not_before)
+        .bind(record.remaining_clicks)
+        .bind(&record.owner)
+        .bind(record.redirect_type)
+        .bind(&record.tags)
+        .bind(record.created_at.unwrap_or_else(Utc::now))
+        .bind(record.interstitial)
+        .bind(&record.sealed_url)
+        .bind(record.passthrough)
+        .bind(&record.utm)
+        .bind(&record.routing)
+        .bind(record.status)
+        .execute(&self.db)
+        .await;
+        match ret {
+            Ok(ret) if ret.rows_affected() == 0 => {
+                Err(StoreError::Conflict(record.id.clone()).into())
+            }
+            Ok(_) => {
+                self.notify_changed(&record.domain, &record.id).await;
+                Ok(())
+            }
+            Err(e) if is_unique_violation(&e) => {
+                Err(StoreError::DuplicateUrl(record.url.clone()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn take_click(&self, domain: &str, id: &str) -> Result<bool> {
+        // 并发访问时 UPDATE 会对这一行加锁，因此 remaining_clicks > 0 的判断和扣减是一起完成的，
+        // 不会超过访问次数限制
+        let ret = sqlx::query(
+            r#"
+            UPDATE urls SET remaining_clicks = remaining_clicks - 1
+            WHERE domain = $1 AND id = $2 AND remaining_clicks > 0
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn purge_dead_links(&self) -> Result<Vec<UrlRecord>> {
+        let purged = sqlx::query_as(&format!(
+            r#"
+            WITH dead AS (
+                DELETE FROM urls WHERE expires_at <= now() OR remaining_clicks = 0 RETURNING {COLUMNS}
+            ), purged_clicks AS (
+                DELETE FROM clicks WHERE (domain, link_id) IN (SELECT domain, id FROM dead)
+            )
+            SELECT * FROM dead
+            "#
+        ))
+        .fetch_all(&self.db)
+        .await?;
+        Ok(purged)
+    }
+
+    async fn insert_clicks(&self, clicks: Vec<Click>) -> Result<()> {
+        // QueryBuilder::push_values 可以把一批数据拼成一条多行 INSERT 语句
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "INSERT INTO clicks (domain, link_id, clicked_at, referrer, user_agent, ip_hash, rule) ",
+        );
+        builder.push_values(clicks, |mut b, click| {
+            b.push_bind(click.domain)
+                .push_bind(click.link_id)
+                .push_bind(click.clicked_at)
+                .push_bind(click.referrer)
+                .push_bind(click.user_agent)
+                .push_bind(click.ip_hash)
+                .push_bind(click.rule);
+        });
+        builder.build().execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn link_stats(&self, domain: &str, id: &str, bucket: Bucket) -> Result<LinkStats> {
+        let (total, unique_visitors): (i64, i64) = sqlx::query_as(
+            "SELECT count(*), count(DISTINCT ip_hash) FROM clicks WHERE domain = $1 AND link_id = $2",
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_one(&self.db)
+        .await?;
+        // date_trunc 把时间截断到小时或天，再按截断后的时间分组统计
+        let buckets: Vec<ClickBucket> = sqlx::query_as(
+            r#"
+            SELECT date_trunc($3, clicked_at) AS start, count(*) AS clicks
+            FROM clicks WHERE domain = $1 AND link_id = $2
+            GROUP BY start ORDER BY start
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(bucket.as_str())
+        .fetch_all(&self.db)
+        .await?;
+        let top_referrers: Vec<ReferrerCount> = sqlx::query_as(
+            r#"
+            SELECT referrer, count(*) AS clicks
+            FROM clicks WHERE domain = $1 AND link_id = $2 AND referrer IS NOT NULL
+            GROUP BY referrer ORDER BY clicks DESC, referrer LIMIT $3
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(TOP_REFERRERS)
+        .fetch_all(&self.db)
+        .await?;
+        let rules: Vec<RuleCount> = sqlx::query_as(
+            r#"
+            SELECT rule, count(*) AS clicks
+            FROM clicks WHERE domain = $1 AND link_id = $2 AND rule IS NOT NULL
+            GROUP BY rule ORDER BY clicks DESC, rule
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(LinkStats {
+            total,
+            unique_visitors,
+            buckets,
+            top_referrers,
+            rules,
+        })
+    }
+
+    async fn count_clicks(&self, domain: &str, id: &str) -> Result<i64> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM clicks WHERE domain = $1 AND link_id = $2")
+                .bind(domain)
+                .bind(id)
+                .fetch_one(&self.db)
+                .await?;
+        Ok(count)
+    }
+
+    async fn next_sequence(&self) -> Result<u64> {
+        let (seq,): (i64,) = sqlx::query_as("SELECT nextval('urls_id_seq')")
+            .fetch_one(&self.db)
+            .await?;
+        Ok(seq as u64)
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO api_keys (id, name, key_hash, scopes, rate_limit, burst, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&key.id)
+        .bind(&key.name)
+        .bind(&key.key_hash)
+        .bind(&key.scopes)
+        .bind(key.rate_limit)
+        .bind(key.burst)
+        .bind(key.created_at)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        let ret =
+            sqlx::query_as("SELECT * FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL")
+                .bind(key_hash)
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(ret)
+    }
+
+    async fn revoke_api_key(&self, id: &str) -> Result<bool> {
+        let ret = sqlx::query(
+            "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL",
+        )
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn insert_report(&self, report: &AbuseReport) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO abuse_reports (id, domain, link_id, reason, details, reporter_hash, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&report.id)
+        .bind(&report.domain)
+        .bind(&report.link_id)
+        .bind(report.reason)
+        .bind(&report.details)
+        .bind(&report.reporter_hash)
+        .bind(report.created_at)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_reports(&self, filter: &ReportFilter) -> Result<Vec<AbuseReport>> {
+        let reports = sqlx::query_as(
+            r#"
+            SELECT * FROM abuse_reports
+            WHERE $1 OR resolved_at IS NULL
+            ORDER BY created_at DESC, id
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(filter.include_resolved)
+        .bind(filter.limit)
+        .bind(filter.offset)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(reports)
+    }
+
+    async fn moderate(&self, action: &ModerationAction) -> Result<bool> {
+        let (domain, id) = (&action.domain, &action.link_id);
+        let mut tx = self.db.begin().await?;
+        let ret = sqlx::query("UPDATE urls SET status = $3 WHERE domain = $1 AND id = $2")
+            .bind(domain)
+            .bind(id)
+            .bind(action.status)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO moderation_actions (id, domain, link_id, status, reason, actor, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&action.id)
+        .bind(domain)
+        .bind(id)
+        .bind(action.status)
+        .bind(&action.reason)
+        .bind(&action.actor)
+        .bind(action.created_at)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE abuse_reports SET resolved_at = $3
+            WHERE domain = $1 AND link_id = $2 AND resolved_at IS NULL
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(action.created_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        self.notify_changed(domain, id).await;
+        Ok(true)
+    }
+
+    async fn moderation_log(&self, domain: &str, id: &str) -> Result<Vec<ModerationAction>> {
+        let actions = sqlx::query_as(
+            r#"
+            SELECT * FROM moderation_actions
+            WHERE domain = $1 AND link_id = $2
+            ORDER BY created_at, id
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(actions)
+    }
+
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO webhooks (id, url, secret, events, click_thresholds, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(&webhook.id)
+        .bind(&webhook.url)
+        .bind(&webhook.secret)
+        .bind(&webhook.events)
+        .bind(&webhook.click_thresholds)
+        .bind(webhook.created_at)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>> {
+        let webhooks = sqlx::query_as("SELECT * FROM webhooks ORDER BY created_at, id")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(webhooks)
+    }
+
+    async fn delete_webhook(&self, id: &str) -> Result<bool> {
+        let ret = sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn enqueue_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+        let mut builder: QueryBuilder<Postgres> = QueryBuilder::new(
+            "INSERT INTO webhook_deliveries (id, webhook_id, event, payload, attempts, next_attempt_at, created_at) ",
+        );
+        builder.push_values(deliveries, |mut b, delivery| {
+            b.push_bind(&delivery.id)
+                .push_bind(&delivery.webhook_id)
+                .push_bind(delivery.event)
+                .push_bind(&delivery.payload)
+                .push_bind(delivery.attempts)
+                .push_bind(delivery.next_attempt_at)
+                .push_bind(delivery.created_at);
+        });
+        builder.build().execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn claim_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>> {
+        // SKIP LOCKED 跳过其他实例正在领取的行，多个实例可以同时发送不同的投递
+        let deliveries = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries SET next_attempt_at = $2
+            WHERE id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE next_attempt_at <= $1
+                ORDER BY next_attempt_at
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(lease_until)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(deliveries)
+    }
+
+    async fn complete_delivery(&self, id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM webhook_deliveries WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn retry_delivery(
+        &self,
+        id: &str,
+        attempts: i32,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE webhook_deliveries SET attempts = $2, next_attempt_at = $3, last_error = $4 WHERE id = $1",
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(next_attempt_at)
+        .bind(error)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn dead_letter(
+        &self,
+        id: &str,
+        attempts: i32,
+        error: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_dead_letters (id, webhook_id, event, payload, attempts, last_error, created_at, failed_at)
+            SELECT id, webhook_id, event, payload, $2, $3, created_at, $4
+            FROM webhook_deliveries WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(error)
+        .bind(failed_at)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM webhook_deliveries WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_dead_letters(&self, webhook_id: &str) -> Result<Vec<DeadLetter>> {
+        let letters = sqlx::query_as(
+            "SELECT * FROM webhook_dead_letters WHERE webhook_id = $1 ORDER BY failed_at, id",
+        )
+        .bind(webhook_id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(letters)
+    }
+
+    async fn replay_dead_letters(&self, webhook_id: &str, now: DateTime<Utc>) -> Result<u64> {
+        let mut tx = self.db.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (id, webhook_id, event, payload, attempts, next_attempt_at, last_error, created_at)
+            SELECT id, webhook_id, event, payload, 0, $2, last_error, created_at
+            FROM webhook_dead_letters WHERE webhook_id = $1
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM webhook_dead_letters WHERE webhook_id = $1")
+            .bind(webhook_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(ret.rows_affected())
+    }
+
+    async fn subscribe_changes(&self) -> Result<Option<mpsc::Receiver<Invalidation>>> {
+        let mut listener = PgListener::connect_with(&self.db).await?;
+        listener.listen(CHANGES_CHANNEL).await?;
+        let (tx, rx) = mpsc::channel(1024);
+        tokio::spawn(forward_changes(listener, tx));
+        Ok(Some(rx))
+    }
+}
+
+async fn insert_link(conn: &mut PgConnection, link: &NewLink) -> sqlx::Result<String> {
+    let ret = if link.is_permanent() {
+        // ON CONFLICT(url) WHERE ...：这部分指定在插入过程中，如果在永久随机短链接的 url 唯一索引上发生冲突
+        // （即已经有一个永久随机短链接指向相同的 url），应如何处理。WHERE 条件需要和部分唯一索引的条件一致。
+        // DO UPDATE SET url=EXCLUDED.url：当发生冲突时，不是简单地忽略或报错，而是执行更新操作。
+        // EXCLUDED 是一个特殊的表别名，代表正在尝试插入的那一行。
+        // SET url=EXCLUDED.url 表示将现有行的 url 列更新为冲突的那一行的 url 值（虽然在这种情况下，值是相同的，因此实际效果是保持不变）。
+        sqlx::query_as::<_, UrlRecord>(
+            r#"
+            INSERT INTO urls (domain, id, url, owner, redirect_type, tags, created_at, interstitial, passthrough, utm, routing)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT(domain, owner, url) WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL AND sealed_url IS NULL
+                AND redirect_type IS NULL AND tags = '[]' AND NOT interstitial AND NOT passthrough AND utm = '{}' AND routing = '{}'
+            DO UPDATE SET url=EXCLUDED.url RETURNING id
+            "#,
+        )
+        .bind(&link.domain)
+        .bind(&link.id)
+        .bind(&link.url)
+        .bind(&link.owner)
+        .bind(link.redirect_type)
+        .bind(Json(&link.tags))
+        .bind(Utc::now())
+        .bind(link.interstitial)
+        .bind(link.passthrough)
+        .bind(Json(&link.utm))
+        .bind(Json(&link.routing))
+        .fetch_one(&mut *conn)
+        .await
+    } else {
+        // 自定义短链接、带有过期策略或者其他选项的短链接不参与去重，直接插入，如果 id 已存在会触发主键冲突
+        sqlx::query_as::<_, UrlRecord>(
+            r#"
+            INSERT INTO urls (domain, id, url, custom, expires_at, not_before, remaining_clicks, owner, redirect_type, tags, created_at, interstitial, sealed_url, passthrough, utm, routing)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+            RETURNING id
+            "#,
+        )
+        .bind(&link.domain)
+        .bind(&link.id)
+        .bind(&link.url)
+        .bind(link.custom)
+        .bind(link.expires_at)
+        .bind(link.not_before)
+        .bind(link.max_clicks)
+        .bind(&link.owner)
+        .bind(link.redirect_type)
+        .bind(Json(&link.tags))
+        .bind(Utc::now())
+        .bind(link.interstitial)
+        .bind(&link.sealed_url)
+        .bind(link.passthrough)
+        .bind(Json(&link.utm))
+        .bind(Json(&link.routing))
+        .fetch_one(&mut *conn)
+        .await
+    };
+    ret.map(|record| record.id)
+}
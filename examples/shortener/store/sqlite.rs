@@ -0,0 +1,833 @@
+use super::{
+    is_unique_violation,
+    migrate::{self, MigrateCommand},
+    AbuseReport, ApiKey, DeadLetter, LinkFilter, LinkStore, LinkUpdate, ModerationAction, NewLink,
+    ReportFilter, StoreError, UrlRecord, Webhook, WebhookDelivery, COLUMNS,
+};
+use crate::clicks::{
+    Bucket, Click, ClickBucket, LinkStats, ReferrerCount, RuleCount, TOP_REFERRERS,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    types::Json,
+    Connection, QueryBuilder, Sqlite, SqliteConnection, SqlitePool,
+};
+use std::str::FromStr;
+use tracing::info;
+
+// 内嵌在程序中的版本化迁移，路径相对于 crate 根目录
+pub(super) static MIGRATOR: Migrator = sqlx::migrate!("examples/shortener/migrations/sqlite");
+
+// 引入版本化迁移之前后来新增的列，旧数据库第一次迁移之前需要先补齐。
+// SQLite 的 ADD COLUMN 不支持 IF NOT EXISTS，需要先检查列是否存在
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("urls", "owner", "TEXT"),
+    ("urls", "redirect_type", "INTEGER"),
+    ("urls", "tags", "TEXT NOT NULL DEFAULT '[]'"),
+    ("urls", "created_at", "TEXT"),
+    ("urls", "domain", "TEXT NOT NULL DEFAULT ''"),
+    ("clicks", "domain", "TEXT NOT NULL DEFAULT ''"),
+];
+
+// 旧数据库中已经被替换掉的索引
+const DROPPED_INDEXES: &[&str] = &[
+    "urls_permanent_url_key",
+    "urls_owner_permanent_url_key",
+    "clicks_link_id_clicked_at_idx",
+];
+
+const URLS_COLUMNS: &str = "domain, id, url, custom, expires_at, not_before, remaining_clicks, owner, redirect_type, tags, created_at";
+
+const DEAD_LINKS: &str = "julianday(expires_at) <= julianday('now') OR remaining_clicks = 0";
+
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    db: SqlitePool,
+}
+
+impl SqliteStore {
+    pub async fn try_new(url: &str, auto_migrate: bool) -> Result<Self> {
+        let pool = connect(url).await?;
+        if auto_migrate {
+            upgrade_legacy(&pool).await?;
+        }
+        migrate::prepare(&pool, &MIGRATOR, auto_migrate).await?;
+        Ok(Self { db: pool })
+    }
+
+    pub async fn migrate(url: &str, command: MigrateCommand) -> Result<()> {
+        let pool = connect(url).await?;
+        if command == MigrateCommand::Up {
+            upgrade_legacy(&pool).await?;
+        }
+        migrate::execute(&pool, &MIGRATOR, command).await
+    }
+}
+
+async fn connect(url: &str) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+    // 内存数据库是按连接隔离的，只能使用一个连接
+    let max_connections = if url.contains(":memory:") { 1 } else { 10 };
+    let pool = SqlitePoolOptions::new()
+        .max_connections(max_connections)
+        .connect_with(options)
+        .await?;
+    Ok(pool)
+}
+
+// 只处理有 urls 表、但还没有执行过迁移的旧数据库，其他情况什么都不做。
+// 迁移记录表可能已经被 `migrate status` 创建出来了，所以要看其中有没有记录
+async fn upgrade_legacy(pool: &SqlitePool) -> Result<()> {
+    if !table_exists(pool, "urls").await? {
+        return Ok(());
+    }
+    if table_exists(pool, "_sqlx_migrations").await? {
+        let (migrated,): (bool,) = sqlx::query_as("SELECT EXISTS (SELECT 1 FROM _sqlx_migrations)")
+            .fetch_one(pool)
+            .await?;
+        if migrated {
+            return Ok(());
+        }
+    }
+    info!("Upgrading a database created before versioned migrations");
+    for (table, column, definition) in ADDED_COLUMNS {
+        // 很早的数据库还没有 clicks 表，交给迁移创建
+        if !table_exists(pool, table).await? {
+            continue;
+        }
+        let (exists,): (bool,) =
+            sqlx::query_as("SELECT count(*) > 0 FROM pragma_table_info($1) WHERE name = $2")
+                .bind(table)
+                .bind(column)
+                .fetch_one(pool)
+                .await?;
+        if !exists {
+            sqlx::query(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            ))
+            .execute(pool)
+            .await?;
+        }
+    }
+    // 旧表的主键只有 id。SQLite 不能修改主键，只能按新的结构建一张表，把数据复制过去之后替换旧表
+    let (pk_columns,): (i64,) =
+        sqlx::query_as("SELECT count(*) FROM pragma_table_info('urls') WHERE pk > 0")
+            .fetch_one(pool)
+            .await?;
+    let mut tx = pool.begin().await?;
+    if pk_columns == 1 {
+        sqlx::query(&urls_table("urls_new"))
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!(
+            "INSERT INTO urls_new ({URLS_COLUMNS}) SELECT {URLS_COLUMNS} FROM urls"
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DROP TABLE urls").execute(&mut *tx).await?;
+        sqlx::query("ALTER TABLE urls_new RENAME TO urls")
+            .execute(&mut *tx)
+            .await?;
+    }
+    for index in DROPPED_INDEXES {
+        sqlx::query(&format!("DROP INDEX IF EXISTS {index}"))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn table_exists(pool: &SqlitePool, name: &str) -> Result<bool> {
+    let (exists,): (bool,) =
+        sqlx::query_as("SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = $1")
+            .bind(name)
+            .fetch_one(pool)
+            .await?;
+    Ok(exists)
+}
+
+#[async_trait]
+impl LinkStore for SqliteStore {
+    async fn create(&self, link: &NewLink) -> Result<String> {
+        let mut conn = self.db.acquire().await?;
+        match insert_link(&mut conn, link).await {
+            Ok(id) => Ok(id),
+            Err(e) if is_unique_violation(&e) => Err(StoreError::Conflict(link.id.clone()).into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn create_many(&self, links: &[NewLink]) -> Result<Vec<Result<String>>> {
+        // 和 PostgreSQL 一样，每一行使用一个 SAVEPOINT
+        let mut tx = self.db.begin().await?;
+        let mut results = Vec::with_capacity(links.len());
+        for link in links {
+            let mut savepoint = tx.begin().await?;
+            match insert_link(&mut savepoint, link).await {
+                Ok(id) => {
+                    savepoint.commit().await?;
+                    results.push(Ok(id));
+                }
+                Err(e) if is_unique_violation(&e) => {
+                    savepoint.rollback().await?;
+                    results.push(Err(StoreError::Conflict(link.id.clone()).into()));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+
+    async fn get(&self, domain: &str, id: &str) -> Result<Option<UrlRecord>> {
+        let ret = sqlx::query_as(&format!(
+            "SELECT {COLUMNS} FROM urls WHERE domain = $1 AND id = $2"
+        ))
+        .bind(domain)
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn update(&self, domain: &str, id: &str, update: &LinkUpdate) -> Result<bool> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE urls SET
+                url = COALESCE($3, url),
+                redirect_type = COALESCE($4, redirect_type),
+                tags = COALESCE($5, tags),
+                interstitial = COALESCE($6, interstitial),
+                passthrough = COALESCE($7, passthrough),
+                utm = COALESCE($8, utm),
+                routing = COALESCE($9, routing)
+            WHERE domain = $1 AND id = $2
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(&update.url)
+        .bind(update.redirect_type)
+        .bind(update.tags.as_ref().map(Json))
+        .bind(update.interstitial)
+        .bind(update.passthrough)
+        .bind(update.utm.as_ref().map(Json))
+        .bind(update.routing.as_ref().map(Json))
+        .execute(&self.db)
+        .await;
+        match ret {
+            Ok(ret) => Ok(ret.rows_affected() > 0),
+            Err(e) if is_unique_violation(&e) => {
+                Err(StoreError::DuplicateUrl(update.url.clone().unwrap_or_default()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, domain: &str, id: &str) -> Result<bool> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query("DELETE FROM clicks WHERE domain = $1 AND link_id = $2")
+            .bind(domain)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let ret = sqlx::query("DELETE FROM urls WHERE domain = $1 AND id = $2")
+            .bind(domain)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn list(&self, owner: &str, filter: &LinkFilter) -> Result<Vec<UrlRecord>> {
+        // json_each 把 JSON 数组展开成多行；instr 做子串匹配，不需要像 LIKE 那样转义 % 和 _
+        let ret = sqlx::query_as(&format!(
+            r#"
+            SELECT {COLUMNS} FROM urls
+            WHERE owner = $1
+                AND ($2 IS NULL OR EXISTS (SELECT 1 FROM json_each(urls.tags) WHERE value = $2))
+                AND ($3 IS NULL OR instr(lower(id || ' ' || url), lower($3)) > 0)
+            ORDER BY created_at DESC, domain, id
+            LIMIT $4 OFFSET $5
+            "#
+        ))
+        .bind(owner)
+        .bind(&filter.tag)
+        .bind(&filter.q)
+        .bind(filter.limit)
+        .bind(filter.offset)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn export(&self, after: Option<(&str, &str)>, limit: i64) -> Result<Vec<UrlRecord>> {
+        // 按主键做 keyset 分页，导出大量数据时不会像 OFFSET 那样越来越慢
+        let (domain, id) = after.unzip();
+        let ret = sqlx::query_as(&format!(
+            r#"
+            SELECT {COLUMNS} FROM urls
+            WHERE $1 IS NULL OR (domain, id) > ($1, $2)
+            ORDER BY domain, id
+            LIMIT $3
+            "#
+        ))
+        .bind(domain)
+        .bind(id)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(ret)
+    }
+
+    async fn import(&self, record: &UrlRecord) -> Result<()> {
+        // 主键冲突时什么都不做，这样才能和永久短链接的去重索引冲突区分开
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO urls (domain, id, url, custom, expires_at, not_before, remaining_clicks, owner, redirect_type, tags, created_at, interstitial, sealed_url, passthrough, utm, routing, status)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+            ON CONFLICT (domain, id) DO NOTHING
+            "#,
+        )
+        .bind(&record.domain)
+        .bind(&record.id)
+        .bind(&record.url)
+        .bind(record.custom)
+        .bind(record.expires_at)
+        .bind(record.not_before)
+        .bind(record.remaining_clicks)
+        .bind(&record.owner)
+        .bind(record.redirect_type)
+        .bind(&record.tags)
+        .bind(record.created_at.unwrap_or_else(Utc::now))
+        .bind(record.interstitial)
+        .bind(&record.sealed_url)
+        .bind(record.passthrough)
+        .bind(&record.utm)
+        .bind(&record.routing)
+        .bind(record.status)
+        .execute(&self.db)
+        .await;
+        match ret {
+            Ok(ret) if ret.rows_affected() == 0 => {
+                Err(StoreError::Conflict(record.id.clone()).into())
+            }
+            Ok(_) => Ok(()),
+            Err(e) if is_unique_violation(&e) => {
+                Err(StoreError::DuplicateUrl(record.url.clone()).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn take_click(&self, domain: &str, id: &str) -> Result<bool> {
+        // SQLite 的写操作是串行执行的，判断和扣减在同一条 UPDATE 中完成
+        let ret = sqlx::query(
+            r#"
+            UPDATE urls SET remaining_clicks = remaining_clicks - 1
+            WHERE domain = $1 AND id = $2 AND remaining_clicks > 0
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn purge_dead_links(&self) -> Result<Vec<UrlRecord>> {
+        // SQLite 不支持在 CTE 中使用 DELETE，这里在一个事务中分两步删除
+        let mut tx = self.db.begin().await?;
+        sqlx::query(&format!(
+            "DELETE FROM clicks WHERE (domain, link_id) IN (SELECT domain, id FROM urls WHERE {DEAD_LINKS})"
+        ))
+        .execute(&mut *tx)
+        .await?;
+        let purged = sqlx::query_as(&format!(
+            "DELETE FROM urls WHERE {DEAD_LINKS} RETURNING {COLUMNS}"
+        ))
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(purged)
+    }
+
+    async fn insert_clicks(&self, clicks: Vec<Click>) -> Result<()> {
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "INSERT INTO clicks (domain, link_id, clicked_at, referrer, user_agent, ip_hash, rule) ",
+        );
+        builder.push_values(clicks, |mut b, click| {
+            b.push_bind(click.domain)
+                .push_bind(click.link_id)
+                .push_bind(click.clicked_at)
+                .push_bind(click.referrer)
+                .push_bind(click.user_agent)
+                .push_bind(click.ip_hash)
+                .push_bind(click.rule);
+        });
+        builder.build().execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn link_stats(&self, domain: &str, id: &str, bucket: Bucket) -> Result<LinkStats> {
+        let (total, unique_visitors): (i64, i64) = sqlx::query_as(
+            "SELECT count(*), count(DISTINCT ip_hash) FROM clicks WHERE domain = $1 AND link_id = $2",
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_one(&self.db)
+        .await?;
+        // SQLite 没有 date_trunc，用 strftime 把时间格式化到小时或天
+        let format = match bucket {
+            Bucket::Hour => "%Y-%m-%dT%H:00:00Z",
+            Bucket::Day => "%Y-%m-%dT00:00:00Z",
+        };
+        let buckets: Vec<ClickBucket> = sqlx::query_as(
+            r#"
+            SELECT strftime($3, clicked_at) AS start, count(*) AS clicks
+            FROM clicks WHERE domain = $1 AND link_id = $2
+            GROUP BY start ORDER BY start
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(format)
+        .fetch_all(&self.db)
+        .await?;
+        let top_referrers: Vec<ReferrerCount> = sqlx::query_as(
+            r#"
+            SELECT referrer, count(*) AS clicks
+            FROM clicks WHERE domain = $1 AND link_id = $2 AND referrer IS NOT NULL
+            GROUP BY referrer ORDER BY clicks DESC, referrer LIMIT $3
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(TOP_REFERRERS)
+        .fetch_all(&self.db)
+        .await?;
+        let rules: Vec<RuleCount> = sqlx::query_as(
+            r#"
+            SELECT rule, count(*) AS clicks
+            FROM clicks WHERE domain = $1 AND link_id = $2 AND rule IS NOT NULL
+            GROUP BY rule ORDER BY clicks DESC, rule
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(LinkStats {
+            total,
+            unique_visitors,
+            buckets,
+            top_referrers,
+            rules,
+        })
+    }
+
+    async fn count_clicks(&self, domain: &str, id: &str) -> Result<i64> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM clicks WHERE domain = $1 AND link_id = $2")
+                .bind(domain)
+                .bind(id)
+                .fetch_one(&self.db)
+                .await?;
+        Ok(count)
+    }
+
+    async fn next_sequence(&self) -> Result<u64> {
+        let (seq,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO sequences (name, value) VALUES ('urls', 1)
+            ON CONFLICT(name) DO UPDATE SET value = value + 1
+            RETURNING value
+            "#,
+        )
+        .fetch_one(&self.db)
+        .await?;
+        Ok(seq as u64)
+    }
+
+    async fn create_api_key(&self, key: &ApiKey) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO api_keys (id, name, key_hash, scopes, rate_limit, burst, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&key.id)
+        .bind(&key.name)
+        .bind(&key.key_hash)
+        .bind(&key.scopes)
+        .bind(key.rate_limit)
+        .bind(key.burst)
+        .bind(key.created_at)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        let ret =
+            sqlx::query_as("SELECT * FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL")
+                .bind(key_hash)
+                .fetch_optional(&self.db)
+                .await?;
+        Ok(ret)
+    }
+
+    async fn revoke_api_key(&self, id: &str) -> Result<bool> {
+        let ret =
+            sqlx::query("UPDATE api_keys SET revoked_at = $2 WHERE id = $1 AND revoked_at IS NULL")
+                .bind(id)
+                .bind(Utc::now())
+                .execute(&self.db)
+                .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn insert_report(&self, report: &AbuseReport) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO abuse_reports (id, domain, link_id, reason, details, reporter_hash, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&report.id)
+        .bind(&report.domain)
+        .bind(&report.link_id)
+        .bind(report.reason)
+        .bind(&report.details)
+        .bind(&report.reporter_hash)
+        .bind(report.created_at)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_reports(&self, filter: &ReportFilter) -> Result<Vec<AbuseReport>> {
+        let reports = sqlx::query_as(
+            r#"
+            SELECT * FROM abuse_reports
+            WHERE $1 OR resolved_at IS NULL
+            ORDER BY created_at DESC, id
+            LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(filter.include_resolved)
+        .bind(filter.limit)
+        .bind(filter.offset)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(reports)
+    }
+
+    async fn moderate(&self, action: &ModerationAction) -> Result<bool> {
+        let (domain, id) = (&action.domain, &action.link_id);
+        let mut tx = self.db.begin().await?;
+        let ret = sqlx::query("UPDATE urls SET status = $3 WHERE domain = $1 AND id = $2")
+            .bind(domain)
+            .bind(id)
+            .bind(action.status)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            r#"
+            INSERT INTO moderation_actions (id, domain, link_id, status, reason, actor, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        )
+        .bind(&action.id)
+        .bind(domain)
+        .bind(id)
+        .bind(action.status)
+        .bind(&action.reason)
+        .bind(&action.actor)
+        .bind(action.created_at)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE abuse_reports SET resolved_at = $3
+            WHERE domain = $1 AND link_id = $2 AND resolved_at IS NULL
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .bind(action.created_at)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn moderation_log(&self, domain: &str, id: &str) -> Result<Vec<ModerationAction>> {
+        let actions = sqlx::query_as(
+            r#"
+            SELECT * FROM moderation_actions
+            WHERE domain = $1 AND link_id = $2
+            ORDER BY created_at, id
+            "#,
+        )
+        .bind(domain)
+        .bind(id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(actions)
+    }
+
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO webhooks (id, url, secret, events, click_thresholds, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        )
+        .bind(&webhook.id)
+        .bind(&webhook.url)
+        .bind(&webhook.secret)
+        .bind(&webhook.events)
+        .bind(&webhook.click_thresholds)
+        .bind(webhook.created_at)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<Webhook>> {
+        let webhooks = sqlx::query_as("SELECT * FROM webhooks ORDER BY created_at, id")
+            .fetch_all(&self.db)
+            .await?;
+        Ok(webhooks)
+    }
+
+    async fn delete_webhook(&self, id: &str) -> Result<bool> {
+        let ret = sqlx::query("DELETE FROM webhooks WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    async fn enqueue_deliveries(&self, deliveries: &[WebhookDelivery]) -> Result<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+        let mut builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+            "INSERT INTO webhook_deliveries (id, webhook_id, event, payload, attempts, next_attempt_at, created_at) ",
+        );
+        builder.push_values(deliveries, |mut b, delivery| {
+            b.push_bind(&delivery.id)
+                .push_bind(&delivery.webhook_id)
+                .push_bind(delivery.event)
+                .push_bind(&delivery.payload)
+                .push_bind(delivery.attempts)
+                .push_bind(delivery.next_attempt_at)
+                .push_bind(delivery.created_at);
+        });
+        builder.build().execute(&self.db).await?;
+        Ok(())
+    }
+
+    async fn claim_deliveries(
+        &self,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<WebhookDelivery>> {
+        // SQLite 同一时间只有一个写事务，一条 UPDATE 就可以保证同一条投递不会被领取两次
+        let deliveries = sqlx::query_as(
+            r#"
+            UPDATE webhook_deliveries SET next_attempt_at = $2
+            WHERE id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE julianday(next_attempt_at) <= julianday($1)
+                ORDER BY julianday(next_attempt_at)
+                LIMIT $3
+            )
+            RETURNING *
+            "#,
+        )
+        .bind(now)
+        .bind(lease_until)
+        .bind(limit)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(deliveries)
+    }
+
+    async fn complete_delivery(&self, id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM webhook_deliveries WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+
+    async fn retry_delivery(
+        &self,
+        id: &str,
+        attempts: i32,
+        next_attempt_at: DateTime<Utc>,
+        error: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE webhook_deliveries SET attempts = $2, next_attempt_at = $3, last_error = $4 WHERE id = $1",
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(next_attempt_at)
+        .bind(error)
+        .execute(&self.db)
+        .await?;
+        Ok(())
+    }
+
+    async fn dead_letter(
+        &self,
+        id: &str,
+        attempts: i32,
+        error: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_dead_letters (id, webhook_id, event, payload, attempts, last_error, created_at, failed_at)
+            SELECT id, webhook_id, event, payload, $2, $3, created_at, $4
+            FROM webhook_deliveries WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(error)
+        .bind(failed_at)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM webhook_deliveries WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_dead_letters(&self, webhook_id: &str) -> Result<Vec<DeadLetter>> {
+        let letters = sqlx::query_as(
+            "SELECT * FROM webhook_dead_letters WHERE webhook_id = $1 ORDER BY julianday(failed_at), id",
+        )
+        .bind(webhook_id)
+        .fetch_all(&self.db)
+        .await?;
+        Ok(letters)
+    }
+
+    async fn replay_dead_letters(&self, webhook_id: &str, now: DateTime<Utc>) -> Result<u64> {
+        let mut tx = self.db.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO webhook_deliveries (id, webhook_id, event, payload, attempts, next_attempt_at, last_error, created_at)
+            SELECT id, webhook_id, event, payload, 0, $2, last_error, created_at
+            FROM webhook_dead_letters WHERE webhook_id = $1
+            "#,
+        )
+        .bind(webhook_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM webhook_dead_letters WHERE webhook_id = $1")
+            .bind(webhook_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(ret.rows_affected())
+    }
+}
+
+async fn insert_link(conn: &mut SqliteConnection, link: &NewLink) -> sqlx::Result<String> {
+    let ret = if link.is_permanent() {
+        // SQLite 同样支持 upsert，冲突目标的 WHERE 条件需要和部分唯一索引的条件一致
+        sqlx::query_as::<_, UrlRecord>(
+            r#"
+            INSERT INTO urls (domain, id, url, owner, redirect_type, tags, created_at, interstitial, passthrough, utm, routing)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT(domain, owner, url) WHERE NOT custom AND expires_at IS NULL AND not_before IS NULL AND remaining_clicks IS NULL AND sealed_url IS NULL
+                AND redirect_type IS NULL AND tags = '[]' AND NOT interstitial AND NOT passthrough AND utm = '{}' AND routing = '{}'
+            DO UPDATE SET url=excluded.url RETURNING id
+            "#,
+        )
+        .bind(&link.domain)
+        .bind(&link.id)
+        .bind(&link.url)
+        .bind(&link.owner)
+        .bind(link.redirect_type)
+        .bind(Json(&link.tags))
+        .bind(Utc::now())
+        .bind(link.interstitial)
+        .bind(link.passthrough)
+        .bind(Json(&link.utm))
+        .bind(Json(&link.routing))
+        .fetch_one(&mut *conn)
+        .await
+    } else {
+        sqlx::query_as::<_, UrlRecord>(
+            r#"
+            INSERT INTO urls (domain, id, url, custom, expires_at, not_before, remaining_clicks, owner, redirect_type, tags, created_at, interstitial, sealed_url, passthrough, utm, routing)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+            RETURNING id
+            "#,
+        )
+        .bind(&link.domain)
+        .bind(&link.id)
+        .bind(&link.url)
+        .bind(link.custom)
+        .bind(link.expires_at)
+        .bind(link.not_before)
+        .bind(link.max_clicks)
+        .bind(&link.owner)
+        .bind(link.redirect_type)
+        .bind(Json(&link.tags))
+        .bind(Utc::now())
+        .bind(link.interstitial)
+        .bind(&link.sealed_url)
+        .bind(link.passthrough)
+        .bind(Json(&link.utm))
+        .bind(Json(&link.routing))
+        .fetch_one(&mut *conn)
+        .await
+    };
+    ret.map(|record| record.id)
+}
+
+// 升级旧表时使用的表结构，和 0001 迁移中的 urls 表相同
+fn urls_table(name: &str) -> String {
+    format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS {name} (
+            domain TEXT NOT NULL DEFAULT '',
+            id TEXT NOT NULL,
+            url TEXT NOT NULL,
+            custom BOOLEAN NOT NULL DEFAULT FALSE,
+            expires_at TEXT,
+            not_before TEXT,
+            remaining_clicks INTEGER,
+            owner TEXT,
+            redirect_type INTEGER,
+            tags TEXT NOT NULL DEFAULT '[]',
+            created_at TEXT,
+            PRIMARY KEY (domain, id)
+        )
+        "#
+    )
+}
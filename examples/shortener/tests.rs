@@ -0,0 +1,1563 @@
+use crate::{
+    app,
+    clicks::IpHasher,
+    config::{Config, IdStrategy, StatelessKey},
+    state::AppState,
+    store::{MemoryStore, RedirectType},
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::connect_info::MockConnectInfo,
+    Router,
+};
+use http::{
+    header::{
+        ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, COOKIE, HOST, LOCATION, RETRY_AFTER,
+        SET_COOKIE, USER_AGENT,
+    },
+    Method, Request, StatusCode,
+};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tower::ServiceExt;
+
+const ADMIN_KEY: &str = "admin-secret";
+
+// 使用内存存储，不需要启动 PostgreSQL
+fn test_app() -> Router {
+    test_app_with(Config::default())
+}
+
+fn test_app_with(config: Config) -> Router {
+    let config = Config {
+        database_url: "memory://".to_string(),
+        admin_key: Some(ADMIN_KEY.to_string()),
+        ..config
+    };
+    let state = AppState::new(config, Arc::new(MemoryStore::default()));
+    // 测试时没有真实的 TCP 连接，用 MockConnectInfo 提供客户端地址
+    app(state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 10000))))
+}
+
+async fn send(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, http::HeaderMap, Value) {
+    send_with_token(app, method, uri, None, body).await
+}
+
+async fn send_with_token(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    token: Option<&str>,
+    body: Option<Value>,
+) -> (StatusCode, http::HeaderMap, Value) {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("content-type", "application/json");
+    // 以 sk_ 开头的是 API key，其余的是管理令牌
+    match token {
+        Some(key) if key.starts_with("sk_") || key == ADMIN_KEY => {
+            req = req.header(AUTHORIZATION, format!("Bearer {key}"));
+        }
+        Some(token) => req = req.header("x-management-token", token),
+        None => {}
+    }
+    let body = body.map_or_else(Body::empty, |v| Body::from(v.to_string()));
+    let res = app.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let value = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+    (status, headers, value)
+}
+
+async fn shorten(app: &Router, body: Value) -> (StatusCode, String) {
+    let (status, _, value) = send(app, Method::POST, "/", Some(body)).await;
+    let id = value["url"]
+        .as_str()
+        .map(|url| url.rsplit('/').next().unwrap().to_string())
+        .unwrap_or_default();
+    (status, id)
+}
+
+#[tokio::test]
+async fn shorten_and_redirect() {
+    let app = test_app();
+    let (status, id) = shorten(&app, json!({ "url": "https://www.rust-lang.org" })).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, headers, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(headers[LOCATION], "https://www.rust-lang.org/");
+
+    let (status, _, problem) = send(&app, Method::GET, "/unknown", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(problem["code"], "not_found");
+}
+
+#[tokio::test]
+async fn shorten_dedupes_identical_urls_of_the_same_owner() {
+    let app = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body.clone())).await;
+    let first = res["url"].as_str().unwrap().to_string();
+    let token = res["token"].as_str().unwrap();
+
+    let (_, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(res["url"], first);
+    // 调用方提供了令牌，不会再返回新的令牌
+    assert!(res.get("token").is_none());
+    // 规范化之后是同一个地址
+    let body = json!({ "url": "HTTPS://WWW.Rust-Lang.org:443/" });
+    let (_, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(res["url"], first);
+
+    // 短链接可以被所有者修改，所以不同所有者之间不去重
+    let (_, other) = shorten(&app, json!({ "url": "https://www.rust-lang.org" })).await;
+    assert_ne!(first.rsplit('/').next().unwrap(), other);
+}
+
+#[tokio::test]
+async fn shorten_does_not_dedupe_links_with_passthrough_or_utm() {
+    let app = test_app();
+    let body = json!({ "url": "https://docs.rs/" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let plain = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let token = res["token"].as_str().unwrap();
+
+    // 相同的地址带上选项时新建短链接，返回已有的短链接会丢掉这些选项
+    let body = json!({
+        "url": "https://docs.rs/",
+        "passthrough": true,
+        "utm": { "source": "newsletter" },
+    });
+    let (status, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    assert_ne!(id, plain);
+    let (_, headers, _) = send(&app, Method::GET, &format!("/{id}/tokio"), None).await;
+    assert_eq!(
+        headers[LOCATION],
+        "https://docs.rs/tokio?utm_source=newsletter"
+    );
+    let (_, headers, _) = send(&app, Method::GET, &format!("/{plain}"), None).await;
+    assert_eq!(headers[LOCATION], "https://docs.rs/");
+
+    // 去掉选项之后和普通的短链接重复
+    let patch = json!({ "passthrough": false, "utm": {} });
+    let uri = format!("/{id}");
+    let (status, _, _) = send_with_token(&app, Method::PATCH, &uri, Some(token), Some(patch)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn shorten_does_not_dedupe_links_with_routing() {
+    let app = test_app();
+    let body = json!({ "url": "https://example.com/" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let plain = res["url"].as_str().unwrap().to_string();
+    let token = res["token"].as_str().unwrap();
+
+    let body = json!({
+        "url": "https://example.com/",
+        "routing": { "variants": [{ "url": "https://example.com/a", "weight": 1 }] },
+    });
+    let (status, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_ne!(res["url"], plain);
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let (_, headers, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(headers[LOCATION], "https://example.com/a");
+}
+
+#[tokio::test]
+async fn shorten_does_not_dedupe_interstitial_links() {
+    let app = test_app();
+    let body = json!({ "url": "https://example.com/" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let plain = res["url"].as_str().unwrap().to_string();
+    let token = res["token"].as_str().unwrap();
+
+    // 去重时返回已有的短链接会跳过中间页
+    let body = json!({ "url": "https://example.com/", "interstitial": true });
+    let (status, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_ne!(res["url"], plain);
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = send(&app, Method::GET, &plain[plain.rfind('/').unwrap()..], None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+}
+
+#[tokio::test]
+async fn shorten_does_not_dedupe_links_with_tags_or_redirect_type() {
+    let app = test_app();
+    let body = json!({ "url": "https://example.com/" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let plain = res["url"].as_str().unwrap().to_string();
+    let token = res["token"].as_str().unwrap();
+
+    // 去重时返回已有的短链接会丢掉标签和跳转类型
+    let body = json!({ "url": "https://example.com/", "redirect_type": 302 });
+    let (status, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_ne!(res["url"], plain);
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::FOUND);
+
+    let body = json!({ "url": "https://example.com/", "tags": ["launch"] });
+    let (status, _, res) = send_with_token(&app, Method::POST, "/", Some(token), Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_ne!(res["url"], plain);
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let uri = "/api/links?tag=launch";
+    let (_, _, res) = send_with_token(&app, Method::GET, uri, Some(token), None).await;
+    assert_eq!(res["links"][0]["id"], id);
+}
+
+#[tokio::test]
+async fn stats_are_only_visible_to_the_owner_and_admins() {
+    let app = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org", "alias": "owned" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let token = res["token"].as_str().unwrap().to_string();
+    let (_, _, res) = send(
+        &app,
+        Method::POST,
+        "/",
+        Some(json!({ "url": "https://crates.io" })),
+    )
+    .await;
+    let stranger = res["token"].as_str().unwrap().to_string();
+
+    let uri = "/owned/stats";
+    let (status, _, _) = send(&app, Method::GET, uri, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = send_with_token(&app, Method::GET, uri, Some(&stranger), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, stats) = send_with_token(&app, Method::GET, uri, Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["total"], 0);
+    let (status, _, _) = send_with_token(&app, Method::GET, uri, Some(ADMIN_KEY), None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn alias_conflict_returns_409() {
+    let app = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org", "alias": "rust" });
+    let (status, id) = shorten(&app, body.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(id, "rust");
+
+    // 错误按照 RFC 7807 返回 problem+json
+    let (status, headers, problem) = send(&app, Method::POST, "/", Some(body)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(headers[CONTENT_TYPE], "application/problem+json");
+    assert_eq!(problem["code"], "conflict");
+    assert_eq!(problem["status"], 409);
+
+    let (status, _) = shorten(
+        &app,
+        json!({ "url": "https://www.rust-lang.org", "alias": "api" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn burn_after_reading_link_is_gone_after_one_click() {
+    let app = test_app();
+    let (_, id) = shorten(
+        &app,
+        json!({ "url": "https://www.rust-lang.org", "max_clicks": 1 }),
+    )
+    .await;
+
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    let (status, _, problem) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::GONE);
+    assert_eq!(problem["code"], "expired");
+}
+
+#[tokio::test]
+async fn malformed_request_returns_problem_json() {
+    let app = test_app();
+    let (status, headers, problem) =
+        send(&app, Method::POST, "/", Some(json!({ "alias": "rust" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(headers[CONTENT_TYPE], "application/problem+json");
+    assert_eq!(problem["code"], "validation_failed");
+}
+
+#[tokio::test]
+async fn cached_missing_link_is_invalidated_on_create() {
+    let app = test_app();
+    let (status, _, _) = send(&app, Method::GET, "/rust", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let body = json!({ "url": "https://www.rust-lang.org", "alias": "rust" });
+    let (status, _) = shorten(&app, body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    for _ in 0..2 {
+        let (status, _, _) = send(&app, Method::GET, "/rust", None).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    }
+
+    let (_, _, stats) = send(&app, Method::GET, "/api/cache", None).await;
+    assert_eq!(stats["hits"], 1);
+    assert_eq!(stats["misses"], 2);
+}
+
+#[tokio::test]
+async fn owner_can_update_list_and_delete_links() {
+    let app = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org", "alias": "rust", "tags": ["lang"] });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let token = res["token"].as_str().unwrap().to_string();
+    let token = Some(token.as_str());
+    let body = json!({ "url": "https://crates.io", "tags": ["registry"] });
+    send_with_token(&app, Method::POST, "/", token, Some(body)).await;
+
+    let patch = json!({ "url": "https://doc.rust-lang.org", "redirect_type": 302 });
+    let (status, _, link) =
+        send_with_token(&app, Method::PATCH, "/rust", token, Some(patch.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(link["redirect_type"], 302);
+    let (status, headers, _) = send(&app, Method::GET, "/rust", None).await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(headers[LOCATION], "https://doc.rust-lang.org/");
+
+    let (_, _, res) = send_with_token(&app, Method::GET, "/api/links", token, None).await;
+    assert_eq!(res["links"].as_array().unwrap().len(), 2);
+    let (_, _, res) =
+        send_with_token(&app, Method::GET, "/api/links?tag=registry", token, None).await;
+    assert_eq!(res["links"][0]["url"], "https://crates.io/");
+    let (_, _, res) = send_with_token(&app, Method::GET, "/api/links?q=DOC.", token, None).await;
+    assert_eq!(res["links"][0]["id"], "rust");
+    let (_, _, res) = send_with_token(
+        &app,
+        Method::GET,
+        "/api/links?limit=1&offset=1",
+        token,
+        None,
+    )
+    .await;
+    assert_eq!(res["links"].as_array().unwrap().len(), 1);
+    assert_eq!(res["next_offset"], 2);
+
+    // 其他令牌不能修改或删除
+    let other = Some("x".repeat(32));
+    let (status, _, _) =
+        send_with_token(&app, Method::PATCH, "/rust", other.as_deref(), Some(patch)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, _) = send(&app, Method::DELETE, "/rust", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _, _) = send_with_token(&app, Method::DELETE, "/rust", token, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _, _) = send(&app, Method::GET, "/rust", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn anonymous_callers_are_rate_limited() {
+    let app = test_app();
+    // 默认的匿名配额可以突发 5 个请求
+    for i in 0..5 {
+        let body = json!({ "url": format!("https://www.rust-lang.org/{i}") });
+        let (status, _) = shorten(&app, body).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let body = json!({ "url": "https://www.rust-lang.org/5" });
+    let (status, headers, problem) = send(&app, Method::POST, "/", Some(body)).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(problem["code"], "rate_limited");
+    let retry_after: u64 = headers[RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!(retry_after >= 1);
+}
+
+#[tokio::test]
+async fn api_keys_are_scoped_and_revocable() {
+    let app = test_app();
+    let req = json!({ "name": "marketing", "scopes": ["create"], "burst": 1 });
+    let (status, _, _) = send(&app, Method::POST, "/api/keys", Some(req.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, key) =
+        send_with_token(&app, Method::POST, "/api/keys", Some(ADMIN_KEY), Some(req)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (id, key) = (key["id"].as_str().unwrap(), key["key"].as_str().unwrap());
+
+    let body = json!({ "url": "https://www.rust-lang.org" });
+    let (status, _, _) =
+        send_with_token(&app, Method::POST, "/", Some(key), Some(body.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    // 签发时指定了 burst 为 1
+    let (status, _, _) =
+        send_with_token(&app, Method::POST, "/", Some(key), Some(body.clone())).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    // 没有 manage 权限
+    let (status, _, _) = send_with_token(&app, Method::GET, "/api/links", Some(key), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    // 普通的 key 不能签发 key
+    let req = json!({ "name": "other", "scopes": ["admin"] });
+    let (status, _, _) =
+        send_with_token(&app, Method::POST, "/api/keys", Some(key), Some(req)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let uri = format!("/api/keys/{id}");
+    let (status, _, _) = send_with_token(&app, Method::DELETE, &uri, Some(ADMIN_KEY), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _, _) = send_with_token(&app, Method::POST, "/", Some(key), Some(body)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+async fn bulk(app: &Router, key: &str, content_type: &str, body: &str) -> (StatusCode, Vec<Value>) {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/api/bulk")
+        .header(CONTENT_TYPE, content_type)
+        .header(AUTHORIZATION, format!("Bearer {key}"))
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    let status = res.status();
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    let rows = String::from_utf8(bytes.to_vec())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    (status, rows)
+}
+
+#[tokio::test]
+async fn bulk_shorten_accepts_json_ndjson_and_csv() {
+    let app = test_app();
+    let req = json!({ "name": "marketing", "scopes": ["create"] });
+    let (_, _, key) =
+        send_with_token(&app, Method::POST, "/api/keys", Some(ADMIN_KEY), Some(req)).await;
+    let key = key["key"].as_str().unwrap();
+
+    let body = json!([
+        { "url": "https://www.rust-lang.org" },
+        { "url": "ftp://example.com" },
+        { "url": "https://tokio.rs", "alias": "tokio" },
+    ]);
+    let (status, rows) = bulk(&app, key, "application/json", &body.to_string()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rows.len(), 3);
+    let rust = rows[0]["url"].as_str().unwrap().to_string();
+    assert_eq!(rows[1]["index"], 1);
+    assert_eq!(rows[1]["error"]["code"], "validation_failed");
+    assert!(rows[2]["url"].as_str().unwrap().ends_with("/tokio"));
+
+    // 同一个 key 创建的相同地址会去重，已被占用的自定义短链接返回冲突
+    let body = "{\"url\":\"https://www.rust-lang.org/\"}\n\nnot json\n{\"url\":\"https://tokio.rs\",\"alias\":\"tokio\"}\n";
+    let (_, rows) = bulk(&app, key, "application/x-ndjson", body).await;
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0]["url"], rust);
+    assert_eq!(rows[1]["error"]["code"], "validation_failed");
+    assert_eq!(rows[2]["error"]["code"], "conflict");
+
+    let body =
+        "url,alias,redirect_type,tags\nhttps://docs.rs,docs,302,rust;docs\nhttps://crates.io,,,\n";
+    let (_, rows) = bulk(&app, key, "text/csv", body).await;
+    assert!(rows.iter().all(|row| row.get("error").is_none()));
+    let (status, headers, _) = send(&app, Method::GET, "/docs", None).await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(headers[LOCATION], "https://docs.rs/");
+
+    // 每一条记录消耗一个令牌，令牌用完之后的记录返回限流错误
+    let req = json!({ "name": "limited", "scopes": ["create"], "burst": 2 });
+    let (_, _, limited) =
+        send_with_token(&app, Method::POST, "/api/keys", Some(ADMIN_KEY), Some(req)).await;
+    let limited = limited["key"].as_str().unwrap();
+    let body = "url\nhttps://serde.rs\nhttps://axum.rs\nhttps://hyper.rs\n";
+    let (_, rows) = bulk(&app, limited, "text/csv", body).await;
+    assert!(rows[0]["url"].is_string());
+    assert!(rows[1]["url"].is_string());
+    assert_eq!(rows[2]["error"]["code"], "rate_limited");
+    let (status, _, _) = send_with_token(
+        &app,
+        Method::POST,
+        "/",
+        Some(limited),
+        Some(json!({ "url": "https://serde.rs" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+    // 匿名调用方不能使用批量接口
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/api/bulk")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from("[]"))
+        .unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+// 向某个域名发送请求，Host 为 None 时不带 Host 请求头
+async fn send_to_host(
+    app: &Router,
+    method: Method,
+    host: Option<&str>,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, http::HeaderMap, Value) {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(CONTENT_TYPE, "application/json");
+    if let Some(host) = host {
+        req = req.header(HOST, host);
+    }
+    let body = body.map_or_else(Body::empty, |v| Body::from(v.to_string()));
+    let res = app.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (
+        status,
+        headers,
+        serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+    )
+}
+
+#[tokio::test]
+async fn links_belong_to_the_domain_of_the_request() {
+    let app = test_app_with(Config {
+        base_url: "https://sho.rt".to_string(),
+        domains: vec!["go.example.com".to_string()],
+        ..Default::default()
+    });
+    let body = json!({ "url": "https://www.rust-lang.org", "alias": "promo" });
+    let (status, _, res) = send_to_host(&app, Method::POST, None, "/", Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(res["url"], "https://sho.rt/promo");
+
+    // 同一个 id 可以在不同的域名下各存在一个
+    let body = json!({ "url": "https://tokio.rs", "alias": "promo" });
+    let host = Some("GO.example.com:443");
+    let (status, _, res) = send_to_host(&app, Method::POST, host, "/", Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(res["url"], "https://go.example.com/promo");
+
+    let (_, headers, _) = send_to_host(&app, Method::GET, None, "/promo", None).await;
+    assert_eq!(headers[LOCATION], "https://www.rust-lang.org/");
+    let (_, headers, _) = send_to_host(&app, Method::GET, host, "/promo", None).await;
+    assert_eq!(headers[LOCATION], "https://tokio.rs/");
+
+    // 请求体中可以显式指定域名，但必须是配置过的域名
+    let body = json!({ "url": "https://docs.rs", "domain": "go.example.com" });
+    let (_, _, res) = send_to_host(&app, Method::POST, None, "/", Some(body)).await;
+    assert!(res["url"]
+        .as_str()
+        .unwrap()
+        .starts_with("https://go.example.com/"));
+    let body = json!({ "url": "https://docs.rs", "domain": "evil.example.com" });
+    let (status, _, _) = send_to_host(&app, Method::POST, None, "/", Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn qr_code_uses_the_redirect_lookup() {
+    let app = test_app();
+    let (_, id) = shorten(
+        &app,
+        json!({ "url": "https://www.rust-lang.org", "max_clicks": 1 }),
+    )
+    .await;
+    let qr = |uri: String, accept: &'static str| {
+        let req = Request::get(uri).header(ACCEPT, accept);
+        let app = app.clone();
+        async move {
+            let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+            let status = res.status();
+            let content_type = res.headers().get(CONTENT_TYPE).cloned();
+            let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            (status, content_type, bytes)
+        }
+    };
+
+    let (status, content_type, body) = qr(format!("/{id}/qr"), "*/*").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.unwrap(), "image/svg+xml");
+    assert!(String::from_utf8_lossy(&body).contains("<svg"));
+
+    let (_, content_type, body) = qr(format!("/{id}/qr?size=128&ecc=H"), "image/png").await;
+    assert_eq!(content_type.unwrap(), "image/png");
+    assert!(body.starts_with(b"\x89PNG"));
+    // 查询参数优先于 Accept
+    let (_, content_type, _) = qr(format!("/{id}/qr?format=svg"), "image/png").await;
+    assert_eq!(content_type.unwrap(), "image/svg+xml");
+
+    let (status, _, _) = qr(format!("/{id}/qr?size=1"), "*/*").await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _, _) = qr("/unknown/qr".to_string(), "*/*").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // 生成二维码不消耗访问次数
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+}
+
+#[tokio::test]
+async fn redirect_type_defaults_to_config_and_can_be_previewed() {
+    let app = test_app_with(Config {
+        redirect_type: RedirectType::Found,
+        ..Default::default()
+    });
+    let (_, id) = shorten(&app, json!({ "url": "https://www.rust-lang.org" })).await;
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::FOUND);
+    let body = json!({ "url": "https://tokio.rs", "redirect_type": 301 });
+    let (_, id) = shorten(&app, body).await;
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+
+    // 预览页只显示目标地址，不消耗访问次数
+    let body = json!({ "url": "https://docs.rs/?q=a&b", "max_clicks": 1 });
+    let (_, id) = shorten(&app, body).await;
+    let req = Request::get(format!("/{id}+")).body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&page).contains(r#"href="https://docs.rs/?q=a&amp;b""#));
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::FOUND);
+    let (status, _, _) = send(&app, Method::GET, "/unknown+", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn interstitial_warns_before_redirecting() {
+    let app = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org", "interstitial": true });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let token = res["token"].as_str().unwrap();
+
+    let req = Request::get(format!("/{id}")).body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()[CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&page)
+        .contains(r#"<meta http-equiv="refresh" content="5;url=https://www.rust-lang.org/">"#));
+
+    // 关闭之后恢复直接跳转
+    let patch = json!({ "interstitial": false });
+    let uri = format!("/{id}");
+    let (_, _, link) = send_with_token(&app, Method::PATCH, &uri, Some(token), Some(patch)).await;
+    assert_eq!(link["interstitial"], false);
+    let (status, _, _) = send(&app, Method::GET, &uri, None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+}
+
+#[tokio::test]
+async fn passthrough_links_forward_path_query_and_utm() {
+    let app = test_app();
+    let body = json!({
+        "url": "https://docs.rs/tokio/?lang=en",
+        "passthrough": true,
+        "utm": { "source": "newsletter", "campaign": "launch" },
+    });
+    let (status, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let token = res["token"].as_str().unwrap();
+
+    let uri = format!("/{id}/latest/tokio?lang=zh&utm_source=twitter");
+    let (status, headers, _) = send(&app, Method::GET, &uri, None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(
+        headers[LOCATION],
+        "https://docs.rs/tokio/latest/tokio?lang=zh&utm_source=twitter&utm_campaign=launch"
+    );
+    // /:id/qr 和 /:id/stats 不会被当作追加的路径
+    let (status, headers, _) = send(&app, Method::GET, &format!("/{id}/qr"), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[CONTENT_TYPE], "image/svg+xml");
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}/a/%2E%2E/b"), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // 关闭 passthrough 之后追加路径返回 404，只有 UTM 参数可以被覆盖
+    let patch = json!({ "passthrough": false, "utm": { "medium": "email" } });
+    let uri = format!("/{id}");
+    let (_, _, link) = send_with_token(&app, Method::PATCH, &uri, Some(token), Some(patch)).await;
+    assert_eq!(link["passthrough"], false);
+    assert_eq!(link["utm"], json!({ "medium": "email" }));
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}/latest"), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let uri = format!("/{id}?lang=zh&utm_medium=social");
+    let (_, headers, _) = send(&app, Method::GET, &uri, None).await;
+    assert_eq!(
+        headers[LOCATION],
+        "https://docs.rs/tokio/?lang=en&utm_medium=social"
+    );
+}
+
+#[tokio::test]
+async fn routing_rules_pick_destinations_by_device_and_weight() {
+    let app = test_app();
+    let body = json!({
+        "url": "https://example.com/",
+        "routing": {
+            "devices": [{ "device": "ios", "url": "https://apps.apple.com/app/id1" }],
+            "variants": [
+                { "url": "https://example.com/a", "weight": 1 },
+                { "url": "https://example.com/b", "weight": 1 },
+            ],
+        },
+    });
+    let (status, id) = shorten(&app, body).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let visit = |user_agent: &'static str, cookie: Option<String>| {
+        let mut req = Request::get(format!("/{id}")).header(USER_AGENT, user_agent);
+        if let Some(cookie) = cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        let app = app.clone();
+        async move { app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap() }
+    };
+
+    let res = visit(
+        "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X)",
+        None,
+    )
+    .await;
+    assert_eq!(res.headers()[LOCATION], "https://apps.apple.com/app/id1");
+    assert_eq!(res.headers()[CACHE_CONTROL], "private, no-store");
+    assert!(res.headers().get(SET_COOKIE).is_none());
+
+    // 桌面设备按权重分组，带上 cookie 之后总是看到同一个分组
+    let res = visit("Mozilla/5.0 (X11; Linux x86_64)", None).await;
+    let location = res.headers()[LOCATION].clone();
+    let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+    assert!(cookie.contains(&format!("Path=/{id};")));
+    let cookie = cookie.split(';').next().unwrap().to_string();
+    for _ in 0..5 {
+        let res = visit("Mozilla/5.0 (X11; Linux x86_64)", Some(cookie.clone())).await;
+        assert_eq!(res.headers()[LOCATION], location);
+        assert!(res.headers().get(SET_COOKIE).is_none());
+    }
+
+    // 访问统计中记录命中的规则，访问记录是后台批量写入的
+    let uri = format!("/{id}/stats");
+    let mut rules = Value::Null;
+    for _ in 0..30 {
+        let (_, _, stats) = send_with_token(&app, Method::GET, &uri, Some(ADMIN_KEY), None).await;
+        if stats["total"] == 7 {
+            rules = stats["rules"].clone();
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let variant = if location == "https://example.com/a" {
+        0
+    } else {
+        1
+    };
+    assert_eq!(
+        rules,
+        json!([
+            { "rule": format!("variant:{variant}"), "clicks": 6 },
+            { "rule": "device:ios", "clicks": 1 },
+        ])
+    );
+
+    // 同一种设备只能有一条规则，密码保护的短链接不能使用规则
+    let body = json!({
+        "url": "https://example.com/",
+        "routing": { "devices": [
+            { "device": "android", "url": "https://example.com/1" },
+            { "device": "android", "url": "https://example.com/2" },
+        ] },
+    });
+    let (status, _) = shorten(&app, body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let body = json!({
+        "url": "https://example.com/",
+        "password": "correct horse",
+        "routing": { "variants": [{ "url": "https://example.com/a" }] },
+    });
+    let (status, _) = shorten(&app, body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn reported_links_can_be_disabled_by_admins() {
+    let app = test_app();
+    let (_, id) = shorten(&app, json!({ "url": "https://phish.example.com/login" })).await;
+    let report = json!({ "reason": "phishing", "details": "fake bank login page" });
+    let uri = format!("/{id}/report");
+    let (status, _, _) = send(&app, Method::POST, &uri, Some(report.clone())).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, _, _) = send(&app, Method::POST, "/unknown/report", Some(report)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _, _) = send(&app, Method::POST, &uri, Some(json!({ "reason": "ugly" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 只有管理员可以查看举报和修改审核状态
+    let (status, _, _) = send(&app, Method::GET, "/api/reports", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (_, _, res) =
+        send_with_token(&app, Method::GET, "/api/reports", Some(ADMIN_KEY), None).await;
+    assert_eq!(res["reports"].as_array().unwrap().len(), 1);
+    assert_eq!(res["reports"][0]["link_id"], id.as_str());
+    assert_eq!(res["reports"][0]["reason"], "phishing");
+
+    let uri = format!("/api/moderation/{id}");
+    let body = json!({ "status": "disabled", "reason": "confirmed phishing" });
+    let (status, _, _) = send(&app, Method::POST, &uri, Some(body.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, res) =
+        send_with_token(&app, Method::POST, &uri, Some(ADMIN_KEY), Some(body)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(res["status"], "disabled");
+    let (status, _, problem) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::GONE);
+    assert_eq!(problem["code"], "disabled");
+
+    let body = json!({ "status": "blocked", "reason": "court order" });
+    send_with_token(&app, Method::POST, &uri, Some(ADMIN_KEY), Some(body)).await;
+    let (status, _, problem) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    assert_eq!(problem["code"], "blocked");
+
+    // 处理过的举报默认不再列出，审核记录按时间顺序保留每一次操作
+    let (_, _, res) =
+        send_with_token(&app, Method::GET, "/api/reports", Some(ADMIN_KEY), None).await;
+    assert_eq!(res["reports"], json!([]));
+    let reports = "/api/reports?include_resolved=true";
+    let (_, _, res) = send_with_token(&app, Method::GET, reports, Some(ADMIN_KEY), None).await;
+    assert!(res["reports"][0]["resolved_at"].is_string());
+    let body = json!({ "status": "active", "reason": "false positive" });
+    send_with_token(&app, Method::POST, &uri, Some(ADMIN_KEY), Some(body)).await;
+    let (_, _, res) = send_with_token(&app, Method::GET, &uri, Some(ADMIN_KEY), None).await;
+    let actions: Vec<_> = res["actions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|action| {
+            (
+                action["status"].as_str().unwrap(),
+                action["actor"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        actions,
+        [
+            ("disabled", "admin"),
+            ("blocked", "admin"),
+            ("active", "admin")
+        ]
+    );
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+}
+
+#[tokio::test]
+async fn password_protected_link_redirects_after_unlock() {
+    let app = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org", "password": "correct horse" });
+    let (_, _, res) = send(&app, Method::POST, "/", Some(body)).await;
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    let token = res["token"].as_str().unwrap();
+    let uri = format!("/{id}");
+
+    // 访问时先显示密码表单，不跳转
+    let req = Request::get(&uri).body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let page = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&page).contains(r#"<form method="post">"#));
+
+    let unlock = |password: &'static str| {
+        let req = Request::post(&uri)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("password={password}")))
+            .unwrap();
+        app.clone().oneshot(req)
+    };
+    let res = unlock("battery+staple").await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = unlock("correct+horse").await.unwrap();
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    assert_eq!(res.headers()[LOCATION], "https://www.rust-lang.org/");
+
+    // 所有者也看不到明文地址，并且不能修改目标地址
+    let (_, _, links) = send_with_token(&app, Method::GET, "/api/links", Some(token), None).await;
+    assert_eq!(links["links"][0]["protected"], true);
+    assert_eq!(links["links"][0]["url"], "");
+    let patch = json!({ "url": "https://tokio.rs" });
+    let (status, _, _) = send_with_token(&app, Method::PATCH, &uri, Some(token), Some(patch)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let body = json!({ "url": "https://www.rust-lang.org", "password": "short" });
+    let (status, _) = shorten(&app, body).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn stateless_links_redirect_without_the_database() {
+    let app = test_app_with(Config {
+        stateless_keys: vec![StatelessKey {
+            id: "k1".to_string(),
+            key: [7; 32],
+        }],
+        ..Default::default()
+    });
+    let body = json!({ "url": "https://www.rust-lang.org", "stateless": true });
+    let (status, _, res) =
+        send_with_token(&app, Method::POST, "/", Some(ADMIN_KEY), Some(body.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    // 无状态短链接没有所有者，不返回管理令牌
+    assert!(res.get("token").is_none());
+    let id = res["url"].as_str().unwrap().rsplit('/').next().unwrap();
+    assert!(id.starts_with("k1."));
+    let (status, headers, _) = send(&app, Method::GET, &format!("/{id}"), None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(headers[LOCATION], "https://www.rust-lang.org/");
+    let (status, _, _) = send(&app, Method::GET, &format!("/{id}/qr"), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // 被篡改的 token 和不存在的短链接一样
+    let last = if id.ends_with('A') { 'B' } else { 'A' };
+    let tampered = format!("{}{last}", &id[..id.len() - 1]);
+    let (status, _, _) = send(&app, Method::GET, &format!("/{tampered}"), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // 匿名调用方不能创建，也不能和需要数据库的选项一起使用
+    let (status, _) = shorten(&app, body).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = json!({ "url": "https://tokio.rs", "stateless": true, "max_clicks": 1 });
+    let (status, _, _) =
+        send_with_token(&app, Method::POST, "/", Some(ADMIN_KEY), Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // 没有配置密钥时不能创建
+    let app = test_app();
+    let body = json!({ "url": "https://tokio.rs", "stateless": true });
+    let (status, _, _) =
+        send_with_token(&app, Method::POST, "/", Some(ADMIN_KEY), Some(body)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+// 以管理员身份发送原始的请求体，返回响应的状态码和文本
+async fn send_raw(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    accept: &str,
+    body: Option<(&str, String)>,
+) -> (StatusCode, String) {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(ACCEPT, accept)
+        .header(AUTHORIZATION, format!("Bearer {ADMIN_KEY}"));
+    let body = match body {
+        Some((content_type, body)) => {
+            req = req.header(CONTENT_TYPE, content_type);
+            Body::from(body)
+        }
+        None => Body::empty(),
+    };
+    let res = app.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+    let status = res.status();
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (status, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn links_can_be_exported_and_imported() {
+    let source = test_app();
+    let body = json!({ "url": "https://www.rust-lang.org", "alias": "rust", "tags": ["lang"] });
+    let (_, _, res) = send(&source, Method::POST, "/", Some(body)).await;
+    let token = res["token"].as_str().unwrap().to_string();
+    let body = json!({ "url": "https://tokio.rs", "max_clicks": 3 });
+    send_with_token(&source, Method::POST, "/", Some(&token), Some(body)).await;
+    let body = json!({ "url": "https://docs.rs", "password": "correct horse" });
+    send_with_token(&source, Method::POST, "/", Some(&token), Some(body)).await;
+
+    let (status, ndjson) = send_raw(&source, Method::GET, "/api/export", "*/*", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(ndjson.lines().count(), 3);
+    let (_, csv) = send_raw(&source, Method::GET, "/api/export", "text/csv", None).await;
+    assert!(csv.starts_with("domain,id,url,custom,"));
+    assert!(csv.contains(",rust,https://www.rust-lang.org/,true,"));
+    // 导出需要管理员权限
+    let (status, _, _) = send(&source, Method::GET, "/api/export", None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let target = test_app();
+    let import = |uri: &'static str, content_type: &'static str, body: String| {
+        let target = target.clone();
+        async move {
+            let (status, report) = send_raw(
+                &target,
+                Method::POST,
+                uri,
+                "*/*",
+                Some((content_type, body)),
+            )
+            .await;
+            assert_eq!(status, StatusCode::OK);
+            serde_json::from_str::<Value>(&report).unwrap()
+        }
+    };
+    let ndjson_type = "application/x-ndjson";
+    let report = import("/api/import?dry_run=true", ndjson_type, ndjson.clone()).await;
+    assert_eq!(report["dry_run"], true);
+    assert_eq!(report["created"], 3);
+    let (status, _, _) = send(&target, Method::GET, "/rust", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let report = import("/api/import", ndjson_type, ndjson.clone()).await;
+    assert_eq!(report["created"], 3);
+    // 导入是幂等的，CSV 和 NDJSON 的内容相同
+    let report = import("/api/import", "text/csv", csv).await;
+    assert_eq!(report["created"], 0);
+    assert_eq!(report["unchanged"], 3);
+
+    // 短链接和管理令牌在新环境中仍然有效
+    let (status, headers, _) = send(&target, Method::GET, "/rust", None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(headers[LOCATION], "https://www.rust-lang.org/");
+    let (_, _, links) =
+        send_with_token(&target, Method::GET, "/api/links", Some(&token), None).await;
+    assert_eq!(links["links"].as_array().unwrap().len(), 3);
+    assert!(links["links"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|link| link["remaining_clicks"] == 3 && link["url"] == "https://tokio.rs/"));
+
+    // 被其他短链接占用的 id 和不合法的行会列出来，不影响其他行
+    let body = [
+        r#"{"id":"rust","url":"https://crates.io"}"#,
+        r#"{"id":"api","url":"https://crates.io"}"#,
+        "not json",
+        r#"{"id":"crates","url":"https://crates.io"}"#,
+    ]
+    .join("\n");
+    let report = import("/api/import", ndjson_type, body).await;
+    assert_eq!(report["created"], 1);
+    assert_eq!(report["conflicts"][0]["index"], 0);
+    assert_eq!(report["conflicts"][0]["id"], "rust");
+    let invalid: Vec<_> = report["invalid"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|row| row["index"].as_u64().unwrap())
+        .collect();
+    assert_eq!(invalid, [1, 2]);
+}
+
+#[tokio::test]
+async fn grpc_and_http_share_the_same_port_and_state() {
+    use crate::grpc::pb::{self, shortener_client::ShortenerClient};
+    use tonic::{Code, Request as GrpcRequest};
+
+    // gRPC 需要 HTTP/2，这里和 main 一样启动真正的服务，由生成的客户端通过 TCP 连接
+    let app = test_app();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let service = app
+        .clone()
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let server = axum::serve(listener, service);
+    tokio::spawn(async move { server.await.unwrap() });
+    let mut client = ShortenerClient::connect(format!("http://{addr}"))
+        .await
+        .unwrap();
+
+    let res = client
+        .shorten(pb::ShortenRequest {
+            url: "https://www.rust-lang.org".to_string(),
+            alias: Some("grpc".to_string()),
+            redirect_type: Some(302),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(res.url.ends_with("/grpc"));
+    let token = res.token.unwrap();
+
+    // 通过 gRPC 创建的短链接可以通过 HTTP 访问，反之亦然
+    let (status, headers, _) = send(&app, Method::GET, "/grpc", None).await;
+    assert_eq!(status, StatusCode::FOUND);
+    assert_eq!(headers[LOCATION], "https://www.rust-lang.org/");
+    let (_, id) = shorten(&app, json!({ "url": "https://crates.io" })).await;
+    let resolve = |id: &str| pb::ResolveRequest {
+        id: id.to_string(),
+        domain: None,
+    };
+    let res = client.resolve(resolve(&id)).await.unwrap().into_inner();
+    assert_eq!(res.url.as_deref(), Some("https://crates.io/"));
+
+    // 查询目标地址不计入访问次数，只有上面的 HTTP 跳转被统计
+    client.resolve(resolve("grpc")).await.unwrap();
+    let stats = pb::StatsRequest {
+        id: "grpc".to_string(),
+        ..Default::default()
+    };
+    // 访问统计和 HTTP 接口一样只对所有者开放
+    let status = client.stats(stats.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    let mut total = 0;
+    for _ in 0..30 {
+        let mut req = GrpcRequest::new(stats.clone());
+        req.metadata_mut()
+            .insert("x-management-token", token.parse().unwrap());
+        total = client.stats(req).await.unwrap().into_inner().total;
+        if total > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(total, 1);
+
+    // 跳转规则和 HTTP 接口的 routing 字段相同，设备规则必须指定设备
+    let routing = pb::Routing {
+        devices: vec![pb::DeviceRule {
+            device: pb::Device::Ios.into(),
+            url: "https://apps.apple.com/app/id1".to_string(),
+        }],
+        variants: vec![pb::Variant {
+            url: "https://www.rust-lang.org/learn".to_string(),
+            weight: None,
+        }],
+    };
+    let res = client
+        .shorten(pb::ShortenRequest {
+            url: "https://www.rust-lang.org".to_string(),
+            alias: Some("grpc-routing".to_string()),
+            routing: Some(routing.clone()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(res.url.ends_with("/grpc-routing"));
+    let (_, headers, _) = send(&app, Method::GET, "/grpc-routing", None).await;
+    assert_eq!(headers[LOCATION], "https://www.rust-lang.org/learn");
+    let mut routing = routing;
+    routing.devices[0].device = pb::Device::Unspecified.into();
+    let status = client
+        .shorten(pb::ShortenRequest {
+            url: "https://www.rust-lang.org".to_string(),
+            routing: Some(routing),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    // 错误和 HTTP 接口一样带有稳定的错误码
+    let delete = pb::DeleteRequest {
+        id: "grpc".to_string(),
+        domain: None,
+    };
+    let status = client.delete(delete.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    assert_eq!(
+        status.metadata().get("x-error-code").unwrap(),
+        "unauthorized"
+    );
+    let mut req = GrpcRequest::new(delete);
+    req.metadata_mut()
+        .insert("x-management-token", token.parse().unwrap());
+    client.delete(req).await.unwrap();
+    let status = client.resolve(resolve("grpc")).await.unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    assert_eq!(status.metadata().get("x-error-code").unwrap(), "not_found");
+}
+
+// 模拟浏览器访问网页：表单以 urlencoded 的形式提交，管理令牌保存在 cookie 中
+async fn browse(
+    app: &Router,
+    method: Method,
+    uri: &str,
+    headers: &[(&str, &str)],
+    form: Option<&str>,
+) -> (StatusCode, http::HeaderMap, String) {
+    let mut req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(HOST, "localhost:9876");
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let body = match form {
+        Some(form) => {
+            req = req.header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+            Body::from(form.to_string())
+        }
+        None => Body::empty(),
+    };
+    let res = app.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+    let status = res.status();
+    let headers = res.headers().clone();
+    let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (status, headers, String::from_utf8(bytes.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn web_ui_shortens_and_lists_links_without_javascript() {
+    let app = test_app();
+    let (status, _, html) = browse(&app, Method::GET, "/", &[], None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(html.contains(r#"<form method="post" action="/ui/shorten">"#));
+
+    // 出错时重新显示表单，保留已经填写的内容
+    let form = "url=ftp%3A%2F%2Fexample.com&alias=web";
+    let (status, _, html) = browse(&app, Method::POST, "/ui/shorten", &[], Some(form)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(html.contains(r#"value="ftp://example.com""#));
+    assert!(html.contains(r#"class="error""#));
+
+    let form =
+        "url=https%3A%2F%2Fwww.rust-lang.org%2F%3Fa%3D1%26b%3D%3Cb%3E&alias=web&expires_in=1d";
+    let (status, headers, html) = browse(&app, Method::POST, "/ui/shorten", &[], Some(form)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert!(html.contains("/web"));
+    assert!(html.contains(r#"src="/web/qr?format=svg""#));
+    // 显示保存的规范化之后的目标地址，模板会转义插入的内容
+    assert!(html.contains(r#"href="https://www.rust-lang.org/?a=1&#38;b=%3Cb%3E""#));
+    let cookie = headers[SET_COOKIE].to_str().unwrap();
+    assert!(cookie.contains("HttpOnly"));
+    let cookie = cookie.split(';').next().unwrap().to_string();
+    let (status, _, _) = send(&app, Method::GET, "/web", None).await;
+    assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+
+    // 同一个浏览器之后创建的短链接属于同一个管理令牌
+    let form = "url=HTTPS%3A%2F%2FCrates.IO";
+    let browser = [("cookie", cookie.as_str())];
+    let (_, headers, html) = browse(&app, Method::POST, "/ui/shorten", &browser, Some(form)).await;
+    assert!(headers.get(SET_COOKIE).is_none());
+    assert!(html.contains(r#"<a href="https://crates.io/""#));
+    let (status, _, html) = browse(&app, Method::GET, "/ui/links", &browser, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(html.contains("https://crates.io/"));
+    assert!(html.contains("/web</a>"));
+
+    let (_, _, html) = browse(&app, Method::GET, "/ui/links", &[], None).await;
+    assert!(html.contains(r#"action="/ui/token""#));
+    let token = cookie.split_once('=').unwrap().1;
+    let form = format!("token={token}");
+    let (status, headers, _) = browse(&app, Method::POST, "/ui/token", &[], Some(&form)).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+    assert!(headers[SET_COOKIE].to_str().unwrap().starts_with(&cookie));
+
+    // 拒绝其他网站提交的表单
+    let cross_site = [("sec-fetch-site", "cross-site")];
+    let form = "url=https%3A%2F%2Fevil.example";
+    let (status, _, _) = browse(&app, Method::POST, "/ui/shorten", &cross_site, Some(form)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let origin = [("origin", "https://evil.example")];
+    let (status, _, _) = browse(&app, Method::POST, "/ui/token", &origin, Some("token=")).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let origin = [("origin", "http://localhost:9876")];
+    let (status, _, _) = browse(&app, Method::POST, "/ui/token", &origin, Some("token=")).await;
+    assert_eq!(status, StatusCode::SEE_OTHER);
+}
+
+#[tokio::test]
+async fn admin_cli_manages_links_through_the_app_state() {
+    use crate::admin::{self, AdminCli};
+    use clap::Parser;
+
+    let config = Config {
+        database_url: "memory://".to_string(),
+        ..Config::default()
+    };
+    let state = AppState::new(config, Arc::new(MemoryStore::default()));
+    let run = |args: &[&str]| {
+        let cli = AdminCli::parse_from(["shortener admin"].iter().chain(args));
+        admin::execute(&state, cli)
+    };
+
+    let out = run(&[
+        "create",
+        "https://www.rust-lang.org",
+        "--alias",
+        "cli",
+        "--tag",
+        "ops",
+    ])
+    .await
+    .unwrap();
+    assert!(out.lines().next().unwrap().ends_with("/cli"));
+    assert!(out.contains("management token: "));
+    run(&["create", "https://crates.io"]).await.unwrap();
+
+    let out = run(&["list"]).await.unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("SHORT URL"));
+    let out = run(&["list", "--json", "--limit", "1"]).await.unwrap();
+    let links: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(links.as_array().unwrap().len(), 1);
+
+    let out = run(&["show", "cli"]).await.unwrap();
+    assert!(out.contains("https://www.rust-lang.org/"));
+    assert!(out.contains("ops"));
+
+    let out = run(&["disable", "cli", "--reason", "phishing"])
+        .await
+        .unwrap();
+    assert_eq!(out, "cli is now disabled\n");
+    let out = run(&["list", "--status", "disabled", "--json"])
+        .await
+        .unwrap();
+    let links: Value = serde_json::from_str(&out).unwrap();
+    assert_eq!(links[0]["id"], "cli");
+    assert_eq!(links[0]["status"], "disabled");
+
+    let out = run(&["stats", "cli", "--bucket", "hour"]).await.unwrap();
+    assert!(out.starts_with("total clicks: 0\n"));
+    let out = run(&["purge-expired", "--json"]).await.unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&out).unwrap()["purged"], 0);
+
+    assert_eq!(run(&["delete", "cli"]).await.unwrap(), "deleted cli\n");
+    assert!(run(&["show", "cli"]).await.is_err());
+    assert!(run(&["delete", "cli", "--domain", "unknown.example"])
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn webhooks_are_signed_retried_and_replayed_from_dead_letters() {
+    use crate::webhooks::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+    use axum::{extract::State, routing::post};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+
+    // 本地的接收方，记录收到的请求；failing 为 true 时返回 500
+    #[derive(Clone, Default)]
+    struct Receiver {
+        failing: Arc<AtomicBool>,
+        received: Arc<Mutex<Vec<(http::HeaderMap, String)>>>,
+    }
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: http::HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        receiver.received.lock().unwrap().push((headers, body));
+        if receiver.failing.load(Ordering::SeqCst) {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+    let receiver = Receiver::default();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook_url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let server = axum::serve(
+        listener,
+        Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver.clone()),
+    );
+    tokio::spawn(async move { server.await.unwrap() });
+    // 等待接收方收到 event 事件，返回请求体
+    let wait_for = |event: &'static str| {
+        let received = receiver.received.clone();
+        async move {
+            for _ in 0..50 {
+                let found = received.lock().unwrap().iter().rev().find_map(|(_, body)| {
+                    let body: Value = serde_json::from_str(body).unwrap();
+                    (body["event"] == event).then_some(body)
+                });
+                if let Some(body) = found {
+                    return body;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            panic!("{event} was not delivered");
+        }
+    };
+
+    let config = Config {
+        database_url: "memory://".to_string(),
+        admin_key: Some(ADMIN_KEY.to_string()),
+        webhook_max_attempts: 2,
+        webhook_retry_base: Duration::from_millis(10),
+        ..Config::default()
+    };
+    let state = AppState::new(config, Arc::new(MemoryStore::default()));
+    tokio::spawn(state.webhooks.clone().run());
+    let app = app(state).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 10000))));
+
+    // 只有管理员可以订阅，订阅 link.clicks 时必须设置阈值
+    let body =
+        json!({ "url": hook_url, "events": ["link.created", "link.deleted", "link.clicks"] });
+    let (status, _, _) = send(&app, Method::POST, "/api/webhooks", Some(body.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _, _) = send_with_token(
+        &app,
+        Method::POST,
+        "/api/webhooks",
+        Some(ADMIN_KEY),
+        Some(body.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let mut body = body;
+    body["click_thresholds"] = json!([2]);
+    let (status, _, webhook) = send_with_token(
+        &app,
+        Method::POST,
+        "/api/webhooks",
+        Some(ADMIN_KEY),
+        Some(body),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (hook_id, secret) = (
+        webhook["id"].as_str().unwrap(),
+        webhook["secret"].as_str().unwrap(),
+    );
+    let (_, _, list) =
+        send_with_token(&app, Method::GET, "/api/webhooks", Some(ADMIN_KEY), None).await;
+    assert_eq!(list[0]["id"], hook_id);
+    assert!(list[0].get("secret").is_none());
+
+    // 投递带有可以用密钥验证的签名
+    let (_, _, created) = send(
+        &app,
+        Method::POST,
+        "/",
+        Some(json!({ "url": "https://www.rust-lang.org", "alias": "hooked" })),
+    )
+    .await;
+    let event = wait_for("link.created").await;
+    assert_eq!(event["data"]["id"], "hooked");
+    assert_eq!(event["data"]["url"], "https://www.rust-lang.org/");
+    {
+        let received = receiver.received.lock().unwrap();
+        let (headers, body) = &received[0];
+        let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+        assert_eq!(
+            headers[SIGNATURE_HEADER],
+            webhooks::sign(secret, timestamp, body)
+        );
+        assert_ne!(
+            headers[SIGNATURE_HEADER],
+            webhooks::sign("wrong", timestamp, body)
+        );
+    }
+
+    // 访问次数达到阈值时通知一次
+    for _ in 0..3 {
+        send(&app, Method::GET, "/hooked", None).await;
+    }
+    let event = wait_for("link.clicks").await;
+    assert_eq!(event["data"]["threshold"], 2);
+
+    // 接收方一直失败，次数用完之后进入死信表
+    receiver.failing.store(true, Ordering::SeqCst);
+    let token = created["token"].as_str().unwrap();
+    let (status, _, _) = send_with_token(&app, Method::DELETE, "/hooked", Some(token), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let dead_letters = format!("/api/webhooks/{hook_id}/dead-letters");
+    let mut letters = Value::Null;
+    for _ in 0..50 {
+        (_, _, letters) =
+            send_with_token(&app, Method::GET, &dead_letters, Some(ADMIN_KEY), None).await;
+        if letters.as_array().is_some_and(|l| !l.is_empty()) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(letters[0]["event"], "link.deleted");
+    assert_eq!(letters[0]["attempts"], 2);
+    assert_eq!(letters[0]["payload"]["data"]["id"], "hooked");
+    let attempts = |received: &[(http::HeaderMap, String)]| {
+        received
+            .iter()
+            .filter(|(headers, _)| headers["x-shortener-event"] == "link.deleted")
+            .count()
+    };
+    assert_eq!(attempts(&receiver.received.lock().unwrap()), 2);
+
+    // 接收方恢复之后重放死信
+    receiver.failing.store(false, Ordering::SeqCst);
+    let replay = format!("/api/webhooks/{hook_id}/replay");
+    let (status, _, res) =
+        send_with_token(&app, Method::POST, &replay, Some(ADMIN_KEY), None).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(res["replayed"], 1);
+    for _ in 0..50 {
+        if attempts(&receiver.received.lock().unwrap()) == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(attempts(&receiver.received.lock().unwrap()), 3);
+    let (_, _, letters) =
+        send_with_token(&app, Method::GET, &dead_letters, Some(ADMIN_KEY), None).await;
+    assert_eq!(letters, json!([]));
+}
+
+#[test]
+fn ip_hashes_depend_on_the_secret() {
+    let ip = "203.0.113.7".parse().unwrap();
+    let hasher = IpHasher::new("a-secret-of-at-least-32-characters", "clicks");
+    assert_eq!(hasher.hash(ip), hasher.hash(ip));
+    // 不知道密钥就无法用预先计算的表还原 IP
+    let other = IpHasher::new("another-secret-of-32-characters!", "clicks");
+    assert_ne!(hasher.hash(ip), other.hash(ip));
+    assert_ne!(
+        hasher.hash(ip),
+        blake3::hash(b"203.0.113.7").to_hex().to_string()
+    );
+}
+
+#[test]
+fn secrets_must_be_configured() {
+    let secret = "a-secret-of-at-least-32-characters";
+    let config = Config {
+        ip_hash_secret: secret.to_string(),
+        ..Config::default()
+    };
+    assert!(config.check_secrets().is_ok());
+    assert!(Config::default().check_secrets().is_err());
+    // obfuscated 策略没有默认密钥，太短的密钥同样拒绝
+    let obfuscated = Config {
+        id_strategy: IdStrategy::Obfuscated,
+        ..config.clone()
+    };
+    assert!(obfuscated.check_secrets().is_err());
+    let weak = Config {
+        id_secret: "change-me".to_string(),
+        ..obfuscated.clone()
+    };
+    assert!(weak.check_secrets().is_err());
+    let strong = Config {
+        id_secret: secret.to_string(),
+        ..obfuscated
+    };
+    assert!(strong.check_secrets().is_ok());
+}
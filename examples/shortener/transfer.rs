@@ -0,0 +1,442 @@
+use crate::{
+    bulk::{self, non_empty},
+    error::ShortenerError,
+    state::{AppState, ImportOutcome},
+    store::{LinkStatus, RedirectType, Routing, UrlRecord, Utm},
+};
+use anyhow::anyhow;
+use axum::body::Body;
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use http::{header::CONTENT_TYPE, HeaderMap};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+
+// 导出时每次从数据库读取的短链接数量
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+// 在不同环境之间迁移短链接：导出的每一行就是一个完整的 UrlRecord，
+// 导入时原样保存，包括 id、所有者（管理令牌的哈希）、创建时间和剩余访问次数，
+// 所以迁移之后原来的短链接地址和管理令牌都仍然有效。访问记录不会被导出
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferFormat {
+    Ndjson,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    // 不传时根据 Accept 请求头选择
+    #[serde(default)]
+    pub format: Option<TransferFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    // 只检查，不保存
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// NDJSON 中的一行
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedLink {
+    #[serde(default)]
+    domain: String,
+    id: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    custom: bool,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    remaining_clicks: Option<i64>,
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    interstitial: bool,
+    #[serde(default)]
+    sealed_url: Option<String>,
+    #[serde(default)]
+    passthrough: bool,
+    #[serde(default)]
+    utm: Utm,
+    #[serde(default)]
+    routing: Routing,
+    #[serde(default)]
+    status: LinkStatus,
+}
+
+// CSV 中的一行，列和 ExportedLink 相同，多个标签用分号分隔，UTM 参数各占一列，
+// 跳转规则以 JSON 的形式放在一列中，没有规则时留空
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvLink {
+    #[serde(default)]
+    domain: String,
+    id: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    custom: bool,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    not_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    remaining_clicks: Option<i64>,
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    interstitial: bool,
+    #[serde(default)]
+    sealed_url: Option<String>,
+    #[serde(default)]
+    passthrough: bool,
+    #[serde(default)]
+    utm_source: Option<String>,
+    #[serde(default)]
+    utm_medium: Option<String>,
+    #[serde(default)]
+    utm_campaign: Option<String>,
+    #[serde(default)]
+    utm_term: Option<String>,
+    #[serde(default)]
+    utm_content: Option<String>,
+    #[serde(default)]
+    routing: String,
+    #[serde(default)]
+    status: LinkStatus,
+}
+
+// 导入的结果，冲突和不合法的行都会列出来，index 是这一行在请求中的序号（从 0 开始）
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    dry_run: bool,
+    created: u64,
+    unchanged: u64,
+    conflicts: Vec<RowError>,
+    invalid: Vec<RowError>,
+}
+
+#[derive(Debug, Serialize)]
+struct RowError {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    detail: String,
+}
+
+// 导出的分页位置
+enum Cursor {
+    Start,
+    After(String, String),
+    Done,
+}
+
+impl TransferFormat {
+    // 只有 Accept 中明确出现 text/csv 时才导出 CSV
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let accepts_csv = accept
+            .unwrap_or_default()
+            .split(',')
+            .any(|item| item.split(';').next().unwrap_or_default().trim() == "text/csv");
+        if accepts_csv {
+            Self::Csv
+        } else {
+            Self::Ndjson
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    pub fn content_disposition(self) -> &'static str {
+        match self {
+            Self::Ndjson => r#"attachment; filename="links.ndjson""#,
+            Self::Csv => r#"attachment; filename="links.csv""#,
+        }
+    }
+
+    // 把一页短链接编码成响应体的一部分，CSV 只在第一页写表头
+    fn encode(self, records: Vec<UrlRecord>, first_page: bool) -> Result<String, ShortenerError> {
+        match self {
+            Self::Ndjson => {
+                let mut out = String::new();
+                for record in records {
+                    out.push_str(&serde_json::to_string(&ExportedLink::from(record)).map_err(
+                        |e| ShortenerError::Internal(anyhow!("failed to encode link: {e}")),
+                    )?);
+                    out.push('\n');
+                }
+                Ok(out)
+            }
+            Self::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(first_page)
+                    .from_writer(Vec::new());
+                for record in records {
+                    writer.serialize(CsvLink::from(record)).map_err(|e| {
+                        ShortenerError::Internal(anyhow!("failed to encode link: {e}"))
+                    })?;
+                }
+                let bytes = writer.into_inner().map_err(|e| {
+                    ShortenerError::Internal(anyhow!("failed to encode links: {e}"))
+                })?;
+                Ok(String::from_utf8_lossy(&bytes).into_owned())
+            }
+        }
+    }
+}
+
+// 按主键顺序逐页读取所有短链接，边读边返回，不需要把整个数据库放进内存。
+// 中途读取失败时响应会被中断，客户端可以据此判断导出不完整
+pub fn export(
+    state: AppState,
+    format: TransferFormat,
+) -> impl Stream<Item = Result<String, ShortenerError>> {
+    stream::try_unfold(Cursor::Start, move |cursor| {
+        let state = state.clone();
+        async move {
+            let after = match &cursor {
+                Cursor::Start => None,
+                Cursor::After(domain, id) => Some((domain.as_str(), id.as_str())),
+                Cursor::Done => return Ok(None),
+            };
+            let records = state.store.export(after, EXPORT_PAGE_SIZE).await?;
+            let next = match records.last() {
+                Some(last) if records.len() as i64 == EXPORT_PAGE_SIZE => {
+                    Cursor::After(last.domain.clone(), last.id.clone())
+                }
+                _ => Cursor::Done,
+            };
+            let chunk = format.encode(records, matches!(cursor, Cursor::Start))?;
+            Ok(Some((chunk, next)))
+        }
+    })
+}
+
+// 根据 Content-Type 解析导入的请求体：NDJSON 按行流式解析，CSV 完整读取之后再解析
+pub async fn read_links(
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<BoxStream<'static, Result<UrlRecord, ShortenerError>>, ShortenerError> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    match mime {
+        "application/x-ndjson" => {
+            let links = bulk::ndjson_lines(body).map(|line| {
+                line.and_then(|line| bulk::parse_json_row::<ExportedLink>(&line))
+                    .map(UrlRecord::from)
+            });
+            Ok(links.boxed())
+        }
+        "text/csv" => {
+            let body = bulk::read_body(body).await?;
+            let links: Vec<_> = csv::Reader::from_reader(&body[..])
+                .deserialize::<CsvLink>()
+                .map(|row| row.map_err(bulk::invalid_row).and_then(UrlRecord::try_from))
+                .collect();
+            Ok(stream::iter(links).boxed())
+        }
+        _ => Err(ShortenerError::Validation(format!(
+            "unsupported content type {content_type:?}, expected application/x-ndjson or text/csv"
+        ))),
+    }
+}
+
+// 逐行导入。某一行不合法或者冲突不影响其他行；存储后端出错时整个请求失败，
+// 已经导入的短链接会保留下来，导入是幂等的，重新导入同一份文件即可
+pub async fn import(
+    state: &AppState,
+    links: BoxStream<'static, Result<UrlRecord, ShortenerError>>,
+    dry_run: bool,
+) -> Result<ImportReport, ShortenerError> {
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut links = links.enumerate();
+    while let Some((index, link)) = links.next().await {
+        let row_error = |domain: Option<String>, id: Option<String>, detail: String| RowError {
+            index,
+            domain,
+            id,
+            detail,
+        };
+        let record = match link {
+            Ok(record) => record,
+            Err(e) => {
+                report.invalid.push(row_error(None, None, e.to_string()));
+                continue;
+            }
+        };
+        let (domain, id) = (record.domain.clone(), record.id.clone());
+        match state.import_link(record, dry_run).await {
+            Ok(ImportOutcome::Created) => report.created += 1,
+            Ok(ImportOutcome::Unchanged) => report.unchanged += 1,
+            Ok(ImportOutcome::Conflict(detail)) => {
+                report
+                    .conflicts
+                    .push(row_error(Some(domain), Some(id), detail));
+            }
+            Err(ShortenerError::Validation(detail)) => {
+                report
+                    .invalid
+                    .push(row_error(Some(domain), Some(id), detail));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(report)
+}
+
+impl From<UrlRecord> for ExportedLink {
+    fn from(record: UrlRecord) -> Self {
+        Self {
+            domain: record.domain,
+            id: record.id,
+            url: record.url,
+            custom: record.custom,
+            expires_at: record.expires_at,
+            not_before: record.not_before,
+            remaining_clicks: record.remaining_clicks,
+            owner: record.owner,
+            redirect_type: record.redirect_type,
+            tags: record.tags.0,
+            created_at: record.created_at,
+            interstitial: record.interstitial,
+            sealed_url: record.sealed_url,
+            passthrough: record.passthrough,
+            utm: record.utm.0,
+            routing: record.routing.0,
+            status: record.status,
+        }
+    }
+}
+
+impl From<ExportedLink> for UrlRecord {
+    fn from(link: ExportedLink) -> Self {
+        Self {
+            domain: link.domain,
+            id: link.id,
+            url: link.url,
+            custom: link.custom,
+            expires_at: link.expires_at,
+            not_before: link.not_before,
+            remaining_clicks: link.remaining_clicks,
+            owner: link.owner,
+            redirect_type: link.redirect_type,
+            tags: Json(link.tags),
+            created_at: link.created_at,
+            interstitial: link.interstitial,
+            sealed_url: link.sealed_url,
+            passthrough: link.passthrough,
+            utm: Json(link.utm),
+            routing: Json(link.routing),
+            status: link.status,
+        }
+    }
+}
+
+impl From<UrlRecord> for CsvLink {
+    fn from(record: UrlRecord) -> Self {
+        Self {
+            domain: record.domain,
+            id: record.id,
+            url: record.url,
+            custom: record.custom,
+            expires_at: record.expires_at,
+            not_before: record.not_before,
+            remaining_clicks: record.remaining_clicks,
+            owner: record.owner,
+            redirect_type: record.redirect_type,
+            tags: record.tags.0.join(";"),
+            created_at: record.created_at,
+            interstitial: record.interstitial,
+            sealed_url: record.sealed_url,
+            passthrough: record.passthrough,
+            utm_source: record.utm.0.source,
+            utm_medium: record.utm.0.medium,
+            utm_campaign: record.utm.0.campaign,
+            utm_term: record.utm.0.term,
+            utm_content: record.utm.0.content,
+            routing: if record.routing.is_empty() {
+                String::new()
+            } else {
+                serde_json::to_string(&record.routing.0).unwrap_or_default()
+            },
+            status: record.status,
+        }
+    }
+}
+
+impl TryFrom<CsvLink> for UrlRecord {
+    type Error = ShortenerError;
+
+    fn try_from(link: CsvLink) -> Result<Self, Self::Error> {
+        let routing = match link.routing.trim() {
+            "" => Routing::default(),
+            routing => serde_json::from_str(routing).map_err(bulk::invalid_row)?,
+        };
+        let tags = link
+            .tags
+            .split(';')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(Self {
+            domain: link.domain,
+            id: link.id,
+            url: link.url,
+            custom: link.custom,
+            expires_at: link.expires_at,
+            not_before: link.not_before,
+            remaining_clicks: link.remaining_clicks,
+            owner: link.owner,
+            redirect_type: link.redirect_type,
+            tags: Json(tags),
+            created_at: link.created_at,
+            interstitial: link.interstitial,
+            sealed_url: non_empty(link.sealed_url),
+            passthrough: link.passthrough,
+            utm: Json(Utm {
+                source: non_empty(link.utm_source),
+                medium: non_empty(link.utm_medium),
+                campaign: non_empty(link.utm_campaign),
+                term: non_empty(link.utm_term),
+                content: non_empty(link.utm_content),
+            }),
+            routing: Json(routing),
+            status: link.status,
+        })
+    }
+}
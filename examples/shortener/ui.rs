@@ -0,0 +1,353 @@
+use crate::{
+    auth,
+    error::ShortenerError,
+    handlers::{self, ShortenReq},
+    state::{self, AppState},
+    store::{LinkFilter, LinkStatus, UrlRecord},
+};
+use anyhow::anyhow;
+use askama::Template;
+use axum::{
+    extract::{
+        rejection::{FormRejection, QueryRejection},
+        ConnectInfo, Host, Query, State,
+    },
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use chrono::{DateTime, Duration, Utc};
+use http::{
+    header::{ORIGIN, SET_COOKIE},
+    HeaderMap, HeaderValue, StatusCode,
+};
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+// 网页创建的短链接使用保存在 cookie 中的管理令牌，同一个浏览器之后创建的短链接属于同一个所有者
+const TOKEN_COOKIE: &str = "shortener_token";
+const TOKEN_COOKIE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+// 列表页每页显示的短链接数量
+const PAGE_SIZE: i64 = 20;
+
+// 面向不熟悉 API 的用户的网页，用 askama 在编译时检查模板，不依赖 JavaScript
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexPage {
+    form: ShortenForm,
+    error: Option<String>,
+    expiry_options: [Expiry; 5],
+}
+
+#[derive(Template)]
+#[template(path = "result.html")]
+struct ResultPage {
+    short_url: String,
+    url: String,
+    qr_url: String,
+    // 新生成的管理令牌，只在这里显示一次
+    token: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "links.html")]
+struct LinksPage {
+    signed_in: bool,
+    links: Vec<LinkRow>,
+    error: Option<String>,
+    prev_offset: Option<i64>,
+    next_offset: Option<i64>,
+}
+
+struct LinkRow {
+    short_url: String,
+    // 受密码保护的短链接没有明文地址
+    url: Option<String>,
+    created_at: String,
+    expires_at: String,
+    status: LinkStatus,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ShortenForm {
+    url: String,
+    // 表单中没有填写的字段是空字符串
+    #[serde(default)]
+    alias: String,
+    #[serde(default)]
+    expires_in: Expiry,
+}
+
+// 表单中只提供几个常用的有效期，需要精确的过期时间时使用 API
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Expiry {
+    #[default]
+    #[serde(rename = "")]
+    Never,
+    #[serde(rename = "1h")]
+    Hour,
+    #[serde(rename = "1d")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenForm {
+    // 空字符串表示忘记当前浏览器中保存的管理令牌
+    #[serde(default)]
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    #[serde(default)]
+    offset: i64,
+}
+
+// GET / 显示创建短链接的表单
+pub async fn index() -> Response {
+    render(
+        StatusCode::OK,
+        &IndexPage::new(ShortenForm::default(), None),
+    )
+}
+
+// 提交表单创建短链接，校验规则和限流都和 POST / 相同，出错时重新显示表单和错误信息
+pub async fn shorten(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    form: Result<Form<ShortenForm>, FormRejection>,
+) -> Response {
+    let form = match form {
+        Ok(Form(form)) => form,
+        Err(e) => return form_error(ShortenForm::default(), e.into()),
+    };
+    if !is_same_origin(&headers, host.as_ref()) {
+        return form_error(form, cross_site());
+    }
+    let domain = handlers::request_domain(&state, host);
+    let token = auth::cookie(&headers, TOKEN_COOKIE);
+    let data = form.to_request();
+    let shortened = match handlers::create_link(&state, None, token, addr, domain, &data).await {
+        Ok(shortened) => shortened,
+        Err(e) => return form_error(form, e),
+    };
+    let id = shortened.url.rsplit('/').next().unwrap_or_default();
+    let page = ResultPage {
+        qr_url: format!("/{id}/qr?format=svg"),
+        short_url: shortened.url.clone(),
+        url: shortened.destination.clone(),
+        token: shortened.token.clone(),
+    };
+    let mut res = render(StatusCode::CREATED, &page);
+    if let Some(token) = &shortened.token {
+        if let Some(cookie) = token_cookie(&state, token, TOKEN_COOKIE_MAX_AGE) {
+            res.headers_mut().insert(SET_COOKIE, cookie);
+        }
+    }
+    res
+}
+
+// GET /ui/links 列出当前浏览器中的管理令牌对应的短链接，按创建时间倒序分页
+pub async fn links(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> Response {
+    let offset = query.map_or(0, |Query(query)| query.offset.max(0));
+    let Some(token) = auth::cookie(&headers, TOKEN_COOKIE) else {
+        return render(StatusCode::OK, &LinksPage::signed_out(None));
+    };
+    let filter = LinkFilter {
+        tag: None,
+        q: None,
+        limit: PAGE_SIZE,
+        offset,
+    };
+    let records = match state.list(token, filter).await {
+        Ok(records) => records,
+        Err(e) => {
+            e.log();
+            return render(e.status(), &LinksPage::signed_out(Some(e.to_string())));
+        }
+    };
+    let page = LinksPage {
+        signed_in: true,
+        next_offset: (records.len() as i64 == PAGE_SIZE).then_some(offset + PAGE_SIZE),
+        prev_offset: (offset > 0).then(|| (offset - PAGE_SIZE).max(0)),
+        links: records
+            .into_iter()
+            .map(|record| LinkRow::new(&state, record))
+            .collect(),
+        error: None,
+    };
+    render(StatusCode::OK, &page)
+}
+
+// 在列表页输入已有的管理令牌，保存到 cookie 之后就可以看到用这个令牌创建的短链接
+pub async fn set_token(
+    State(state): State<AppState>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    form: Result<Form<TokenForm>, FormRejection>,
+) -> Response {
+    let token = match form {
+        Ok(Form(form)) => form.token,
+        Err(e) => return links_error(e.into()),
+    };
+    if !is_same_origin(&headers, host.as_ref()) {
+        return links_error(cross_site());
+    }
+    let token = token.trim();
+    let cookie = if token.is_empty() {
+        token_cookie(&state, "", 0)
+    } else {
+        match state::validate_token(token) {
+            Ok(token) => token_cookie(&state, token, TOKEN_COOKIE_MAX_AGE),
+            Err(e) => return links_error(e),
+        }
+    };
+    let mut res = Redirect::to("/ui/links").into_response();
+    if let Some(cookie) = cookie {
+        res.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    res
+}
+
+impl IndexPage {
+    fn new(form: ShortenForm, error: Option<String>) -> Self {
+        Self {
+            form,
+            error,
+            expiry_options: Expiry::ALL,
+        }
+    }
+}
+
+impl LinksPage {
+    fn signed_out(error: Option<String>) -> Self {
+        Self {
+            signed_in: false,
+            links: Vec::new(),
+            error,
+            prev_offset: None,
+            next_offset: None,
+        }
+    }
+}
+
+impl LinkRow {
+    fn new(state: &AppState, record: UrlRecord) -> Self {
+        Self {
+            short_url: state.domains.short_url(&record.domain, &record.id),
+            url: record.sealed_url.is_none().then_some(record.url),
+            created_at: record.created_at.map(format_time).unwrap_or_default(),
+            expires_at: record
+                .expires_at
+                .map_or_else(|| "never".to_string(), format_time),
+            status: record.status,
+        }
+    }
+}
+
+impl ShortenForm {
+    fn to_request(&self) -> ShortenReq {
+        let alias = self.alias.trim();
+        ShortenReq {
+            url: self.url.trim().to_string(),
+            alias: (!alias.is_empty()).then(|| alias.to_string()),
+            expires_at: self.expires_in.duration().map(|d| Utc::now() + d),
+            ..Default::default()
+        }
+    }
+}
+
+impl Expiry {
+    const ALL: [Self; 5] = [Self::Never, Self::Hour, Self::Day, Self::Week, Self::Month];
+
+    fn value(&self) -> &'static str {
+        match self {
+            Self::Never => "",
+            Self::Hour => "1h",
+            Self::Day => "1d",
+            Self::Week => "7d",
+            Self::Month => "30d",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Never => "Never",
+            Self::Hour => "In 1 hour",
+            Self::Day => "In 1 day",
+            Self::Week => "In 7 days",
+            Self::Month => "In 30 days",
+        }
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        match self {
+            Self::Never => None,
+            Self::Hour => Some(Duration::hours(1)),
+            Self::Day => Some(Duration::days(1)),
+            Self::Week => Some(Duration::days(7)),
+            Self::Month => Some(Duration::days(30)),
+        }
+    }
+}
+
+fn render(status: StatusCode, page: &impl Template) -> Response {
+    match page.render() {
+        Ok(html) => (status, Html(html)).into_response(),
+        Err(e) => ShortenerError::Internal(anyhow!("failed to render page: {e}")).into_response(),
+    }
+}
+
+fn form_error(form: ShortenForm, e: ShortenerError) -> Response {
+    e.log();
+    render(e.status(), &IndexPage::new(form, Some(e.to_string())))
+}
+
+fn links_error(e: ShortenerError) -> Response {
+    e.log();
+    render(e.status(), &LinksPage::signed_out(Some(e.to_string())))
+}
+
+fn cross_site() -> ShortenerError {
+    ShortenerError::Forbidden("forms can only be submitted from this site".to_string())
+}
+
+// 表单依赖 cookie 中的管理令牌，需要拒绝其他网站提交的表单（CSRF）。
+// 现代浏览器会带上 Sec-Fetch-Site，旧的浏览器至少会在 POST 请求中带上 Origin，两者都没有时不是浏览器发起的请求
+fn is_same_origin(headers: &HeaderMap, host: Option<&Host>) -> bool {
+    if let Some(site) = headers.get("sec-fetch-site") {
+        return matches!(site.as_bytes(), b"same-origin" | b"none");
+    }
+    let Some(origin) = headers.get(ORIGIN).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let origin_host = origin.split_once("://").map(|(_, host)| host);
+    matches!((origin_host, host), (Some(origin), Some(Host(host))) if origin.eq_ignore_ascii_case(host))
+}
+
+// 管理令牌只由服务端读取，使用 HttpOnly；max_age 为 0 时删除 cookie。
+// 配置的地址是 https 时加上 Secure，本地通过 http 调试时仍然可以使用
+fn token_cookie(state: &AppState, token: &str, max_age: u64) -> Option<HeaderValue> {
+    let secure = if state.config.base_url.starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    };
+    let cookie = format!(
+        "{TOKEN_COOKIE}={token}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}"
+    );
+    HeaderValue::from_str(&cookie).ok()
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M UTC").to_string()
+}
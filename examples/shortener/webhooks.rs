@@ -0,0 +1,326 @@
+use crate::{
+    config::Config,
+    domain::Domains,
+    store::{LinkStatus, LinkStore, UrlRecord, Webhook, WebhookDelivery, WebhookEvent},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future;
+use hmac::{Hmac, Mac};
+use http::header::CONTENT_TYPE;
+use nanoid::nanoid;
+use serde::Serialize;
+use sha2::Sha256;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{sync::Notify, time};
+use tracing::warn;
+
+// 投递请求中的请求头，接收方用 timestamp 和请求体验证 signature
+pub const EVENT_HEADER: &str = "x-shortener-event";
+pub const DELIVERY_HEADER: &str = "x-shortener-delivery";
+pub const TIMESTAMP_HEADER: &str = "x-shortener-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-shortener-signature";
+// 每次从队列中取出的投递数量
+const DELIVERY_BATCH_SIZE: i64 = 50;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+// 取出的投递在这段时间内不会被其他实例取出，需要比发送的超时时间长
+const DELIVERY_LEASE: Duration = Duration::from_secs(60);
+// 没有新事件时检查到期的重试和其他实例放入的投递的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+// 失败原因只保存开头的一部分，接收方返回的错误页面可能很长
+const ERROR_MAX_LEN: usize = 500;
+
+// 把短链接事件写入投递队列，由后台任务签名之后发送给订阅了这个事件的 webhook。
+// 队列保存在数据库中，服务重启或者接收方暂时不可用都不会丢失事件
+pub struct Webhooks {
+    store: Arc<dyn LinkStore>,
+    domains: Arc<Domains>,
+    client: reqwest::Client,
+    // 有新的投递时唤醒后台任务，不用等到下一次轮询
+    notify: Notify,
+    max_attempts: u32,
+    retry_base: Duration,
+}
+
+// 请求体的格式，同一个事件发送给多个 webhook 时 id 相同，接收方可以用它去重
+#[derive(Debug, Serialize)]
+struct Payload<T> {
+    id: String,
+    event: WebhookEvent,
+    created_at: DateTime<Utc>,
+    data: T,
+}
+
+// link.created、link.updated、link.deleted 和 link.expired 事件中的短链接
+#[derive(Debug, Serialize)]
+pub struct LinkData {
+    domain: String,
+    id: String,
+    short_url: String,
+    // 受密码保护的短链接没有明文地址
+    url: Option<String>,
+    tags: Vec<String>,
+    status: LinkStatus,
+    created_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    remaining_clicks: Option<i64>,
+}
+
+// link.clicks 事件，clicks 是写入这批访问记录之后的总访问次数
+#[derive(Debug, Serialize)]
+struct ClicksData {
+    domain: String,
+    id: String,
+    short_url: String,
+    clicks: i64,
+    threshold: i64,
+}
+
+impl Webhooks {
+    pub fn new(config: &Config, store: Arc<dyn LinkStore>, domains: Arc<Domains>) -> Self {
+        // 不跟随跳转，接收方的地址变了应该由管理员修改订阅
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build the webhook HTTP client");
+        Self {
+            store,
+            domains,
+            client,
+            notify: Notify::new(),
+            max_attempts: config.webhook_max_attempts,
+            retry_base: config.webhook_retry_base,
+        }
+    }
+
+    pub fn link_data(&self, record: &UrlRecord) -> LinkData {
+        LinkData {
+            domain: record.domain.clone(),
+            id: record.id.clone(),
+            short_url: self.domains.short_url(&record.domain, &record.id),
+            url: record.sealed_url.is_none().then(|| record.url.clone()),
+            tags: record.tags.0.clone(),
+            status: record.status,
+            created_at: record.created_at,
+            expires_at: record.expires_at,
+            remaining_clicks: record.remaining_clicks,
+        }
+    }
+
+    // 触发一批同类型的事件。写入队列失败只记录日志，不影响触发事件的操作
+    pub async fn emit<T: Serialize>(&self, event: WebhookEvent, items: Vec<T>) {
+        if items.is_empty() {
+            return;
+        }
+        if let Err(e) = self.enqueue(event, items).await {
+            warn!("Failed to enqueue {event} webhooks: {e}");
+        }
+    }
+
+    async fn enqueue<T: Serialize>(&self, event: WebhookEvent, items: Vec<T>) -> Result<()> {
+        let webhooks = self.store.list_webhooks().await?;
+        let subscribed: Vec<_> = webhooks
+            .iter()
+            .filter(|webhook| webhook.events.contains(&event))
+            .collect();
+        if subscribed.is_empty() {
+            return Ok(());
+        }
+        let mut deliveries = Vec::new();
+        for data in items {
+            deliveries.extend(new_deliveries(&subscribed, event, data)?);
+        }
+        self.push(deliveries).await
+    }
+
+    // 写入一批访问记录之后调用，counts 是这一批中每个短链接的访问次数。
+    // 总访问次数从 total - n 增加到 total，越过了某个阈值时触发 link.clicks。
+    // 多个实例同时写入同一个短链接的访问记录时可能重复通知，和其他投递一样，接收方需要按 id 去重
+    pub async fn clicks_recorded(&self, counts: HashMap<(String, String), i64>) {
+        if let Err(e) = self.enqueue_clicks(counts).await {
+            warn!("Failed to enqueue {} webhooks: {e}", WebhookEvent::Clicks);
+        }
+    }
+
+    async fn enqueue_clicks(&self, counts: HashMap<(String, String), i64>) -> Result<()> {
+        let webhooks = self.store.list_webhooks().await?;
+        let subscribed: Vec<_> = webhooks
+            .iter()
+            .filter(|webhook| webhook.events.contains(&WebhookEvent::Clicks))
+            .collect();
+        if subscribed.is_empty() {
+            return Ok(());
+        }
+        let mut deliveries = Vec::new();
+        for ((domain, id), n) in counts {
+            let total = self.store.count_clicks(&domain, &id).await?;
+            for webhook in &subscribed {
+                for &threshold in webhook.click_thresholds.iter() {
+                    if total - n < threshold && threshold <= total {
+                        let data = ClicksData {
+                            short_url: self.domains.short_url(&domain, &id),
+                            domain: domain.clone(),
+                            id: id.clone(),
+                            clicks: total,
+                            threshold,
+                        };
+                        deliveries.extend(new_deliveries(&[webhook], WebhookEvent::Clicks, data)?);
+                    }
+                }
+            }
+        }
+        self.push(deliveries).await
+    }
+
+    async fn push(&self, deliveries: Vec<WebhookDelivery>) -> Result<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+        self.store.enqueue_deliveries(&deliveries).await?;
+        self.wake();
+        Ok(())
+    }
+
+    // 唤醒后台任务，例如死信被放回队列之后
+    pub fn wake(&self) {
+        self.notify.notify_one();
+    }
+
+    // 后台任务：不断取出到期的投递并发送
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            match self.deliver_due().await {
+                // 取满了一批说明可能还有到期的投递
+                Ok(n) if n as i64 == DELIVERY_BATCH_SIZE => continue,
+                Ok(_) => {}
+                Err(e) => warn!("Failed to deliver webhooks: {e}"),
+            }
+            // 超时说明没有新的事件，这时检查到期的重试
+            let _ = time::timeout(POLL_INTERVAL, self.notify.notified()).await;
+        }
+    }
+
+    async fn deliver_due(&self) -> Result<usize> {
+        let now = Utc::now();
+        let lease_until = now + chrono::Duration::from_std(DELIVERY_LEASE)?;
+        let deliveries = self
+            .store
+            .claim_deliveries(now, lease_until, DELIVERY_BATCH_SIZE)
+            .await?;
+        if deliveries.is_empty() {
+            return Ok(0);
+        }
+        let webhooks: HashMap<_, _> = self
+            .store
+            .list_webhooks()
+            .await?
+            .into_iter()
+            .map(|webhook| (webhook.id.clone(), webhook))
+            .collect();
+        // 并发发送，一个很慢的接收方不会拖慢其他投递。
+        // webhook 在取出之后被删除时，它的投递也已经被一起删除了
+        let attempts = deliveries.iter().filter_map(|delivery| {
+            let webhook = webhooks.get(&delivery.webhook_id)?;
+            Some(self.attempt(webhook, delivery))
+        });
+        for ret in future::join_all(attempts).await {
+            if let Err(e) = ret {
+                warn!("Failed to update webhook delivery: {e}");
+            }
+        }
+        Ok(deliveries.len())
+    }
+
+    // 发送一次，根据结果删除投递、安排重试，或者在次数用完时移到死信表
+    async fn attempt(&self, webhook: &Webhook, delivery: &WebhookDelivery) -> Result<()> {
+        let attempts = delivery.attempts + 1;
+        let error = match self.send(webhook, delivery).await {
+            Ok(()) => return self.store.complete_delivery(&delivery.id).await,
+            Err(e) => e.chars().take(ERROR_MAX_LEN).collect::<String>(),
+        };
+        if attempts as u32 >= self.max_attempts {
+            warn!(
+                "Webhook delivery {} to {} failed {attempts} times, moving it to dead letters: {error}",
+                delivery.id, webhook.url
+            );
+            return self
+                .store
+                .dead_letter(&delivery.id, attempts, &error, Utc::now())
+                .await;
+        }
+        let next_attempt_at = Utc::now() + chrono::Duration::from_std(self.backoff(attempts))?;
+        self.store
+            .retry_delivery(&delivery.id, attempts, next_attempt_at, &error)
+            .await
+    }
+
+    async fn send(&self, webhook: &Webhook, delivery: &WebhookDelivery) -> Result<(), String> {
+        // 每次发送时重新签名，接收方可以拒绝 timestamp 太旧的请求，防止请求被截获之后重放
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = sign(&webhook.secret, &timestamp, &delivery.payload);
+        let res = self
+            .client
+            .post(&webhook.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, delivery.event.to_string())
+            .header(DELIVERY_HEADER, &delivery.id)
+            .header(TIMESTAMP_HEADER, &timestamp)
+            .header(SIGNATURE_HEADER, signature)
+            .body(delivery.payload.clone())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("endpoint responded with {}", res.status()));
+        }
+        Ok(())
+    }
+
+    // 第 n 次失败之后等待 retry_base * 2^(n-1)，最多等待一小时
+    fn backoff(&self, attempts: i32) -> Duration {
+        let exp = attempts.clamp(1, 20) as u32 - 1;
+        self.retry_base
+            .saturating_mul(1 << exp)
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+// 签名是 HMAC-SHA256(secret, "{timestamp}.{body}") 的十六进制，加上 sha256= 前缀
+pub fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// 同一个事件对每个订阅了它的 webhook 各生成一条投递，请求体相同
+fn new_deliveries<T: Serialize>(
+    webhooks: &[&Webhook],
+    event: WebhookEvent,
+    data: T,
+) -> Result<Vec<WebhookDelivery>> {
+    let now = Utc::now();
+    let payload = serde_json::to_string(&Payload {
+        id: nanoid!(16),
+        event,
+        created_at: now,
+        data,
+    })?;
+    Ok(webhooks
+        .iter()
+        .map(|webhook| WebhookDelivery {
+            id: nanoid!(16),
+            webhook_id: webhook.id.clone(),
+            event,
+            payload: payload.clone(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+        })
+        .collect())
+}